@@ -3,7 +3,9 @@ use std::fmt::Display;
 
 use packed_struct::prelude::{PackedStruct, PrimitiveEnum_u8};
 
-use crate::Placeholder;
+use crate::symbols::Placeholder;
+
+pub mod assembler;
 
 pub const NOP: Instruction = Instruction {
     op: Operation::Load,
@@ -88,6 +90,12 @@ impl Instruction {
             } else {
                 format!("{:04x}  {}{},{}{}", rom_addr, op_name, bus, addr, reg)
             }
+        } else if self.op != Operation::Jump
+            && self.mode == Mode::Out_Y_Xpp_Bra
+            && self.bus != Bus::Ram
+        {
+            // X is incremented even though nothing is read from RAM
+            format!("{:04x}  {}{},{}{}", rom_addr, op_name, bus, addr, reg)
         } else {
             format!("{:04x}  {}{}{}", rom_addr, op_name, bus, reg)
         }
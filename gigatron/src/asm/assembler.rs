@@ -0,0 +1,795 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::Display,
+    io::{BufWriter, Write},
+};
+
+use packed_struct::PackedStruct;
+
+use super::{Bus, Instruction, Mode, Operation};
+use crate::{
+    cpu::{self, OpCode, RomWord, ROM_SIZE},
+    symbols::{Placeholder, SymbolTable, ZeroPageVariable},
+};
+
+// Assembles source written the way Instruction::disassemble prints it, so
+// the ROM can be built without Python:
+//
+//   ; Comments start with a semicolon
+//           .zp    frame            ; Allocate zero-page bytes: .zp name [length]
+//           .equ   black $00        ; Define a constant
+//   loop:   ld     [frame]
+//           adda   1
+//           st     [frame]
+//           ld     hi(table),y
+//           ld     [y,x++],out
+//           ld     $00,[y,x++],out  ; Increments X without reading RAM
+//           bra    loop             ; Bare symbols mean their low byte
+//           .align $100             ; Pad with nops to a multiple of $100
+//           .fill  $fb              ; Pad with nops up to this page offset
+//
+// Operands are numbers ($12, 0x12 or 18), symbols, hi(...) and lo(...)
+// joined with + and -. Like the disassembly, brackets read the bus from RAM,
+// so "bne [$30]" branches to the address stored at $30.
+#[derive(Debug)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for AsmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Num(i64),
+    Symbol(String),
+    Hi(Box<Value>),
+    Lo(Box<Value>),
+    Add(Box<Value>, Box<Value>),
+    Sub(Box<Value>, Box<Value>),
+}
+
+impl Value {
+    fn has_symbol(&self) -> bool {
+        match self {
+            Self::Num(_) => false,
+            Self::Symbol(_) => true,
+            Self::Hi(v) | Self::Lo(v) => v.has_symbol(),
+            Self::Add(a, b) | Self::Sub(a, b) => a.has_symbol() || b.has_symbol(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Address {
+    D(Value),
+    X,
+    YD(Value),
+    YX,
+    YXpp,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Operand {
+    Value(Value),
+    Mem(Address),
+    Ac,
+    In,
+    X,
+    Y,
+    Out,
+}
+
+enum Symbol {
+    Label(u16),
+    ZeroPage(u8),
+    Const(Value),
+}
+
+// An instruction with its data byte still to be resolved. Every value has to
+// agree, since the bus and address can both come from D.
+struct Statement {
+    line: usize,
+    inst: Instruction,
+    data: Vec<Value>,
+}
+
+pub struct Assembly {
+    pub rom: Vec<RomWord>,
+    pub zero_page: Vec<ZeroPageVariable>,
+    pub labels: Vec<(String, u16)>,
+    // Symbolic operands in the placeholder syntax of .sym files
+    pub placeholders: Vec<(u16, String)>,
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '#'
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let (radix, digits) = if let Some(hex) = text.strip_prefix('$') {
+        (16, hex)
+    } else if let Some(hex) = text.strip_prefix("0x") {
+        (16, hex)
+    } else {
+        (10, text)
+    };
+    i64::from_str_radix(digits, radix).ok()
+}
+
+// value := term (('+' | '-') term)*
+// term  := number | name | hi(value) | lo(value) | (value)
+fn parse_value(text: &str) -> Result<Value, String> {
+    fn term(text: &str) -> Result<(Value, &str), String> {
+        let text = text.trim_start();
+        let len = text
+            .find(|c: char| !is_name_char(c) && c != '$')
+            .unwrap_or(text.len());
+        if len == 0 {
+            let inner = text
+                .strip_prefix('(')
+                .ok_or_else(|| format!("Expected a value at '{}'", text))?;
+            let (val, rest) = sum(inner)?;
+            let rest = rest
+                .trim_start()
+                .strip_prefix(')')
+                .ok_or_else(|| format!("Expected ')' at '{}'", rest))?;
+            return Ok((val, rest));
+        }
+
+        let (word, rest) = text.split_at(len);
+        if let Some(inner) = rest.trim_start().strip_prefix('(') {
+            let (val, rest) = sum(inner)?;
+            let rest = rest
+                .trim_start()
+                .strip_prefix(')')
+                .ok_or_else(|| format!("Expected ')' at '{}'", rest))?;
+            let val = Box::new(val);
+            return match word {
+                "hi" => Ok((Value::Hi(val), rest)),
+                "lo" => Ok((Value::Lo(val), rest)),
+                _ => Err(format!("Unknown function '{}'", word)),
+            };
+        }
+
+        if word.starts_with(|c: char| c.is_ascii_digit() || c == '$') {
+            let num = parse_number(word).ok_or_else(|| format!("Invalid number '{}'", word))?;
+            Ok((Value::Num(num), rest))
+        } else {
+            Ok((Value::Symbol(word.to_string()), rest))
+        }
+    }
+
+    fn sum(text: &str) -> Result<(Value, &str), String> {
+        let (mut val, mut rest) = term(text)?;
+        loop {
+            let trimmed = rest.trim_start();
+            if let Some(after) = trimmed.strip_prefix('+') {
+                let (rhs, r) = term(after)?;
+                val = Value::Add(Box::new(val), Box::new(rhs));
+                rest = r;
+            } else if let Some(after) = trimmed.strip_prefix('-') {
+                let (rhs, r) = term(after)?;
+                val = Value::Sub(Box::new(val), Box::new(rhs));
+                rest = r;
+            } else {
+                return Ok((val, rest));
+            }
+        }
+    }
+
+    let (val, rest) = sum(text)?;
+    if !rest.trim().is_empty() {
+        return Err(format!("Unexpected '{}'", rest.trim()));
+    }
+    Ok(val)
+}
+
+fn parse_address(text: &str) -> Result<Address, String> {
+    let parts: Vec<&str> = text.split(',').map(str::trim).collect();
+    match parts[..] {
+        ["x"] => Ok(Address::X),
+        ["y", "x"] => Ok(Address::YX),
+        ["y", "x++"] => Ok(Address::YXpp),
+        ["y", d] => Ok(Address::YD(parse_value(d)?)),
+        [d] => Ok(Address::D(parse_value(d)?)),
+        _ => Err(format!("Invalid address '[{}]'", text)),
+    }
+}
+
+fn parse_operand(text: &str) -> Result<Operand, String> {
+    match text {
+        "ac" => Ok(Operand::Ac),
+        "in" => Ok(Operand::In),
+        "x" => Ok(Operand::X),
+        "y" => Ok(Operand::Y),
+        "out" => Ok(Operand::Out),
+        _ => match text.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+            Some(inner) => Ok(Operand::Mem(parse_address(inner)?)),
+            None => Ok(Operand::Value(parse_value(text)?)),
+        },
+    }
+}
+
+// Splits on commas outside of brackets and parentheses
+fn split_operands(text: &str) -> Vec<&str> {
+    let mut operands = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '[' | '(' => depth += 1,
+            ']' | ')' => depth -= 1,
+            ',' if depth == 0 => {
+                operands.push(text[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if !text.trim().is_empty() {
+        operands.push(text[start..].trim());
+    }
+    operands
+}
+
+// Bus and data values for an operand that puts a value on the bus
+fn source_bus(source: &Operand) -> Result<(Bus, Vec<Value>), String> {
+    match source {
+        Operand::Value(val) => Ok((Bus::Data, vec![val.clone()])),
+        Operand::Mem(_) => Ok((Bus::Ram, vec![])),
+        Operand::Ac => Ok((Bus::Acc, vec![])),
+        Operand::In => Ok((Bus::In, vec![])),
+        _ => Err("Expected a value, memory, ac or in".to_string()),
+    }
+}
+
+// Mode and data values for an address and destination register
+fn address_mode(address: Option<&Address>, dest: &Operand) -> Result<(Mode, Vec<Value>), String> {
+    let d = |val: &Value| vec![val.clone()];
+    match (address, dest) {
+        (None, Operand::Ac) => Ok((Mode::Acc_D_Far, vec![])),
+        (Some(Address::D(val)), Operand::Ac) => Ok((Mode::Acc_D_Far, d(val))),
+        (Some(Address::X), Operand::Ac) => Ok((Mode::Acc_X_Gt, vec![])),
+        (Some(Address::YD(val)), Operand::Ac) => Ok((Mode::Acc_Y_D_Lt, d(val))),
+        (Some(Address::YX), Operand::Ac) => Ok((Mode::Acc_Y_X_Ne, vec![])),
+        (Some(Address::YXpp), Operand::Out) => Ok((Mode::Out_Y_Xpp_Bra, vec![])),
+        (None, Operand::X) => Ok((Mode::X_D_Eq, vec![])),
+        (Some(Address::D(val)), Operand::X) => Ok((Mode::X_D_Eq, d(val))),
+        (None, Operand::Y) => Ok((Mode::Y_D_Ge, vec![])),
+        (Some(Address::D(val)), Operand::Y) => Ok((Mode::Y_D_Ge, d(val))),
+        (None, Operand::Out) => Ok((Mode::Out_D_Le, vec![])),
+        (Some(Address::D(val)), Operand::Out) => Ok((Mode::Out_D_Le, d(val))),
+        _ => Err("Invalid combination of address and destination".to_string()),
+    }
+}
+
+fn alu_operation(mnemonic: &str) -> Option<Operation> {
+    match mnemonic {
+        "ld" => Some(Operation::Load),
+        "anda" => Some(Operation::And),
+        "ora" => Some(Operation::Or),
+        "xora" => Some(Operation::Xor),
+        "adda" => Some(Operation::Add),
+        "suba" => Some(Operation::Sub),
+        _ => None,
+    }
+}
+
+fn branch_condition(mnemonic: &str) -> Option<Mode> {
+    match mnemonic {
+        "bgt" => Some(Mode::Acc_X_Gt),
+        "blt" | "bmi" => Some(Mode::Acc_Y_D_Lt),
+        "bne" => Some(Mode::Acc_Y_X_Ne),
+        "beq" => Some(Mode::X_D_Eq),
+        "bge" | "bpl" => Some(Mode::Y_D_Ge),
+        "ble" => Some(Mode::Out_D_Le),
+        "bra" => Some(Mode::Out_Y_Xpp_Bra),
+        _ => None,
+    }
+}
+
+fn parse_instruction(
+    mnemonic: &str,
+    operands: &[Operand],
+) -> Result<(Instruction, Vec<Value>), String> {
+    let inst = |op, mode, bus| Instruction { op, mode, bus };
+
+    if mnemonic == "nop" {
+        if !operands.is_empty() {
+            return Err("nop takes no operands".to_string());
+        }
+        return Ok((super::NOP, vec![]));
+    }
+
+    if let Some(op) = alu_operation(mnemonic) {
+        let (source, address, dest) = match operands {
+            [source] => (source, None, &Operand::Ac),
+            [source, dest] => (source, None, dest),
+            // Without a RAM read, [y,x++] after the source still increments X
+            [source, Operand::Mem(address @ Address::YXpp), dest]
+                if !matches!(source, Operand::Mem(_)) =>
+            {
+                (source, Some(address), dest)
+            }
+            _ => {
+                return Err(format!(
+                    "{} takes a source and optional destination",
+                    mnemonic
+                ))
+            }
+        };
+        let (bus, mut data) = source_bus(source)?;
+        let address = match source {
+            Operand::Mem(address) => Some(address),
+            _ => address,
+        };
+        let (mode, address_data) = address_mode(address, dest)?;
+        data.extend(address_data);
+        return Ok((inst(op, mode, bus), data));
+    }
+
+    if mnemonic == "st" || mnemonic == "ctrl" {
+        // The source defaults to ac, ctrl reads the bus from the address
+        let (source, address, dest) = match operands {
+            [Operand::Mem(a)] if mnemonic == "ctrl" => (&operands[0], a, &Operand::Ac),
+            [Operand::Mem(a), dest @ (Operand::X | Operand::Y)] if mnemonic == "ctrl" => {
+                (&operands[0], a, dest)
+            }
+            [Operand::Mem(a)] => (&Operand::Ac, a, &Operand::Ac),
+            [Operand::Mem(a), dest @ (Operand::X | Operand::Y)] => (&Operand::Ac, a, dest),
+            [source, Operand::Mem(a)] => (source, a, &Operand::Ac),
+            [source, Operand::Mem(a), dest] => (source, a, dest),
+            _ => return Err(format!("Invalid operands for {}", mnemonic)),
+        };
+        let (bus, mut data) = source_bus(source)?;
+        // Stores to [y,x++] still increment X, but nothing is written to OUT
+        let dest = match (address, dest) {
+            (Address::YXpp, Operand::Ac) => &Operand::Out,
+            _ => dest,
+        };
+        let (mode, address_data) = address_mode(Some(address), dest)?;
+        data.extend(address_data);
+        return Ok((inst(Operation::Store, mode, bus), data));
+    }
+
+    let (mode, target) = match (mnemonic, operands) {
+        ("jmp", [Operand::Y, target]) => (Mode::Acc_D_Far, target),
+        ("jmp", _) => return Err("jmp takes y and a target".to_string()),
+        (_, [target]) => match branch_condition(mnemonic) {
+            Some(mode) => (mode, target),
+            None => return Err(format!("Unknown instruction '{}'", mnemonic)),
+        },
+        _ => return Err(format!("Unknown instruction '{}'", mnemonic)),
+    };
+    let (bus, data) = match target {
+        Operand::Mem(Address::D(val)) => (Bus::Ram, vec![val.clone()]),
+        Operand::Mem(_) => return Err("Branches can only read from [d]".to_string()),
+        target => source_bus(target)?,
+    };
+    Ok((inst(Operation::Jump, mode, bus), data))
+}
+
+struct Assembler {
+    statements: Vec<Statement>,
+    symbols: HashMap<String, Symbol>,
+    labels: Vec<(String, u16)>,
+    zero_page: Vec<ZeroPageVariable>,
+    zp_next: u16,
+}
+
+impl Assembler {
+    fn pc(&self) -> usize {
+        self.statements.len()
+    }
+
+    fn define(&mut self, name: &str, symbol: Symbol) -> Result<(), String> {
+        if !name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            || !name.chars().all(is_name_char)
+        {
+            return Err(format!("Invalid name '{}'", name));
+        }
+        if self.symbols.insert(name.to_string(), symbol).is_some() {
+            return Err(format!("'{}' is already defined", name));
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, line: usize, inst: Instruction, data: Vec<Value>) -> Result<(), String> {
+        if self.pc() >= ROM_SIZE {
+            return Err("Program does not fit in ROM".to_string());
+        }
+        self.statements.push(Statement { line, inst, data });
+        Ok(())
+    }
+
+    fn pad(&mut self, line: usize, count: usize) -> Result<(), String> {
+        for _ in 0..count {
+            self.emit(line, super::NOP, vec![])?;
+        }
+        Ok(())
+    }
+
+    fn const_arg(&self, text: Option<&str>, default: i64) -> Result<i64, String> {
+        match text {
+            Some(text) => parse_number(text).ok_or_else(|| format!("Invalid number '{}'", text)),
+            None => Ok(default),
+        }
+    }
+
+    fn directive(&mut self, line: usize, name: &str, args: &[&str]) -> Result<(), String> {
+        match (name, args) {
+            (".zp", [var, rest @ ..]) if rest.len() <= 1 => {
+                let len = self.const_arg(rest.first().copied(), 1)?;
+                if !(1..=0xff).contains(&len) {
+                    return Err(format!("Invalid length {}", len));
+                }
+                // Same allocation as zpByte in asm.py, keeping $80 free
+                let mut start = self.zp_next;
+                if start <= 0x80 && 0x80 < start + len as u16 {
+                    start = 0x81;
+                }
+                if start + len as u16 > 0x100 {
+                    return Err("Zero page is full".to_string());
+                }
+                self.zp_next = start + len as u16;
+                self.define(var, Symbol::ZeroPage(start as u8))?;
+                self.zero_page.push(ZeroPageVariable {
+                    address: start as u8,
+                    length: len as u8,
+                    name: var.to_string(),
+                });
+            }
+            (".zpreset", [] | [_]) => {
+                let start = self.const_arg(args.first().copied(), 1)?;
+                if !(0..=0xff).contains(&start) {
+                    return Err(format!("Invalid zero-page address {}", start));
+                }
+                self.zp_next = start as u16;
+            }
+            (".equ", [var, value @ ..]) if !value.is_empty() => {
+                let value = parse_value(&value.join(" "))?;
+                self.define(var, Symbol::Const(value))?;
+            }
+            (".align", [] | [_]) => {
+                let align = self.const_arg(args.first().copied(), 0x100)?;
+                if !(1..=ROM_SIZE as i64).contains(&align) {
+                    return Err(format!("Invalid alignment {}", align));
+                }
+                let count = (align as usize - self.pc() % align as usize) % align as usize;
+                self.pad(line, count)?;
+            }
+            (".fill", [] | [_]) => {
+                let until = self.const_arg(args.first().copied(), 0x100)?;
+                let offset = (self.pc() & 0xff) as i64;
+                if until < offset || until > 0x100 {
+                    return Err(format!("Already past page offset {}", until));
+                }
+                self.pad(line, (until - offset) as usize)?;
+            }
+            _ => return Err(format!("Invalid directive '{} {}'", name, args.join(" "))),
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, line: usize, text: &str) -> Result<(), String> {
+        // Labels end with a colon and can share a line with an instruction
+        let mut text = text.trim();
+        if let Some((name, rest)) = text.split_once(':') {
+            let name = name.trim();
+            if name.chars().all(is_name_char) {
+                self.define(name, Symbol::Label(self.pc() as u16))?;
+                self.labels.push((name.to_string(), self.pc() as u16));
+                text = rest.trim();
+            }
+        }
+        if text.is_empty() {
+            return Ok(());
+        }
+
+        let (mnemonic, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        if mnemonic.starts_with('.') {
+            let args: Vec<&str> = rest.split_whitespace().collect();
+            return self.directive(line, mnemonic, &args);
+        }
+
+        let operands = split_operands(rest)
+            .into_iter()
+            .map(parse_operand)
+            .collect::<Result<Vec<_>, _>>()?;
+        let (inst, data) = parse_instruction(&mnemonic.to_ascii_lowercase(), &operands)?;
+        self.emit(line, inst, data)
+    }
+
+    fn eval(&self, val: &Value, depth: u32) -> Result<i64, String> {
+        if depth > 32 {
+            return Err("Constants refer to each other in a loop".to_string());
+        }
+        Ok(match val {
+            Value::Num(n) => *n,
+            Value::Symbol(name) => match self.symbols.get(name) {
+                Some(Symbol::Label(addr)) => *addr as i64,
+                Some(Symbol::ZeroPage(addr)) => *addr as i64,
+                Some(Symbol::Const(val)) => self.eval(val, depth + 1)?,
+                None => return Err(format!("Undefined symbol '{}'", name)),
+            },
+            Value::Hi(v) => (self.eval(v, depth)? >> 8) & 0xff,
+            Value::Lo(v) => self.eval(v, depth)? & 0xff,
+            Value::Add(a, b) => self.eval(a, depth)? + self.eval(b, depth)?,
+            Value::Sub(a, b) => self.eval(a, depth)? - self.eval(b, depth)?,
+        })
+    }
+
+    // Writes a value the way asm.py writes placeholders, None if the .sym
+    // format cannot express it
+    fn placeholder(&self, val: &Value, top: bool) -> Option<String> {
+        Some(match val {
+            Value::Num(n) => n.to_string(),
+            Value::Symbol(name) => match self.symbols.get(name) {
+                Some(Symbol::ZeroPage(_)) => format!("zp {}", name),
+                _ if top => format!("lo {}", name),
+                _ => name.clone(),
+            },
+            Value::Hi(v) => format!("hi {}", self.placeholder(v, false)?),
+            Value::Lo(v) => format!("lo {}", self.placeholder(v, false)?),
+            Value::Add(a, b) => format!(
+                "add {} {}",
+                self.placeholder(a, top)?,
+                self.placeholder(b, false)?
+            ),
+            Value::Sub(a, b) => match **b {
+                Value::Num(n) => format!("add {} {}", self.placeholder(a, top)?, -n),
+                _ => return None,
+            },
+        })
+    }
+
+    fn finish(self) -> Result<Assembly, AsmError> {
+        let mut rom = Vec::with_capacity(self.statements.len());
+        let mut placeholders = vec![];
+        for (addr, st) in self.statements.iter().enumerate() {
+            let err = |message| AsmError {
+                line: st.line,
+                message,
+            };
+            let mut data = None;
+            for val in &st.data {
+                let byte = (self.eval(val, 0).map_err(err)? & 0xff) as u8;
+                if data.is_some_and(|d| d != byte) {
+                    return Err(err(
+                        "Source and address both use d but need different values".to_string(),
+                    ));
+                }
+                data = Some(byte);
+            }
+
+            if let Some(val) = st.data.iter().find(|v| v.has_symbol()) {
+                if let Some(text) = self.placeholder(val, true) {
+                    placeholders.push((addr as u16, text));
+                }
+            }
+            rom.push(RomWord {
+                inst: OpCode(st.inst.pack().map_err(|e| err(e.to_string()))?[0]),
+                data: data.unwrap_or(0),
+            });
+        }
+
+        Ok(Assembly {
+            rom,
+            zero_page: self.zero_page,
+            labels: self.labels,
+            placeholders,
+        })
+    }
+}
+
+impl Assembler {
+    fn new() -> Self {
+        Self {
+            statements: vec![],
+            symbols: HashMap::new(),
+            labels: vec![],
+            zero_page: vec![],
+            zp_next: 1,
+        }
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Assembly, AsmError> {
+    let mut asm = Assembler::new();
+    for (i, line) in source.lines().enumerate() {
+        let text = line.split(';').next().unwrap_or_default();
+        asm.statement(i + 1, text).map_err(|message| AsmError {
+            line: i + 1,
+            message,
+        })?;
+    }
+    asm.finish()
+}
+
+// Assembles a single instruction, resolving names with the labels and
+// zero-page variables of an already built ROM
+pub fn assemble_line(
+    text: &str,
+    symbols: &SymbolTable,
+) -> Result<(RomWord, Option<Placeholder>), String> {
+    let mut asm = Assembler::new();
+    for (&addr, name) in &symbols.labels {
+        asm.symbols.insert(name.clone(), Symbol::Label(addr));
+    }
+    for var in &symbols.zero_page {
+        asm.symbols
+            .insert(var.name.clone(), Symbol::ZeroPage(var.address));
+    }
+
+    let text = text.split(';').next().unwrap_or_default();
+    asm.statement(1, text)?;
+    if asm.statements.len() != 1 || !asm.labels.is_empty() {
+        return Err("Expected a single instruction".to_string());
+    }
+    let assembly = asm.finish().map_err(|e| e.message)?;
+    let placeholder = match assembly.placeholders.first() {
+        Some((_, text)) => Some(text.parse()?),
+        None => None,
+    };
+    Ok((assembly.rom[0], placeholder))
+}
+
+impl Assembly {
+    // Same layout as the .sym files written by asm.py
+    pub fn write_sym(&self, w: &mut impl Write) -> Result<(), std::io::Error> {
+        for var in &self.zero_page {
+            writeln!(w, "z {} {} {}", var.address, var.length, var.name)?;
+        }
+        for (name, addr) in &self.labels {
+            writeln!(w, "l {} {}", addr, name)?;
+        }
+        for (addr, text) in &self.placeholders {
+            writeln!(w, "p {} {}", addr, text)?;
+        }
+        Ok(())
+    }
+
+    // Writes "<stem>.rom" and "<stem>.sym"
+    pub fn save(&self, stem: &str) -> Result<(), std::io::Error> {
+        cpu::save_rom(&format!("{}.rom", stem), &self.rom)?;
+        let mut sym = BufWriter::new(std::fs::File::create(format!("{}.sym", stem))?);
+        self.write_sym(&mut sym)?;
+        sym.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disassemble(word: &RomWord) -> String {
+        let inst = Instruction::unpack(&[word.inst.0]).unwrap();
+        // Strip the address in front
+        inst.disassemble(0, word.data, None)[6..].to_string()
+    }
+
+    // Maps encodings that behave identically to one of them
+    fn canonical(opcode: u8) -> u8 {
+        let mut inst = Instruction::unpack(&[opcode]).unwrap();
+        match (inst.op, inst.mode) {
+            // Stores never write AC or OUT
+            (Operation::Store, Mode::Out_D_Le) => inst.mode = Mode::Acc_D_Far,
+            (Operation::Store | Operation::Jump, _) => {}
+            // The address of an AC result is unused without a RAM read
+            (_, Mode::Acc_X_Gt | Mode::Acc_Y_D_Lt | Mode::Acc_Y_X_Ne) if inst.bus != Bus::Ram => {
+                inst.mode = Mode::Acc_D_Far
+            }
+            _ => {}
+        }
+        inst.pack().unwrap()[0]
+    }
+
+    #[test]
+    fn reassembles_disassembly() {
+        for opcode in 0..=255u8 {
+            let word = RomWord {
+                inst: OpCode(opcode),
+                data: 0x12,
+            };
+            let text = disassemble(&word);
+            let asm = assemble(&text).unwrap_or_else(|e| panic!("'{}': {}", text, e));
+            let reassembled = asm.rom[0].inst.0;
+            if reassembled != opcode {
+                assert_eq!(
+                    canonical(reassembled),
+                    canonical(opcode),
+                    "'{}' reassembled {:02x} as {:02x}",
+                    text,
+                    opcode,
+                    reassembled
+                );
+            }
+            assert_eq!(disassemble(&asm.rom[0]), text, "opcode {:02x}", opcode);
+        }
+    }
+
+    #[test]
+    fn writes_rom_and_symbols() {
+        let source = "
+            .zp   counter
+            .zp   buffer 3
+            .equ  step $10
+            start:
+                    ld    [counter]
+                    adda  step + 1
+                    st    [counter]
+                    ld    hi(table),y
+            loop:   bne   loop      ; Spin
+                    st    $05,[x]
+                    .fill $fe
+                    jmp   y,lo(table)
+                    nop
+            table:  ld    buffer+2
+        ";
+        let asm = assemble(source).unwrap();
+        assert_eq!(asm.rom.len(), 0x101);
+        assert_eq!(asm.rom[1].data, 0x11);
+        assert_eq!(asm.rom[3].data, 0x01);
+        assert_eq!(asm.rom[4].data, 4);
+        assert_eq!(asm.rom[0xfe].data, 0x00);
+        assert_eq!(asm.rom[0x100].data, 2 + 2);
+
+        let stem = std::env::temp_dir().join(format!("gigatron-asm-{}", std::process::id()));
+        let stem = stem.to_str().unwrap();
+        asm.save(stem).unwrap();
+        let rom = cpu::load_rom(&format!("{}.rom", stem)).unwrap();
+        let symbols = SymbolTable::load(&format!("{}.sym", stem)).unwrap();
+        std::fs::remove_file(format!("{}.rom", stem)).unwrap();
+        std::fs::remove_file(format!("{}.sym", stem)).unwrap();
+
+        assert!(rom
+            .iter()
+            .zip(&asm.rom)
+            .all(|(a, b)| (a.inst, a.data) == (b.inst, b.data)));
+        assert_eq!(symbols.find_label("loop"), Some(4));
+        assert_eq!(symbols.find_label("table"), Some(0x100));
+        assert_eq!(symbols.find_var("buffer").unwrap().address, 2);
+        assert_eq!(symbols.placeholders[&0].to_string(), "counter");
+        assert_eq!(symbols.placeholders[&3].to_string(), "hi(table)");
+        assert_eq!(symbols.placeholders[&4].to_string(), "lo(loop)");
+        assert_eq!(symbols.placeholders[&0x100].to_string(), "buffer add 2");
+    }
+
+    #[test]
+    fn assembles_line_with_symbols() {
+        let mut symbols = SymbolTable::default();
+        symbols.labels.insert(0x0123, "loop".to_string());
+        symbols.zero_page.push(ZeroPageVariable {
+            address: 0x30,
+            length: 1,
+            name: "counter".to_string(),
+        });
+
+        let (word, placeholder) = assemble_line("bne loop", &symbols).unwrap();
+        assert_eq!(disassemble(&word), "bne  $23");
+        assert_eq!(placeholder.unwrap().to_string(), "lo(loop)");
+        let (word, placeholder) = assemble_line("adda [counter]", &symbols).unwrap();
+        assert_eq!(disassemble(&word), "adda [$30]");
+        assert_eq!(placeholder.unwrap().to_string(), "counter");
+        assert!(assemble_line("adda [x]", &symbols).unwrap().1.is_none());
+        assert!(assemble_line("done: nop", &symbols).is_err());
+        assert!(assemble_line(".zp foo", &symbols).is_err());
+    }
+
+    #[test]
+    fn reports_errors_with_line() {
+        let err = assemble("nop\n ld [x],y").err().unwrap();
+        assert_eq!(err.line, 2);
+        assert!(assemble("bra nowhere").is_err());
+        assert!(assemble("st $01,[$02]").is_err());
+        assert!(assemble("a: nop\na: nop").is_err());
+    }
+}
@@ -0,0 +1,208 @@
+#[cfg(feature = "playback")]
+use std::sync::{Arc, Mutex};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+};
+
+use crate::cpu::{CycleInfo, RegisterFile, CLOCK_HZ};
+
+// Recent 4-bit audio samples, one each time XOUT latches (once per scanline)
+pub struct SampleStream {
+    samples: VecDeque<u8>,
+    capacity: usize,
+}
+
+impl SampleStream {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn update(&mut self, info: &CycleInfo, reg: &RegisterFile) {
+        if !info.xout_latched {
+            return;
+        }
+
+        if self.samples.len() >= self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(reg.audio_sample());
+    }
+
+    pub fn samples(&self) -> impl Iterator<Item = u8> + '_ {
+        self.samples.iter().copied()
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+}
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+// Converts the XOUT audio level to host samples. Each host sample is the
+// average level over the CPU cycles it covers, so output follows emulated
+// time no matter how fast the emulator actually runs.
+pub struct Resampler {
+    sample_rate: u32,
+    cycles: u64, // Accumulated cycles, scaled by the sample rate
+    level_sum: u64,
+}
+
+impl Resampler {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            cycles: 0,
+            level_sum: 0,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Call once per CPU cycle, returns a host sample when one is complete
+    pub fn update(&mut self, reg: &RegisterFile) -> Option<i16> {
+        self.level_sum += reg.audio_sample() as u64;
+        self.cycles += self.sample_rate as u64;
+        if self.cycles < CLOCK_HZ as u64 {
+            return None;
+        }
+
+        let cycle_count = self.cycles / self.sample_rate as u64;
+        let level = self.level_sum as f32 / cycle_count as f32;
+        self.cycles -= CLOCK_HZ as u64;
+        self.level_sum = 0;
+
+        // Center the 0..15 range around zero
+        Some(((level * 2.0 - 15.0) * 1024.0) as i16)
+    }
+}
+
+// Minimal 16-bit mono PCM WAV writer
+pub struct WavWriter {
+    file: BufWriter<File>,
+    sample_count: u32,
+}
+
+impl WavWriter {
+    pub fn create(file_name: &str, sample_rate: u32) -> Result<Self, std::io::Error> {
+        let mut writer = Self {
+            file: BufWriter::new(File::create(file_name)?),
+            sample_count: 0,
+        };
+        writer.write_header(sample_rate)?;
+        Ok(writer)
+    }
+
+    fn write_header(&mut self, sample_rate: u32) -> Result<(), std::io::Error> {
+        let data_size = self.sample_count * 2;
+        let f = &mut self.file;
+        f.write_all(b"RIFF")?;
+        f.write_all(&(36 + data_size).to_le_bytes())?;
+        f.write_all(b"WAVEfmt ")?;
+        f.write_all(&16u32.to_le_bytes())?; // Format chunk size
+        f.write_all(&1u16.to_le_bytes())?; // PCM
+        f.write_all(&1u16.to_le_bytes())?; // Mono
+        f.write_all(&sample_rate.to_le_bytes())?;
+        f.write_all(&(sample_rate * 2).to_le_bytes())?; // Byte rate
+        f.write_all(&2u16.to_le_bytes())?; // Block align
+        f.write_all(&16u16.to_le_bytes())?; // Bits per sample
+        f.write_all(b"data")?;
+        f.write_all(&data_size.to_le_bytes())
+    }
+
+    pub fn write_sample(&mut self, sample: i16) -> Result<(), std::io::Error> {
+        self.sample_count += 1;
+        self.file.write_all(&sample.to_le_bytes())
+    }
+
+    // Fills in the chunk sizes, which are unknown until recording stops
+    pub fn finish(mut self, sample_rate: u32) -> Result<(), std::io::Error> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.write_header(sample_rate)?;
+        self.file.flush()
+    }
+}
+
+// Plays host samples on the default output device
+#[cfg(feature = "playback")]
+pub struct Playback {
+    _stream: cpal::Stream,
+    buffer: Arc<Mutex<VecDeque<i16>>>,
+    sample_rate: u32,
+}
+
+#[cfg(feature = "playback")]
+impl Playback {
+    pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
+        use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or("No audio output device")?;
+        let config: cpal::StreamConfig = device.default_output_config()?.into();
+        let channels = config.channels as usize;
+
+        let buffer = Arc::new(Mutex::new(VecDeque::new()));
+        let stream_buffer = buffer.clone();
+        let stream = device.build_output_stream(
+            &config,
+            move |data: &mut [f32], _| {
+                let mut buffer = stream_buffer.lock().unwrap();
+                for frame in data.chunks_mut(channels) {
+                    let sample = buffer.pop_front().unwrap_or(0) as f32 / 32768.0;
+                    frame.fill(sample);
+                }
+            },
+            |e| eprintln!("Audio stream error: {}", e),
+            None,
+        )?;
+        stream.play()?;
+
+        Ok(Self {
+            _stream: stream,
+            buffer,
+            sample_rate: config.sample_rate.0,
+        })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Queues samples, dropping the oldest once more than a quarter second
+    // is buffered so latency stays bounded when emulation runs fast
+    pub fn push(&self, samples: &[i16]) {
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.extend(samples);
+        let max_len = self.sample_rate as usize / 4;
+        while buffer.len() > max_len {
+            buffer.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resampler_follows_emulated_time() {
+        let mut reg = RegisterFile::new_zeroed();
+        reg.xout = 0xf0;
+
+        let mut resampler = Resampler::new(DEFAULT_SAMPLE_RATE);
+        let samples = (0..CLOCK_HZ)
+            .filter_map(|_| resampler.update(&reg))
+            .collect::<Vec<_>>();
+
+        assert_eq!(samples.len(), DEFAULT_SAMPLE_RATE as usize);
+        assert!(samples.iter().all(|&s| s == 15 * 1024));
+    }
+}
@@ -0,0 +1,38 @@
+use std::{error::Error, path::Path};
+
+use clap::Parser;
+use gigatron::asm::assembler;
+
+/// Assembles a Gigatron source file into .rom and .sym files
+#[derive(Parser)]
+struct Args {
+    /// Source file to assemble
+    source: String,
+
+    /// Output file name without extension, defaults to the source file name
+    #[arg(short, long)]
+    out: Option<String>,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+    let source = std::fs::read_to_string(&args.source)?;
+    let assembly = assembler::assemble(&source).map_err(|e| format!("{}: {}", args.source, e))?;
+
+    let stem = match args.out {
+        Some(out) => out,
+        None => Path::new(&args.source)
+            .with_extension("")
+            .to_string_lossy()
+            .into_owned(),
+    };
+    assembly.save(&stem)?;
+    println!(
+        "Wrote {} words to {}.rom and {} symbols to {}.sym",
+        assembly.rom.len(),
+        stem,
+        assembly.labels.len() + assembly.zero_page.len(),
+        stem
+    );
+    Ok(())
+}
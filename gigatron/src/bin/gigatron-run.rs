@@ -0,0 +1,318 @@
+use std::{error::Error, io::Write};
+
+use clap::Parser;
+use gigatron::{
+    audio::{self, Resampler, WavWriter},
+    cpu::{self, Cpu, PowerOn, PowerOnFill, RegisterFile},
+    gdb::GdbStub,
+    golden::{self, GoldenResult},
+    input::{InputScript, NO_BUTTONS},
+    movie::Movie,
+    profile::Profile,
+    savestate::SaveState,
+    screenshot,
+    symbols::SymbolTable,
+    vga::{Vga, VideoMode},
+    video::VideoWriter,
+};
+
+/// Runs a Gigatron ROM without opening a window
+#[derive(Parser)]
+struct Args {
+    /// ROM file to load
+    rom: String,
+
+    /// Stop after this many frames have been drawn
+    #[arg(long)]
+    frames: Option<u64>,
+
+    /// Stop after this many CPU cycles
+    #[arg(long)]
+    cycles: Option<u64>,
+
+    /// Seed for the power-on state and floating bus values
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Power-on contents of RAM and registers: random, zero or pattern
+    #[arg(long, default_value = "random")]
+    fill: PowerOnFill,
+
+    /// Save state to resume from instead of powering on
+    #[arg(long)]
+    load_state: Option<String>,
+
+    /// Write a save state of the final machine to this file
+    #[arg(long)]
+    save_state: Option<String>,
+
+    /// Input script with "<frame> <buttons>" lines
+    #[arg(long)]
+    input: Option<String>,
+
+    /// Replay a recorded movie from power on, runs to its end unless
+    /// --frames or --cycles is given
+    #[arg(long, conflicts_with_all = ["seed", "fill", "load_state", "input"])]
+    movie: Option<String>,
+
+    /// Record the input of every frame to this movie file
+    #[arg(long, conflicts_with = "load_state")]
+    record_movie: Option<String>,
+
+    /// Write the final RAM contents to this file
+    #[arg(long)]
+    ram_out: Option<String>,
+
+    /// Write the final register values to this file
+    #[arg(long)]
+    regs_out: Option<String>,
+
+    /// Record the audio output to this WAV file
+    #[arg(long)]
+    wav: Option<String>,
+
+    /// Sample rate of the recorded audio
+    #[arg(long, default_value_t = audio::DEFAULT_SAMPLE_RATE)]
+    sample_rate: u32,
+
+    /// Write the last completed frame to this file, as a PNG if the name
+    /// ends in .png and as a PPM otherwise
+    #[arg(long)]
+    frame_out: Option<String>,
+
+    /// Record every frame to this animated PNG file, at the frame rate of
+    /// the video mode. Combine with --wav to capture the audio alongside it.
+    #[arg(long)]
+    video: Option<String>,
+
+    /// Compare frames against reference images "frame<N>.png" in this
+    /// directory, writing diff images and failing on a mismatch
+    #[arg(long)]
+    golden: Option<String>,
+
+    /// Frame numbers to compare against the references, e.g. 10,50,100
+    #[arg(long, value_delimiter = ',', requires = "golden")]
+    golden_frames: Vec<u64>,
+
+    /// Write missing or different references instead of failing
+    #[arg(long, requires = "golden")]
+    update_golden: bool,
+
+    /// Sync timing the monitor expects: default, vesa-640x480, vga-640x400,
+    /// vga-640x350 or "front,pulse,back,visible/front,pulse,back,visible"
+    #[arg(long, default_value = "default")]
+    video_mode: VideoMode,
+
+    /// Write the cycles spent under each label to this CSV file
+    #[arg(long)]
+    profile: Option<String>,
+
+    /// Symbol file used to name the labels in --profile
+    #[arg(long, requires = "profile")]
+    symbols: Option<String>,
+
+    /// Serve the GDB remote protocol on this localhost port instead of running
+    #[arg(long, conflicts_with_all = ["frames", "cycles"])]
+    gdb: Option<u16>,
+}
+
+fn format_registers(reg: &RegisterFile) -> String {
+    format!(
+        "pc {:04x}\nir {:02x}\nd {:02x}\nac {:02x}\nx {:02x}\ny {:02x}\nout {:02x}\nxout {:02x}\n",
+        reg.pc, reg.ir.0, reg.d, reg.ac, reg.x, reg.y, reg.out, reg.xout
+    )
+}
+
+fn write_ppm(file_name: &str, vga: &Vga) -> Result<(), std::io::Error> {
+    let (width, height) = vga.size();
+    let mut file = std::io::BufWriter::new(std::fs::File::create(file_name)?);
+    write!(file, "P6\n{} {}\n255\n", width, height)?;
+    for pixel in vga.last_frame().chunks_exact(4) {
+        file.write_all(&pixel[..3])?;
+    }
+    file.flush()
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+    let no_limit = args.frames.is_none() && args.cycles.is_none();
+    if no_limit && args.gdb.is_none() && args.movie.is_none() {
+        return Err("One of --frames, --cycles, --movie or --gdb must be given".into());
+    }
+
+    let total_frame_cycles = args.video_mode.total_frame_cycles() as u64;
+
+    let script = match &args.input {
+        Some(file_name) => Some(InputScript::load(file_name)?),
+        None => None,
+    };
+
+    let power_on = PowerOn::new(args.seed, args.fill);
+    println!("Power-on seed {} ({} fill)", power_on.seed, power_on.fill);
+    let mut cpu = Cpu::new(cpu::load_rom(&args.rom)?, power_on);
+    let mut vga = Vga::from_mode(&args.video_mode);
+    if let Some(file_name) = &args.load_state {
+        SaveState::load(file_name)?.restore(&mut cpu, &mut vga)?;
+    }
+
+    let movie = match &args.movie {
+        Some(file_name) => {
+            let movie = Movie::load(file_name)?;
+            movie.start(&mut cpu)?;
+            println!(
+                "Playing {} frames, power-on seed {} ({} fill)",
+                movie.len(),
+                movie.power_on.seed,
+                movie.power_on.fill
+            );
+            Some(movie)
+        }
+        None => None,
+    };
+    let frames = match &movie {
+        Some(movie) if no_limit => Some(movie.len()),
+        _ => args.frames,
+    };
+    let mut recording = args.record_movie.as_ref().map(|_| Movie::new(&cpu));
+
+    let mut wav = match &args.wav {
+        Some(file_name) => Some((
+            WavWriter::create(file_name, args.sample_rate)?,
+            Resampler::new(args.sample_rate),
+        )),
+        None => None,
+    };
+
+    let mut video = match &args.video {
+        Some(file_name) => Some(VideoWriter::create(
+            file_name,
+            vga.size(),
+            total_frame_cycles as u32,
+        )?),
+        None => None,
+    };
+
+    if let Some(port) = args.gdb {
+        let mut stub = GdbStub::new(cpu, vga);
+        stub.serve(port)?;
+        (cpu, vga) = (stub.cpu, stub.vga);
+    }
+
+    let mut profile = args.profile.as_ref().map(|_| Profile::new());
+
+    // Frames and cycles are counted from the start of this run
+    let start_frame = vga.frame_count();
+    let mut cycles = 0;
+    let mut frame_start_cycle = 0;
+    let mut golden_failures = 0;
+    while args.gdb.is_none() {
+        let frame = vga.frame_count() - start_frame;
+        if frames.is_some_and(|f| frame >= f) || args.cycles.is_some_and(|c| cycles >= c) {
+            break;
+        }
+
+        cpu.input = match (&movie, &script) {
+            (Some(movie), _) => movie.input_at(frame).unwrap_or(NO_BUTTONS),
+            (None, Some(script)) => script.input_at(frame),
+            (None, None) => NO_BUTTONS,
+        };
+        if let Some(recording) = &mut recording {
+            if recording.len() == frame {
+                recording.inputs.push(cpu.input);
+            }
+        }
+        if let Some(profile) = &mut profile {
+            profile.record(cpu.state.queued_pc);
+        }
+        cpu.clock();
+        cycles += 1;
+
+        if let Some((wav, resampler)) = &mut wav {
+            if let Some(sample) = resampler.update(&cpu.state.reg) {
+                wav.write_sample(sample)?;
+            }
+        }
+
+        if vga.update(&cpu.state.reg).should_render {
+            frame_start_cycle = cycles;
+            if let Some(profile) = &mut profile {
+                profile.frame();
+            }
+
+            if let Some(video) = &mut video {
+                video.write_frame(vga.last_frame())?;
+            }
+
+            let frame = vga.frame_count() - start_frame;
+            if let Some(dir) = args
+                .golden
+                .as_ref()
+                .filter(|_| args.golden_frames.contains(&frame))
+            {
+                let name = format!("frame{}", frame);
+                match golden::check_frame(
+                    dir,
+                    &name,
+                    vga.size(),
+                    vga.last_frame(),
+                    args.update_golden,
+                )? {
+                    GoldenResult::Matched => println!("Frame {} matches", frame),
+                    GoldenResult::Updated => println!("Frame {} reference updated", frame),
+                    GoldenResult::Mismatch(diff) => {
+                        println!("Frame {} differs: {}", frame, diff);
+                        golden_failures += 1;
+                    }
+                }
+            }
+        } else if frames.is_some() && cycles - frame_start_cycle >= total_frame_cycles * 2 {
+            return Err(format!("CPU failed to produce frame in time at cycle {}", cycles).into());
+        }
+    }
+
+    println!(
+        "Ran {} cycles, {} frames",
+        cycles,
+        vga.frame_count() - start_frame
+    );
+    print!("{}", format_registers(&cpu.state.reg));
+
+    if let Some((wav, _)) = wav {
+        wav.finish(args.sample_rate)?;
+    }
+    if let Some(video) = video {
+        video.finish()?;
+    }
+    if let (Some(file_name), Some(profile)) = (&args.profile, &profile) {
+        let symbols = match &args.symbols {
+            Some(file_name) => SymbolTable::load(file_name)?,
+            None => SymbolTable::default(),
+        };
+        profile.save_csv(file_name, &symbols)?;
+    }
+    if let (Some(file_name), Some(recording)) = (&args.record_movie, &recording) {
+        recording.save(file_name)?;
+    }
+    if let Some(file_name) = &args.ram_out {
+        std::fs::write(file_name, &cpu.state.ram)?;
+    }
+    if let Some(file_name) = &args.regs_out {
+        std::fs::write(file_name, format_registers(&cpu.state.reg))?;
+    }
+    if let Some(file_name) = &args.save_state {
+        SaveState::capture(&cpu, &vga).save(file_name)?;
+    }
+    if let Some(file_name) = &args.frame_out {
+        if file_name.ends_with(".png") {
+            screenshot::save_png(file_name, &vga)?;
+        } else {
+            write_ppm(file_name, &vga)?;
+        }
+    }
+
+    if golden_failures > 0 {
+        return Err(format!("{} frames differ from their references", golden_failures).into());
+    }
+
+    Ok(())
+}
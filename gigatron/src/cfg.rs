@@ -0,0 +1,673 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::Display,
+};
+
+use packed_struct::{PackedStruct, PrimitiveEnum};
+
+use crate::{
+    asm::{Bus, Instruction, Mode, Operation},
+    cpu::RomWord,
+    symbols::{Placeholder, SymbolTable},
+};
+
+// Where the pipeline is: the instruction executing now and the address
+// fetched behind it, which only differ from cur + 1 in a branch delay slot.
+// AC is tracked while it holds a constant so that delay loops like the ones
+// from wait() in asm.py unroll.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PipelineState {
+    pub cur: u16,
+    pub next: u16,
+    pub ac: Option<u8>,
+}
+
+impl PipelineState {
+    pub fn at(addr: u16) -> Self {
+        Self {
+            cur: addr,
+            next: addr.wrapping_add(1),
+            ac: None,
+        }
+    }
+}
+
+// AC after executing inst, if it is still a known constant
+fn execute_ac(inst: &Instruction, data: u8, ac: Option<u8>) -> Option<u8> {
+    let writes_ac = matches!(
+        inst.mode,
+        Mode::Acc_D_Far | Mode::Acc_X_Gt | Mode::Acc_Y_D_Lt | Mode::Acc_Y_X_Ne
+    );
+    if matches!(inst.op, Operation::Store | Operation::Jump) || !writes_ac {
+        return ac;
+    }
+
+    let b = match inst.bus {
+        Bus::Data => data,
+        Bus::Acc => ac?,
+        Bus::Ram | Bus::In => return None,
+    };
+    match inst.op {
+        Operation::Load => Some(b),
+        Operation::And => Some(ac? & b),
+        Operation::Or => Some(ac? | b),
+        Operation::Xor => Some(ac? ^ b),
+        Operation::Add => Some(ac?.wrapping_add(b)),
+        Operation::Sub => Some(ac?.wrapping_sub(b)),
+        Operation::Store | Operation::Jump => ac,
+    }
+}
+
+// Whether a conditional branch is taken, the same test as Cpu::clock
+fn condition_met(mode: Mode, ac: u8) -> bool {
+    let cond = (ac >> 7) + if ac == 0 { 2 } else { 0 };
+    mode.to_primitive() & (1 << cond) != 0
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CycleRange {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl CycleRange {
+    fn union(self, other: Self) -> Self {
+        Self {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+}
+
+impl Display for CycleRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.min == self.max {
+            write!(f, "{}", self.min)
+        } else {
+            write!(f, "{}-{}", self.min, self.max)
+        }
+    }
+}
+
+// A conditional branch whose two sides take different times to reach the end
+#[derive(Debug, PartialEq)]
+pub struct UnbalancedBranch {
+    pub addr: u16,
+    pub taken: CycleRange,
+    pub not_taken: CycleRange,
+}
+
+#[derive(Debug, Default)]
+pub struct PathTiming {
+    // Cycles from the start until the end is about to execute, None if no
+    // path gets there. Only the minimum holds when there are loops, since
+    // they can go around any number of times.
+    pub cycles: Option<CycleRange>,
+    pub unbalanced: Vec<UnbalancedBranch>,
+    // Jumps through AC or RAM, and paths running off the end of the ROM
+    pub unresolved: Vec<u16>,
+    // Jumps back into the path before it reached the end
+    pub loops: Vec<u16>,
+}
+
+impl PathTiming {
+    pub fn is_bounded(&self) -> bool {
+        self.loops.is_empty()
+    }
+}
+
+// Control flow decoded from the instructions of a ROM, using the symbols to
+// resolve far jumps
+pub struct ControlFlow<'a> {
+    rom: &'a [RomWord],
+    symbols: &'a SymbolTable,
+}
+
+impl<'a> ControlFlow<'a> {
+    pub fn new(rom: &'a [RomWord], symbols: &'a SymbolTable) -> Self {
+        Self { rom, symbols }
+    }
+
+    pub fn decode(&self, addr: u16) -> Option<Instruction> {
+        let word = self.rom.get(addr as usize)?;
+        Instruction::unpack(&[word.inst.0]).ok()
+    }
+
+    // Whether the jump is taken no matter what AC holds
+    pub fn is_unconditional(inst: &Instruction) -> bool {
+        matches!(inst.mode, Mode::Acc_D_Far | Mode::Out_Y_Xpp_Bra)
+    }
+
+    // Y loaded with a constant since the last entry point before addr
+    fn known_y(&self, addr: u16) -> Option<u8> {
+        let mut a = addr;
+        loop {
+            if a == 0 || self.symbols.labels.contains_key(&a) {
+                return None;
+            }
+            a -= 1;
+            let inst = self.decode(a)?;
+            if inst.op == Operation::Jump {
+                return None;
+            }
+            if inst.mode == Mode::Y_D_Ge {
+                let constant = inst.op == Operation::Load && inst.bus == Bus::Data;
+                return constant.then_some(self.rom[a as usize].data);
+            }
+        }
+    }
+
+    // Target of the jump at addr, None if it depends on AC or RAM. Far jumps
+    // need Y loaded just before them or a lo(label) operand.
+    pub fn jump_target(&self, addr: u16) -> Option<u16> {
+        self.pipeline_jump_target(PipelineState::at(addr))
+    }
+
+    // Like jump_target for the jump at state.cur. Branches stay in the page
+    // of state.next, which is only the delay slot's page when the jump isn't
+    // itself in the delay slot of another.
+    fn pipeline_jump_target(&self, state: PipelineState) -> Option<u16> {
+        let addr = state.cur;
+        let inst = self.decode(addr)?;
+        if inst.op != Operation::Jump || inst.bus != Bus::Data {
+            return None;
+        }
+        let lo = self.rom[addr as usize].data as u16;
+        if inst.mode != Mode::Acc_D_Far {
+            return Some((state.next & 0xff00) | lo);
+        }
+        if let Some(y) = self.known_y(addr) {
+            return Some(((y as u16) << 8) | lo);
+        }
+        match self.symbols.placeholders.get(&addr) {
+            Some(Placeholder::Unary { name, val }) if name == "lo" => {
+                let target = self.symbols.find_label(&val.to_string())?;
+                (target & 0xff == lo).then_some(target)
+            }
+            _ => None,
+        }
+    }
+
+    // States after executing state.cur, taken branch first. None if the
+    // target of a jump is unknown.
+    pub fn successors(&self, state: PipelineState) -> Option<Vec<PipelineState>> {
+        let inst = self.decode(state.cur)?;
+        let data = self.rom[state.cur as usize].data;
+        let fall_through = PipelineState {
+            cur: state.next,
+            next: state.next.wrapping_add(1),
+            ac: execute_ac(&inst, data, state.ac),
+        };
+        if inst.op != Operation::Jump {
+            return Some(vec![fall_through]);
+        }
+
+        let taken = |target| PipelineState {
+            cur: state.next,
+            next: target,
+            ac: state.ac,
+        };
+        if Self::is_unconditional(&inst) {
+            return Some(vec![taken(self.pipeline_jump_target(state)?)]);
+        }
+        match state.ac.map(|ac| condition_met(inst.mode, ac)) {
+            Some(false) => Some(vec![fall_through]),
+            Some(true) => Some(vec![taken(self.pipeline_jump_target(state)?)]),
+            None => Some(vec![taken(self.pipeline_jump_target(state)?), fall_through]),
+        }
+    }
+
+    // Counts cycles along every path from start until end is next to execute,
+    // one per instruction. With start == end this times one trip around a
+    // loop, like a scanline of the video loop.
+    pub fn path_timing(&self, start: u16, end: u16) -> PathTiming {
+        let mut result = PathTiming::default();
+        let mut dist: HashMap<PipelineState, Option<CycleRange>> = HashMap::new();
+        let mut on_stack = HashSet::new();
+        let dist_of = |dist: &HashMap<_, _>, s: &PipelineState| {
+            if s.cur == end {
+                Some(CycleRange { min: 0, max: 0 })
+            } else {
+                dist.get(s).copied().flatten()
+            }
+        };
+
+        // Depth first without recursion, states are revisited once all of
+        // their successors are done
+        let root = PipelineState::at(start);
+        let mut stack = vec![(root, false)];
+        while let Some((state, expanded)) = stack.pop() {
+            let succs = self.successors(state);
+            if !expanded {
+                if dist.contains_key(&state) || (state.cur == end && state != root) {
+                    continue;
+                }
+                on_stack.insert(state);
+                stack.push((state, true));
+                match &succs {
+                    Some(succs) => {
+                        for succ in succs {
+                            if succ.cur == end {
+                                continue;
+                            }
+                            if on_stack.contains(succ) {
+                                result.loops.push(state.cur);
+                            } else if !dist.contains_key(succ) {
+                                stack.push((*succ, false));
+                            }
+                        }
+                    }
+                    None => result.unresolved.push(state.cur),
+                }
+                continue;
+            }
+
+            on_stack.remove(&state);
+            let succ_dists = succs
+                .unwrap_or_default()
+                .iter()
+                .map(|s| dist_of(&dist, s))
+                .collect::<Vec<_>>();
+            if let [Some(taken), Some(not_taken)] = succ_dists[..] {
+                if taken != not_taken {
+                    result.unbalanced.push(UnbalancedBranch {
+                        addr: state.cur,
+                        taken: CycleRange {
+                            min: taken.min + 1,
+                            max: taken.max + 1,
+                        },
+                        not_taken: CycleRange {
+                            min: not_taken.min + 1,
+                            max: not_taken.max + 1,
+                        },
+                    });
+                }
+            }
+            let range = succ_dists
+                .into_iter()
+                .flatten()
+                .reduce(CycleRange::union)
+                .map(|r| CycleRange {
+                    min: r.min + 1,
+                    max: r.max + 1,
+                });
+            dist.insert(state, range);
+        }
+
+        result.cycles = dist[&root];
+        result.unbalanced.sort_by_key(|b| b.addr);
+        result.unbalanced.dedup_by_key(|b| b.addr);
+        for addrs in [&mut result.unresolved, &mut result.loops] {
+            addrs.sort_unstable();
+            addrs.dedup();
+        }
+        result
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EdgeKind {
+    Taken,
+    FallThrough,
+    // Only seen while running, the jump goes through AC, RAM or Y
+    Runtime,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Edge {
+    pub target: u16,
+    pub kind: EdgeKind,
+}
+
+// Straight-line run of instructions, ending after the delay slot of a jump
+// or where another block starts
+#[derive(Debug)]
+pub struct BasicBlock {
+    pub start: u16,
+    pub len: u16,
+    // The jump ending the block
+    pub jump: Option<u16>,
+    // Whether the target of the jump is only known at runtime
+    pub computed: bool,
+    pub edges: Vec<Edge>,
+}
+
+impl BasicBlock {
+    pub fn contains(&self, addr: u16) -> bool {
+        addr.wrapping_sub(self.start) < self.len
+    }
+
+    pub fn addrs(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..self.len).map(|i| self.start.wrapping_add(i))
+    }
+}
+
+// Basic blocks of a whole ROM, split at labels, jumps and jump targets
+pub struct BlockGraph {
+    blocks: BTreeMap<u16, BasicBlock>,
+    preds: HashMap<u16, Vec<u16>>,
+}
+
+impl BlockGraph {
+    pub fn build(flow: &ControlFlow) -> Self {
+        let len = flow.rom.len();
+        let is_jump = |a: usize| {
+            flow.decode(a as u16)
+                .is_some_and(|inst| inst.op == Operation::Jump)
+        };
+
+        let mut leaders: BTreeSet<usize> =
+            flow.symbols.labels.keys().map(|&a| a as usize).collect();
+        for a in (0..len).filter(|&a| is_jump(a)) {
+            if let Some(target) = flow.jump_target(a as u16) {
+                leaders.insert(target as usize);
+            }
+            leaders.insert(a + 2);
+        }
+
+        let mut blocks = BTreeMap::new();
+        let mut start = 0;
+        while start < len {
+            let mut a = start;
+            let (end, jump) = loop {
+                if is_jump(a) {
+                    // The delay slot always runs with the jump
+                    break ((a + 2).min(len), Some(a as u16));
+                }
+                if a + 1 >= len || leaders.contains(&(a + 1)) {
+                    break (a + 1, None);
+                }
+                a += 1;
+            };
+
+            let mut edges = vec![];
+            let mut computed = false;
+            let mut falls_through = true;
+            if let Some(jump) = jump {
+                match flow.jump_target(jump) {
+                    Some(target) => edges.push(Edge {
+                        target,
+                        kind: EdgeKind::Taken,
+                    }),
+                    None => computed = true,
+                }
+                falls_through = !ControlFlow::is_unconditional(&flow.decode(jump).unwrap());
+            }
+            if falls_through && end < len {
+                edges.push(Edge {
+                    target: end as u16,
+                    kind: EdgeKind::FallThrough,
+                });
+            }
+
+            blocks.insert(
+                start as u16,
+                BasicBlock {
+                    start: start as u16,
+                    len: (end - start) as u16,
+                    jump,
+                    computed,
+                    edges,
+                },
+            );
+            start = end;
+        }
+
+        let mut graph = Self {
+            blocks,
+            preds: HashMap::new(),
+        };
+        let mut preds: HashMap<u16, Vec<u16>> = HashMap::new();
+        for block in graph.blocks.values() {
+            for edge in &block.edges {
+                if let Some(target) = graph.block_at(edge.target) {
+                    preds.entry(target.start).or_default().push(block.start);
+                }
+            }
+        }
+        graph.preds = preds;
+        graph
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn block_at(&self, addr: u16) -> Option<&BasicBlock> {
+        let (_, block) = self.blocks.range(..=addr).next_back()?;
+        block.contains(addr).then_some(block)
+    }
+
+    // Starts of the blocks with a static edge into the block at start
+    pub fn predecessors(&self, start: u16) -> &[u16] {
+        self.preds.get(&start).map_or(&[], Vec::as_slice)
+    }
+}
+
+// Targets seen while running for jumps that cannot be followed statically,
+// like the jmp y,[nextCodeLo] dispatch of the video loop
+#[derive(Default)]
+pub struct ObservedJumps {
+    last: Option<u16>,
+    targets: BTreeMap<u16, BTreeSet<u16>>,
+}
+
+impl ObservedJumps {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    // Starts over from the next recorded instruction, for when execution
+    // doesn't continue from the last one
+    pub fn forget_last(&mut self) {
+        self.last = None;
+    }
+
+    // Called with the address of every executed instruction. Execution only
+    // skips ahead once the delay slot after a taken jump has run.
+    pub fn record(&mut self, rom: &[RomWord], pc: u16) {
+        let Some(last) = self.last.replace(pc) else {
+            return;
+        };
+        if pc == last.wrapping_add(1) {
+            return;
+        }
+        let jump = last.wrapping_sub(1);
+        let Some(word) = rom.get(jump as usize) else {
+            return;
+        };
+        let computed = Instruction::unpack(&[word.inst.0]).is_ok_and(|inst| {
+            inst.op == Operation::Jump && (inst.bus != Bus::Data || inst.mode == Mode::Acc_D_Far)
+        });
+        if computed {
+            self.targets.entry(jump).or_default().insert(pc);
+        }
+    }
+
+    pub fn targets(&self, jump: u16) -> impl Iterator<Item = u16> + '_ {
+        self.targets.get(&jump).into_iter().flatten().copied()
+    }
+
+    // Jumps seen going to addr
+    pub fn sources(&self, addr: u16) -> impl Iterator<Item = u16> + '_ {
+        self.targets
+            .iter()
+            .filter(move |(_, targets)| targets.contains(&addr))
+            .map(|(&jump, _)| jump)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assembler;
+
+    fn build(source: &str) -> (Vec<RomWord>, SymbolTable) {
+        let asm = assembler::assemble(source).unwrap();
+        let mut symbols = SymbolTable::default();
+        for (name, addr) in asm.labels {
+            symbols.labels.insert(addr, name);
+        }
+        (asm.rom, symbols)
+    }
+
+    #[test]
+    fn times_paths_through_branches() {
+        let (rom, symbols) = build(
+            "
+            start:  ld    [$10]
+                    beq   even
+                    ld    $00          ; Delay slot
+                    nop
+                    bra   join
+                    nop
+            even:   nop
+                    nop
+            join:   ld    hi(far),y
+                    jmp   y,far
+                    nop
+                    .fill $10
+            far:    bra   far
+                    nop
+            delay:  ld    2
+                    bne   delay+1
+                    suba  1
+                    nop
+            ",
+        );
+        let flow = ControlFlow::new(&rom, &symbols);
+        let label = |name| symbols.find_label(name).unwrap();
+
+        let timing = flow.path_timing(label("start"), label("join"));
+        assert_eq!(timing.cycles, Some(CycleRange { min: 5, max: 6 }));
+        assert_eq!(
+            timing.unbalanced,
+            vec![UnbalancedBranch {
+                addr: 1,
+                taken: CycleRange { min: 4, max: 4 },
+                not_taken: CycleRange { min: 5, max: 5 },
+            }]
+        );
+        assert!(timing.unresolved.is_empty() && timing.loops.is_empty());
+
+        // Around the loop at far, through its delay slot
+        let timing = flow.path_timing(label("far"), label("far"));
+        assert_eq!(timing.cycles, Some(CycleRange { min: 2, max: 2 }));
+
+        // Counted down with AC, so the loop runs a known number of times
+        let timing = flow.path_timing(label("delay"), label("delay") + 3);
+        assert_eq!(timing.cycles, Some(CycleRange { min: 7, max: 7 }));
+        assert!(timing.loops.is_empty());
+
+        // The far jump resolves through Y into a loop that never gets back
+        let timing = flow.path_timing(label("join"), label("start"));
+        assert_eq!(timing.cycles, None);
+        assert_eq!(timing.loops, vec![label("far") + 1]);
+    }
+
+    #[test]
+    fn times_paths_through_loops_and_delay_slots() {
+        let (rom, symbols) = build(
+            "
+            start:  ld    hi(other),y
+                    jmp   y,other
+                    bra   end          ; In the delay slot, lands in other's page
+                    nop
+            wait:   ld    [$10]
+                    bne   wait
+                    nop
+            done:   nop
+                    .align $100
+            other:  nop
+                    .fill $20
+            end:    nop
+            ",
+        );
+        let flow = ControlFlow::new(&rom, &symbols);
+        let label = |name| symbols.find_label(name).unwrap();
+
+        let timing = flow.path_timing(label("start"), label("end"));
+        assert_eq!(timing.cycles, Some(CycleRange { min: 4, max: 4 }));
+        assert!(timing.is_bounded());
+
+        // Polling RAM can loop forever, only the shortest path is known
+        let timing = flow.path_timing(label("wait"), label("done"));
+        assert_eq!(timing.cycles.map(|c| c.min), Some(3));
+        assert!(!timing.is_bounded());
+        assert_eq!(timing.loops, vec![label("wait") + 2]);
+    }
+
+    #[test]
+    fn splits_blocks_at_labels_and_jumps() {
+        let (rom, symbols) = build(
+            "
+            start:  ld    [$10]
+                    beq   even
+                    nop
+                    nop
+            even:   ld    $01,y
+                    jmp   y,[$20]
+                    nop
+            ",
+        );
+        let graph = BlockGraph::build(&ControlFlow::new(&rom, &symbols));
+        assert_eq!(graph.len(), 3);
+
+        let first = graph.block_at(1).unwrap();
+        assert_eq!((first.start, first.len, first.jump), (0, 3, Some(1)));
+        assert_eq!(
+            first.edges,
+            vec![
+                Edge {
+                    target: 4,
+                    kind: EdgeKind::Taken,
+                },
+                Edge {
+                    target: 3,
+                    kind: EdgeKind::FallThrough,
+                },
+            ]
+        );
+        assert_eq!(graph.predecessors(4), &[0, 3]);
+        let last = graph.block_at(6).unwrap();
+        assert!(last.computed && last.edges.is_empty());
+
+        // The computed jump lands on start after its delay slot
+        let mut observed = ObservedJumps::new();
+        for pc in [4, 5, 6, 0, 1, 2, 4] {
+            observed.record(&rom, pc);
+        }
+        assert_eq!(observed.targets(5).collect::<Vec<_>>(), vec![0]);
+        assert_eq!(observed.sources(0).collect::<Vec<_>>(), vec![5]);
+        assert!(observed.sources(4).next().is_none());
+
+        // Stepping back from the delay slot isn't a jump to where it resumes
+        observed.record(&rom, 6);
+        observed.forget_last();
+        observed.record(&rom, 3);
+        assert!(observed.sources(3).next().is_none());
+    }
+
+    #[test]
+    fn reports_computed_jumps() {
+        let (rom, symbols) = build(
+            "
+            start:  ld    [$10]
+                    bra   ac
+                    nop
+                    nop
+            ",
+        );
+        let timing = ControlFlow::new(&rom, &symbols).path_timing(0, 3);
+        assert_eq!(timing.cycles, None);
+        assert_eq!(timing.unresolved, vec![1]);
+    }
+}
@@ -1,11 +1,15 @@
-use std::fmt::Debug;
+use std::{fmt::Debug, fmt::Display, str::FromStr};
 
+use itertools::Itertools;
 use packed_struct::{PackedStruct, PrimitiveEnum};
-use rand::Rng;
+use rand::{Rng, RngCore};
 
-use crate::asm::{Bus, Instruction, Mode, Operation, NOP};
+use crate::{
+    asm::{Bus, Instruction, Mode, Operation, NOP},
+    vga::HSYNC,
+};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct RegisterFile {
     pub pc: u16,    // Program counter
     pub ir: OpCode, // Instruction register
@@ -14,12 +18,12 @@ pub struct RegisterFile {
     pub x: u8,      // Address X register
     pub y: u8,      // Address Y register
     pub out: u8,    // Output register
+    pub xout: u8,   // Extended output register (LEDs and audio)
     pub undef: u8,  // Undefined value (floating bus)
 }
 
 impl RegisterFile {
-    pub fn new_random() -> Self {
-        let mut r = rand::thread_rng();
+    pub fn new_random(r: &mut impl Rng) -> Self {
         Self {
             pc: r.gen(),
             ir: OpCode(r.gen()),
@@ -28,13 +32,139 @@ impl RegisterFile {
             x: r.gen(),
             y: r.gen(),
             out: r.gen(),
+            xout: r.gen(),
             undef: r.gen(),
         }
     }
+
+    pub fn new_zeroed() -> Self {
+        Self {
+            pc: 0,
+            ir: OpCode(0),
+            d: 0,
+            ac: 0,
+            x: 0,
+            y: 0,
+            out: 0,
+            xout: 0,
+            undef: 0,
+        }
+    }
+
+    // Lower nibble of XOUT drives the four LEDs
+    pub fn leds(&self) -> u8 {
+        self.xout & 0x0f
+    }
+
+    // Upper nibble of XOUT is the 4-bit audio sample
+    pub fn audio_sample(&self) -> u8 {
+        self.xout >> 4
+    }
+}
+
+// SplitMix64, small enough that its whole state is one seedable word
+#[derive(Clone)]
+pub struct CpuRng {
+    pub state: u64,
 }
 
+impl CpuRng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+}
+
+impl RngCore for CpuRng {
+    fn next_u32(&mut self) -> u32 {
+        self.next_u64() as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+// What RAM and registers contain after a hard reset
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PowerOnFill {
+    Random,  // Random registers and RAM, like real hardware
+    Zero,    // Everything zeroed
+    Pattern, // Zeroed registers, each RAM byte holds the low byte of its address
+}
+
+impl PowerOnFill {
+    pub const ALL: [Self; 3] = [Self::Random, Self::Zero, Self::Pattern];
+}
+
+impl Display for PowerOnFill {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Random => "random",
+            Self::Zero => "zero",
+            Self::Pattern => "pattern",
+        })
+    }
+}
+
+impl FromStr for PowerOnFill {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|fill| fill.to_string() == s)
+            .ok_or_else(|| format!("Unknown fill mode {}, expected random, zero or pattern", s))
+    }
+}
+
+// Everything needed to reproduce a power-on state
 #[derive(Clone, Copy)]
+pub struct PowerOn {
+    pub seed: u64,
+    pub fill: PowerOnFill,
+}
+
+impl PowerOn {
+    // Picks a fresh seed if none is given, so every run has one to report
+    pub fn new(seed: Option<u64>, fill: PowerOnFill) -> Self {
+        Self {
+            seed: seed.unwrap_or_else(rand::random),
+            fill,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct OpCode(pub u8);
+
+impl OpCode {
+    // Whether executing this instruction loads the OUT register
+    pub fn writes_out(self) -> bool {
+        match Instruction::unpack(&[self.0]) {
+            Ok(inst) => {
+                !matches!(inst.op, Operation::Store | Operation::Jump)
+                    && matches!(inst.mode, Mode::Out_D_Le | Mode::Out_Y_Xpp_Bra)
+            }
+            Err(_) => false,
+        }
+    }
+}
 impl Debug for OpCode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}=", self.0)?;
@@ -45,17 +175,56 @@ impl Debug for OpCode {
 pub const RAM_SIZE: usize = 1 << 15;
 pub const ROM_SIZE: usize = 1 << 16;
 
-#[derive(Debug)]
+// Gigatron crystal frequency, one instruction per cycle
+pub const CLOCK_HZ: u32 = 6_250_000;
+
+#[derive(Clone, Copy, Debug)]
 pub struct RomWord {
     pub inst: OpCode,
     pub data: u8,
 }
 
+pub fn load_rom(file_name: &str) -> Result<Vec<RomWord>, std::io::Error> {
+    let bytes = std::fs::read(file_name)?;
+
+    let rom = bytes
+        .into_iter()
+        .tuples()
+        .map(|(opcode, data)| RomWord {
+            inst: OpCode(opcode),
+            data,
+        })
+        .collect_vec();
+
+    Ok(rom)
+}
+
+// Writes the ROM in the format read by load_rom
+pub fn save_rom(file_name: &str, rom: &[RomWord]) -> Result<(), std::io::Error> {
+    let bytes = rom
+        .iter()
+        .flat_map(|word| [word.inst.0, word.data])
+        .collect_vec();
+    std::fs::write(file_name, bytes)
+}
+
+// FNV-1a over the ROM contents, used to tie saved data to a ROM
+pub fn rom_hash(rom: &[RomWord]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for word in rom {
+        for byte in [word.inst.0, word.data] {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
 enum ResultDest {
-    AC,
+    Ac,
     X,
     Y,
-    OUT,
+    Out,
     None,
 }
 
@@ -66,8 +235,8 @@ impl ResultDest {
         }
 
         match self {
-            Self::AC => Self::None,
-            Self::OUT => Self::None,
+            Self::Ac => Self::None,
+            Self::Out => Self::None,
             Self::X => Self::X,
             Self::Y => Self::Y,
             Self::None => Self::None,
@@ -87,9 +256,16 @@ pub struct MemAccess {
 
 pub struct CycleInfo {
     pub mem_access: Option<MemAccess>,
-}
 
-// TODO: Switch to new CPU state API
+    // Whether XOUT latched a new value this cycle
+    pub xout_latched: bool,
+
+    // Address of the instruction executed this cycle, if it loaded OUT
+    pub out_pc: Option<u16>,
+
+    // Diff that undoes this cycle when applied
+    pub reverse: CycleDiff,
+}
 
 #[derive(Clone)]
 pub enum MemDiffOp {
@@ -106,11 +282,16 @@ pub struct MemDiff {
     pub op: MemDiffOp,
 }
 
+#[derive(Clone)]
 pub struct CycleDiff {
     // TODO
     new_reg: RegisterFile,
     queued_pc: u16,
     mem: Option<MemDiff>,
+
+    // Floating bus RNG state, set in diffs made by Cpu so that rewinding
+    // replays the same undefined values
+    rng_state: Option<u64>,
 }
 
 pub struct CpuState {
@@ -120,19 +301,26 @@ pub struct CpuState {
 }
 
 impl CpuState {
-    pub fn hard_reset(rom: &[RomWord]) -> Self {
-        let mut state = Self {
-            ram: Vec::with_capacity(RAM_SIZE),
-            reg: RegisterFile::new_random(),
-            queued_pc: 0,
+    pub fn hard_reset(rom: &[RomWord], fill: PowerOnFill, r: &mut impl Rng) -> Self {
+        let mut state = match fill {
+            // Fill RAM with random garbage
+            PowerOnFill::Random => Self {
+                reg: RegisterFile::new_random(r),
+                ram: (0..RAM_SIZE).map(|_| r.gen()).collect(),
+                queued_pc: 0,
+            },
+            PowerOnFill::Zero => Self {
+                reg: RegisterFile::new_zeroed(),
+                ram: vec![0; RAM_SIZE],
+                queued_pc: 0,
+            },
+            PowerOnFill::Pattern => Self {
+                reg: RegisterFile::new_zeroed(),
+                ram: (0..RAM_SIZE).map(|addr| addr as u8).collect(),
+                queued_pc: 0,
+            },
         };
 
-        // Fill RAM with random garbage
-        let mut r = rand::thread_rng();
-        for _ in 0..RAM_SIZE {
-            state.ram.push(r.gen());
-        }
-
         state.soft_reset(rom);
         state
     }
@@ -151,28 +339,27 @@ impl CpuState {
         CycleDiff {
             new_reg: std::mem::replace(&mut self.reg, diff.new_reg),
             queued_pc: std::mem::replace(&mut self.queued_pc, diff.queued_pc),
-            mem: match diff.mem {
-                Some(mem) => Some(match mem.op {
-                    MemDiffOp::Read => mem,
-                    MemDiffOp::Write(val) => {
-                        let ram_addr = (mem.addr & 0x7FFF) as usize;
-                        let prev_val = self.ram[ram_addr];
-                        self.ram[ram_addr] = val;
-
-                        MemDiff {
-                            addr: mem.addr,
-                            op: MemDiffOp::Write(prev_val),
-                        }
+            rng_state: None,
+            mem: diff.mem.map(|mem| match mem.op {
+                MemDiffOp::Read => mem,
+                MemDiffOp::Write(val) => {
+                    let ram_addr = (mem.addr & 0x7FFF) as usize;
+                    let prev_val = self.ram[ram_addr];
+                    self.ram[ram_addr] = val;
+
+                    MemDiff {
+                        addr: mem.addr,
+                        op: MemDiffOp::Write(prev_val),
                     }
-                }),
-                None => None,
-            },
+                }
+            }),
         }
     }
 
-    pub fn clock(&self, rom: &[RomWord], input: u8) -> CycleDiff {
+    // The floating bus value for the next cycle is supplied by the caller
+    pub fn clock(&self, rom: &[RomWord], input: u8, undef: u8) -> CycleDiff {
         let mut new_reg = self.reg.clone();
-        new_reg.undef = rand::random();
+        new_reg.undef = undef;
 
         let word = &rom[self.reg.pc as usize];
         new_reg.ir = word.inst;
@@ -193,25 +380,25 @@ impl CpuState {
         let mut inc_x = false;
         if !jump {
             match mode {
-                Mode::Acc_D_Far => to = ResultDest::AC,
+                Mode::Acc_D_Far => to = ResultDest::Ac,
                 Mode::Acc_X_Gt => {
-                    to = ResultDest::AC;
+                    to = ResultDest::Ac;
                     lo = self.reg.x;
                 }
                 Mode::Acc_Y_D_Lt => {
-                    to = ResultDest::AC;
+                    to = ResultDest::Ac;
                     hi = self.reg.y;
                 }
                 Mode::Acc_Y_X_Ne => {
-                    to = ResultDest::AC;
+                    to = ResultDest::Ac;
                     lo = self.reg.x;
                     hi = self.reg.y;
                 }
                 Mode::X_D_Eq => to = ResultDest::X,
                 Mode::Y_D_Ge => to = ResultDest::Y,
-                Mode::Out_D_Le => to = ResultDest::OUT,
+                Mode::Out_D_Le => to = ResultDest::Out,
                 Mode::Out_Y_Xpp_Bra => {
-                    to = ResultDest::OUT;
+                    to = ResultDest::Out;
                     lo = self.reg.x;
                     hi = self.reg.y;
                     inc_x = true;
@@ -257,10 +444,10 @@ impl CpuState {
         };
 
         match to {
-            ResultDest::AC => new_reg.ac = alu,
+            ResultDest::Ac => new_reg.ac = alu,
             ResultDest::X => new_reg.x = alu,
             ResultDest::Y => new_reg.y = alu,
-            ResultDest::OUT => new_reg.out = alu,
+            ResultDest::Out => new_reg.out = alu,
             ResultDest::None => {}
         }
         if inc_x {
@@ -280,178 +467,390 @@ impl CpuState {
             }
         }
 
+        // XOUT latches AC on the rising edge of HSYNC
+        if !self.reg.out & new_reg.out & HSYNC != 0 {
+            new_reg.xout = self.reg.ac;
+        }
+
         CycleDiff {
             new_reg,
             queued_pc: self.reg.pc,
             mem,
+            rng_state: None,
         }
     }
 }
 
+// Imperative wrapper around CpuState that owns the ROM and input port
 pub struct Cpu {
     pub rom: Vec<RomWord>,
-
-    pub reg: RegisterFile,
-    pub ram: Vec<u8>,
-    pub queued_pc: u16,
-
+    pub state: CpuState,
     pub input: u8,
+
+    // Takes effect on the next hard reset
+    pub power_on: PowerOn,
+    pub rng: CpuRng,
 }
 
 impl Cpu {
-    pub fn new(rom: Vec<RomWord>) -> Self {
-        let mut cpu = Self {
-            reg: RegisterFile::new_random(),
-            ram: Vec::with_capacity(RAM_SIZE),
+    pub fn new(rom: Vec<RomWord>, power_on: PowerOn) -> Self {
+        let mut rng = CpuRng::new(power_on.seed);
+        Self {
+            state: CpuState::hard_reset(&rom, power_on.fill, &mut rng),
             rom,
             input: 0,
-            queued_pc: 0,
-        };
-
-        let mut r = rand::thread_rng();
-        for _ in 0..RAM_SIZE {
-            cpu.ram.push(r.gen());
+            power_on,
+            rng,
         }
-
-        cpu.hard_reset();
-        cpu
     }
 
     pub fn soft_reset(&mut self) {
-        self.reg.pc = 0;
-        self.clock();
-        self.reg.pc = 0;
-        self.queued_pc = 0;
+        self.state.soft_reset(&self.rom);
     }
 
+    // Reseeds the RNG, so a hard reset always reproduces the same run
     pub fn hard_reset(&mut self) {
-        let mut r = rand::thread_rng();
-        self.ram.clear();
-        for _ in 0..RAM_SIZE {
-            self.ram.push(r.gen());
-        }
-        self.soft_reset();
+        self.rng = CpuRng::new(self.power_on.seed);
+        self.state = CpuState::hard_reset(&self.rom, self.power_on.fill, &mut self.rng);
     }
 
-    pub fn clock(&mut self) -> CycleInfo {
-        let mut new_reg = self.reg.clone();
-        new_reg.undef = rand::random();
-
-        let word = &self.rom[self.reg.pc as usize];
-        new_reg.ir = word.inst;
-        new_reg.d = word.data;
-        self.queued_pc = self.reg.pc;
+    // Returns a diff that reverses the changes done by the given diff
+    pub fn apply_diff(&mut self, diff: CycleDiff) -> CycleDiff {
+        let rng_state = diff
+            .rng_state
+            .map(|state| std::mem::replace(&mut self.rng.state, state));
+        let mut reverse = self.state.apply_diff(diff);
+        reverse.rng_state = rng_state;
+        reverse
+    }
 
-        let Instruction {
-            op: inst,
-            mode,
-            bus,
-        } = Instruction::unpack(&[self.reg.ir.0]).unwrap_or(NOP);
+    pub fn clock(&mut self) -> CycleInfo {
+        let rng_state = self.rng.state;
+        let undef = self.rng.gen();
+        let out_pc = self
+            .state
+            .reg
+            .ir
+            .writes_out()
+            .then_some(self.state.queued_pc);
+        let diff = self.state.clock(&self.rom, self.input, undef);
+        let mut reverse = self.state.apply_diff(diff);
+        reverse.rng_state = Some(rng_state);
+
+        let mem_access = reverse.mem.as_ref().map(|mem| {
+            let val = self.state.ram[(mem.addr & 0x7fff) as usize];
+            MemAccess {
+                addr: mem.addr,
+                op: match mem.op {
+                    MemDiffOp::Read => MemOperation::Read { val },
+                    MemDiffOp::Write(prev_val) => MemOperation::Write {
+                        prev_val,
+                        new_val: val,
+                    },
+                },
+            }
+        });
 
-        let write = inst == Operation::Store;
-        let jump = inst == Operation::Jump;
+        CycleInfo {
+            mem_access,
+            xout_latched: !reverse.new_reg.out & self.state.reg.out & HSYNC != 0,
+            out_pc,
+            reverse,
+        }
+    }
+}
 
-        let mut lo = self.reg.d;
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    // Straight port of cpuCycle from emu.py, kept independent from the
+    // decoder above so the two can be checked against each other
+    fn reference_cycle(
+        reg: &RegisterFile,
+        ram: &mut [u8],
+        rom: &[RomWord],
+        input: u8,
+    ) -> RegisterFile {
+        let mut next = reg.clone();
+        next.ir = rom[reg.pc as usize].inst;
+        next.d = rom[reg.pc as usize].data;
+
+        let ir = reg.ir.0;
+        let instruction = ir >> 5;
+        let mode = (ir >> 2) & 7;
+        let bus = ir & 3;
+        let w = instruction == 6;
+        let j = instruction == 7;
+
+        let mut lo = reg.d;
         let mut hi = 0;
-        let mut to = ResultDest::None;
+        let mut to = None;
         let mut inc_x = false;
-        if !jump {
+        if !j {
             match mode {
-                Mode::Acc_D_Far => to = ResultDest::AC,
-                Mode::Acc_X_Gt => {
-                    to = ResultDest::AC;
-                    lo = self.reg.x;
+                0 => to = if w { None } else { Some('a') },
+                1 => {
+                    to = if w { None } else { Some('a') };
+                    lo = reg.x;
                 }
-                Mode::Acc_Y_D_Lt => {
-                    to = ResultDest::AC;
-                    hi = self.reg.y;
+                2 => {
+                    to = if w { None } else { Some('a') };
+                    hi = reg.y;
                 }
-                Mode::Acc_Y_X_Ne => {
-                    to = ResultDest::AC;
-                    lo = self.reg.x;
-                    hi = self.reg.y;
+                3 => {
+                    to = if w { None } else { Some('a') };
+                    lo = reg.x;
+                    hi = reg.y;
                 }
-                Mode::X_D_Eq => to = ResultDest::X,
-                Mode::Y_D_Ge => to = ResultDest::Y,
-                Mode::Out_D_Le => to = ResultDest::OUT,
-                Mode::Out_Y_Xpp_Bra => {
-                    to = ResultDest::OUT;
-                    lo = self.reg.x;
-                    hi = self.reg.y;
+                4 => to = Some('x'),
+                5 => to = Some('y'),
+                6 => to = if w { None } else { Some('o') },
+                _ => {
+                    to = if w { None } else { Some('o') };
+                    lo = reg.x;
+                    hi = reg.y;
                     inc_x = true;
                 }
             }
-            to = to.mask_write(write);
         }
-        let addr: u16 = ((hi as u16) << 8) | (lo as u16);
+        let addr = (((hi as u16) << 8) | lo as u16) & 0x7fff;
 
-        let mut mem_access = None;
         let b = match bus {
-            Bus::Data => self.reg.d,
-            Bus::Ram => {
-                if write {
-                    self.reg.undef
-                } else {
-                    let val = self.ram[(addr & 0x7fff) as usize];
-                    mem_access = Some(MemAccess {
-                        addr,
-                        op: MemOperation::Read { val },
-                    });
-                    val
-                }
-            }
-            Bus::Acc => self.reg.ac,
-            Bus::In => self.input,
+            0 => reg.d,
+            1 if w => reg.undef,
+            1 => ram[addr as usize],
+            2 => reg.ac,
+            _ => input,
         };
-        if write {
-            let ram_addr = (addr & 0x7fff) as usize;
-            mem_access = Some(MemAccess {
-                addr,
-                op: MemOperation::Write {
-                    prev_val: self.ram[ram_addr],
-                    new_val: b,
-                },
-            });
-            self.ram[ram_addr] = b;
+        if w {
+            ram[addr as usize] = b;
         }
 
-        let alu = match inst {
-            Operation::Load => b,
-            Operation::And => self.reg.ac & b,
-            Operation::Or => self.reg.ac | b,
-            Operation::Xor => self.reg.ac ^ b,
-            Operation::Add => self.reg.ac.wrapping_add(b),
-            Operation::Sub => self.reg.ac.wrapping_sub(b),
-            Operation::Store => self.reg.ac,
-            Operation::Jump => self.reg.ac.wrapping_neg(),
+        let alu = match instruction {
+            0 => b,
+            1 => reg.ac & b,
+            2 => reg.ac | b,
+            3 => reg.ac ^ b,
+            4 => reg.ac.wrapping_add(b),
+            5 => reg.ac.wrapping_sub(b),
+            6 => reg.ac,
+            _ => reg.ac.wrapping_neg(),
         };
 
         match to {
-            ResultDest::AC => new_reg.ac = alu,
-            ResultDest::X => new_reg.x = alu,
-            ResultDest::Y => new_reg.y = alu,
-            ResultDest::OUT => new_reg.out = alu,
-            ResultDest::None => {}
+            Some('a') => next.ac = alu,
+            Some('x') => next.x = alu,
+            Some('y') => next.y = alu,
+            Some('o') => next.out = alu,
+            _ => {}
         }
         if inc_x {
-            new_reg.x = self.reg.x.wrapping_add(1);
+            next.x = reg.x.wrapping_add(1);
         }
 
-        new_reg.pc = self.reg.pc.wrapping_add(1);
-        if jump {
-            let mode_bits = mode.to_primitive();
-            if mode_bits != 0 {
-                let cond = (self.reg.ac >> 7) + if self.reg.ac == 0 { 2 } else { 0 };
-                if mode_bits & (1 << cond) != 0 {
-                    new_reg.pc = (self.reg.pc & 0xff00) | (b as u16);
+        next.pc = reg.pc.wrapping_add(1);
+        if j {
+            if mode != 0 {
+                let cond = (reg.ac >> 7) + if reg.ac == 0 { 2 } else { 0 };
+                if mode & (1 << cond) != 0 {
+                    next.pc = (reg.pc & 0xff00) | b as u16;
                 }
             } else {
-                new_reg.pc = ((self.reg.y as u16) << 8) | (b as u16);
+                next.pc = ((reg.y as u16) << 8) | b as u16;
             }
         }
+        if reg.out & 0x40 == 0 && next.out & 0x40 != 0 {
+            next.xout = reg.ac;
+        }
+        next
+    }
+
+    fn random_rom(r: &mut StdRng) -> Vec<RomWord> {
+        (0..ROM_SIZE)
+            .map(|_| RomWord {
+                inst: OpCode(r.gen()),
+                data: r.gen(),
+            })
+            .collect()
+    }
+
+    fn random_state(r: &mut StdRng, ram: &[u8]) -> CpuState {
+        CpuState {
+            ram: ram.to_vec(),
+            reg: RegisterFile {
+                pc: r.gen(),
+                ir: OpCode(r.gen()),
+                d: r.gen(),
+                ac: r.gen(),
+                x: r.gen(),
+                y: r.gen(),
+                out: r.gen(),
+                xout: r.gen(),
+                undef: r.gen(),
+            },
+            queued_pc: r.gen(),
+        }
+    }
 
-        self.reg = new_reg;
+    #[test]
+    fn every_opcode_matches_reference() {
+        let mut r = StdRng::seed_from_u64(0x6167);
+        let mut cpu = Cpu::new(
+            random_rom(&mut r),
+            PowerOn::new(Some(1), PowerOnFill::Random),
+        );
+        let ram = cpu.state.ram.clone();
+
+        for opcode in 0..=255u8 {
+            for _ in 0..64 {
+                let mut state = random_state(&mut r, &ram);
+                state.reg.ir = OpCode(opcode);
+                // Exercise the zero and sign conditions of branches
+                state.reg.ac = match r.gen_range(0..4) {
+                    0 => 0,
+                    1 => 0x80,
+                    _ => r.gen(),
+                };
+                let input = r.gen();
+
+                let mut expected_ram = state.ram.clone();
+                let expected = reference_cycle(&state.reg, &mut expected_ram, &cpu.rom, input);
+
+                let pc = state.reg.pc;
+                cpu.state = state;
+                cpu.input = input;
+                cpu.clock();
+
+                // Floating bus value is random each cycle
+                let mut actual = cpu.state.reg.clone();
+                actual.undef = expected.undef;
+
+                assert_eq!(actual, expected, "opcode {:02x}", opcode);
+                assert_eq!(cpu.state.queued_pc, pc, "opcode {:02x}", opcode);
+                assert!(cpu.state.ram == expected_ram, "opcode {:02x}", opcode);
+            }
+        }
+    }
 
-        CycleInfo { mem_access }
+    #[test]
+    fn reverse_diff_restores_state() {
+        let mut r = StdRng::seed_from_u64(0x7472);
+        let rom = random_rom(&mut r);
+        let ram = (0..RAM_SIZE).map(|_| r.gen()).collect::<Vec<u8>>();
+
+        for opcode in 0..=255u8 {
+            let mut state = random_state(&mut r, &ram);
+            state.reg.ir = OpCode(opcode);
+            let before_reg = state.reg.clone();
+            let before_ram = state.ram.clone();
+            let before_queued_pc = state.queued_pc;
+
+            let diff = state.clock(&rom, r.gen(), r.gen());
+            let reverse = state.apply_diff(diff);
+            state.apply_diff(reverse);
+
+            assert_eq!(state.reg, before_reg, "opcode {:02x}", opcode);
+            assert_eq!(state.queued_pc, before_queued_pc, "opcode {:02x}", opcode);
+            assert!(state.ram == before_ram, "opcode {:02x}", opcode);
+        }
+    }
+
+    #[test]
+    fn soft_reset_loads_first_word() {
+        let mut r = StdRng::seed_from_u64(0x7273);
+        let rom = random_rom(&mut r);
+        let mut cpu = Cpu::new(rom, PowerOn::new(Some(2), PowerOnFill::Random));
+        let ram = cpu.state.ram.clone();
+
+        cpu.soft_reset();
+
+        assert_eq!(cpu.state.reg.pc, 0);
+        assert_eq!(cpu.state.queued_pc, 0);
+        assert_eq!(cpu.state.reg.ir, cpu.rom[0].inst);
+        assert_eq!(cpu.state.reg.d, cpu.rom[0].data);
+        assert!(cpu.state.ram == ram);
+    }
+
+    #[test]
+    fn same_seed_replays_exactly() {
+        let mut r = StdRng::seed_from_u64(0x7365);
+        let rom = random_rom(&mut r);
+        let power_on = PowerOn::new(Some(1234), PowerOnFill::Random);
+        let mut a = Cpu::new(rom.clone(), power_on);
+        let mut b = Cpu::new(rom, power_on);
+
+        for _ in 0..1000 {
+            a.clock();
+            b.clock();
+        }
+        assert_eq!(a.state.reg, b.state.reg);
+        assert!(a.state.ram == b.state.ram);
+
+        // Hard reset starts the same sequence over again
+        let reg = a.state.reg.clone();
+        a.hard_reset();
+        for _ in 0..1000 {
+            a.clock();
+        }
+        assert_eq!(a.state.reg, reg);
+    }
+
+    #[test]
+    fn step_back_replays_exactly() {
+        let mut r = StdRng::seed_from_u64(0x7362);
+        let rom = random_rom(&mut r);
+        let mut cpu = Cpu::new(rom, PowerOn::new(Some(99), PowerOnFill::Random));
+        for _ in 0..100 {
+            cpu.clock();
+        }
+
+        let mut reverse = vec![];
+        for _ in 0..1000 {
+            reverse.push(cpu.clock().reverse);
+        }
+        let (reg, ram, rng_state) = (cpu.state.reg.clone(), cpu.state.ram.clone(), cpu.rng.state);
+
+        for diff in reverse.into_iter().rev() {
+            cpu.apply_diff(diff);
+        }
+        for _ in 0..1000 {
+            cpu.clock();
+        }
+        assert_eq!(cpu.state.reg, reg);
+        assert!(cpu.state.ram == ram);
+        assert_eq!(cpu.rng.state, rng_state);
+    }
+
+    #[test]
+    fn fill_modes() {
+        let rom = vec![RomWord {
+            inst: OpCode(0),
+            data: 0,
+        }];
+
+        let zero = Cpu::new(rom.clone(), PowerOn::new(None, PowerOnFill::Zero));
+        assert!(zero.state.ram.iter().all(|&b| b == 0));
+        assert_eq!(zero.state.reg.ac, 0);
+
+        let pattern = Cpu::new(rom, PowerOn::new(None, PowerOnFill::Pattern));
+        assert_eq!(pattern.state.ram[0x1234], 0x34);
+        assert_eq!(pattern.state.ram[0x00ff], 0xff);
+    }
+
+    #[test]
+    fn writes_out_matches_reference() {
+        for opcode in 0..=255u8 {
+            let (instruction, mode) = (opcode >> 5, (opcode >> 2) & 7);
+            let expected = instruction < 6 && mode >= 6;
+            assert_eq!(
+                OpCode(opcode).writes_out(),
+                expected,
+                "opcode {:02x}",
+                opcode
+            );
+        }
     }
 }
@@ -0,0 +1,271 @@
+use std::{fmt::Display, str::FromStr};
+
+use bit_set::BitSet;
+
+use crate::{
+    cpu::{self, Cpu, MemAccess, MemOperation},
+    expr::Expr,
+};
+
+pub struct Breakpoint {
+    pub enabled: bool,
+    // None if the condition can be true at any address
+    pub addr: Option<u16>,
+    pub condition: Option<(String, Expr)>,
+    pub hits: u32,
+    pub ignore_count: u32,
+}
+
+impl Breakpoint {
+    pub fn new(addr: Option<u16>, condition: Option<(String, Expr)>, ignore_count: u32) -> Self {
+        Self {
+            enabled: true,
+            addr: addr.or_else(|| condition.as_ref().and_then(|(_, c)| c.required_pc())),
+            condition,
+            hits: 0,
+            ignore_count,
+        }
+    }
+
+    fn is_unconditional_at(&self, addr: u16) -> bool {
+        self.addr == Some(addr) && self.condition.is_none()
+    }
+
+    fn matches(&self, pc: u16, cpu: &Cpu) -> bool {
+        self.addr.is_none_or(|addr| addr == pc)
+            && self.condition.as_ref().is_none_or(|(_, c)| c.is_true(cpu))
+    }
+}
+
+pub struct BreakpointList {
+    breakpoints: Vec<Breakpoint>,
+
+    // Addresses with at least one enabled breakpoint, so conditions only need
+    // to be evaluated there. Conditions without an address are checked every
+    // cycle.
+    addr_index: BitSet,
+    has_unindexed: bool,
+}
+
+impl Default for BreakpointList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BreakpointList {
+    pub fn new() -> Self {
+        Self {
+            breakpoints: Vec::new(),
+            addr_index: BitSet::with_capacity(cpu::ROM_SIZE),
+            has_unindexed: false,
+        }
+    }
+
+    pub fn list(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    // Gives mutable access to the breakpoints, updating the index afterwards
+    pub fn edit<R>(&mut self, f: impl FnOnce(&mut Vec<Breakpoint>) -> R) -> R {
+        let result = f(&mut self.breakpoints);
+        self.addr_index.clear();
+        self.has_unindexed = false;
+        for bp in self.breakpoints.iter().filter(|bp| bp.enabled) {
+            match bp.addr {
+                Some(addr) => {
+                    self.addr_index.insert(addr as usize);
+                }
+                None => self.has_unindexed = true,
+            }
+        }
+        result
+    }
+
+    pub fn add(&mut self, bp: Breakpoint) {
+        self.edit(|list| list.push(bp));
+    }
+
+    // Adds or removes the unconditional breakpoint at an address. Adding one
+    // that is disabled enables it again.
+    pub fn set_unconditional(&mut self, addr: u16, enabled: bool) {
+        if enabled {
+            self.edit(
+                |list| match list.iter_mut().find(|bp| bp.is_unconditional_at(addr)) {
+                    Some(bp) => bp.enabled = true,
+                    None => list.push(Breakpoint::new(Some(addr), None, 0)),
+                },
+            );
+        } else {
+            self.edit(|list| list.retain(|bp| !bp.is_unconditional_at(addr)));
+        }
+    }
+
+    // Whether an enabled unconditional breakpoint is at addr
+    pub fn has_unconditional(&self, addr: u16) -> bool {
+        self.breakpoints
+            .iter()
+            .any(|bp| bp.enabled && bp.is_unconditional_at(addr))
+    }
+
+    fn is_indexed(&self, pc: u16) -> bool {
+        self.has_unindexed || self.addr_index.contains(pc as usize)
+    }
+
+    // Whether any enabled breakpoint's address and condition match, without
+    // counting a hit. Used when running backwards, where nothing is counted.
+    pub fn matches(&self, cpu: &Cpu) -> bool {
+        let pc = cpu.state.queued_pc;
+        self.is_indexed(pc)
+            && self
+                .breakpoints
+                .iter()
+                .any(|bp| bp.enabled && bp.matches(pc, cpu))
+    }
+
+    // Called before each instruction executes. Counts a hit on every matching
+    // breakpoint, and breaks once one has been hit more than its ignore count.
+    pub fn should_break(&mut self, cpu: &Cpu) -> bool {
+        let pc = cpu.state.queued_pc;
+        if !self.is_indexed(pc) {
+            return false;
+        }
+
+        let mut should_break = false;
+        for bp in self.breakpoints.iter_mut().filter(|bp| bp.enabled) {
+            if !bp.matches(pc, cpu) {
+                continue;
+            }
+            bp.hits += 1;
+            if bp.hits > bp.ignore_count {
+                should_break = true;
+            }
+        }
+        should_break
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchAction {
+    Break,
+    Log,
+    // Suppresses other watches over the same addresses, to cut holes in a range
+    Ignore,
+}
+
+impl WatchAction {
+    pub const ALL: [Self; 3] = [Self::Break, Self::Log, Self::Ignore];
+    pub const NAMES: [&'static str; 3] = ["Break", "Log", "Ignore"];
+}
+
+impl Display for WatchAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Break => "break",
+            Self::Log => "log",
+            Self::Ignore => "ignore",
+        })
+    }
+}
+
+impl FromStr for WatchAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|a| a.to_string() == s)
+            .ok_or_else(|| format!("Unknown watch action '{}'", s))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Watch {
+    // Inclusive address range
+    pub start: u16,
+    pub end: u16,
+    pub read: bool,
+    pub write: bool,
+    pub action: WatchAction,
+
+    // Only match accesses with this value (the new value for writes)
+    pub value: Option<u8>,
+}
+
+impl Watch {
+    pub fn matches(&self, access: &MemAccess) -> bool {
+        if !(self.start..=self.end).contains(&access.addr) {
+            return false;
+        }
+        let (enabled, val) = match access.op {
+            MemOperation::Read { val } => (self.read, val),
+            MemOperation::Write { new_val, .. } => (self.write, new_val),
+        };
+        enabled && self.value.is_none_or(|v| v == val)
+    }
+}
+
+// Combines the actions of every watch matching an access. Ignore wins over
+// everything, then Break, then Log.
+pub fn watch_action(watches: &[Watch], access: &MemAccess) -> Option<WatchAction> {
+    let mut action = None;
+    for watch in watches.iter().filter(|w| w.matches(access)) {
+        action = match (action, watch.action) {
+            (Some(WatchAction::Ignore), _) | (_, WatchAction::Ignore) => Some(WatchAction::Ignore),
+            (Some(WatchAction::Break), _) | (_, WatchAction::Break) => Some(WatchAction::Break),
+            _ => Some(WatchAction::Log),
+        };
+    }
+    action
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{OpCode, PowerOn, PowerOnFill, RomWord};
+
+    #[test]
+    fn only_forward_checks_count_hits() {
+        let rom = vec![
+            RomWord {
+                inst: OpCode(0),
+                data: 0
+            };
+            16
+        ];
+        let cpu = Cpu::new(rom, PowerOn::new(Some(1), PowerOnFill::Zero));
+        let mut list = BreakpointList::new();
+        list.add(Breakpoint::new(Some(cpu.state.queued_pc), None, 1));
+
+        assert!(list.matches(&cpu));
+        assert!(list.matches(&cpu));
+        assert_eq!(list.list()[0].hits, 0);
+
+        // The first hit is ignored
+        assert!(!list.should_break(&cpu));
+        assert!(list.should_break(&cpu));
+        assert_eq!(list.list()[0].hits, 2);
+
+        list.edit(|list| list[0].enabled = false);
+        assert!(!list.matches(&cpu));
+        assert!(!list.should_break(&cpu));
+    }
+
+    #[test]
+    fn unconditional_breakpoints_follow_enabled() {
+        let mut list = BreakpointList::new();
+        list.set_unconditional(0x100, true);
+        assert!(list.has_unconditional(0x100));
+
+        list.edit(|list| list[0].enabled = false);
+        assert!(!list.has_unconditional(0x100));
+
+        // Re-enables the existing breakpoint instead of adding another
+        list.set_unconditional(0x100, true);
+        assert!(list.has_unconditional(0x100));
+        assert_eq!(list.list().len(), 1);
+
+        list.set_unconditional(0x100, false);
+        assert!(list.list().is_empty());
+    }
+}
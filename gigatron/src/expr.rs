@@ -0,0 +1,405 @@
+use std::{error::Error, fmt::Display};
+
+use crate::{cpu::Cpu, symbols::SymbolTable};
+
+// Condition expressions for the debugger, e.g. "pc == perFrame && [pieceY] > 18".
+//
+// Values are integers, comparisons and logic operators give 0 or 1. Names
+// are registers (pc, ir, d, ac, x, y, out, xout, in), then zero-page
+// variables (evaluating to their address), then ROM labels. [a] reads RAM
+// at a, and [a,b] reads RAM at a * 256 + b like the [y,x] addressing mode.
+// Numbers are decimal, $hex or 0xhex.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Register {
+    Pc, // Address of the instruction about to execute
+    Ir,
+    D,
+    Ac,
+    X,
+    Y,
+    Out,
+    Xout,
+    In,
+}
+
+impl Register {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "pc" => Self::Pc,
+            "ir" => Self::Ir,
+            "d" => Self::D,
+            "ac" => Self::Ac,
+            "x" => Self::X,
+            "y" => Self::Y,
+            "out" => Self::Out,
+            "xout" => Self::Xout,
+            "in" => Self::In,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnaryOp {
+    Not,
+    Neg,
+    BitNot,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Const(i64),
+    Reg(Register),
+    Mem(Box<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug)]
+pub struct ParseError(String);
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for ParseError {}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Num(i64),
+    Ident(String),
+    Op(&'static str),
+}
+
+// Longest operators first so "<=" is not read as "<"
+const OPERATORS: [&str; 22] = [
+    "||", "&&", "==", "!=", "<<", ">>", "<=", ">=", "<", ">", "|", "^", "&", "+", "-", "!", "~",
+    "[", "]", ",", "(", ")",
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = vec![];
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+        if c.is_ascii_digit() || c == '$' {
+            let (radix, digits) = if let Some(hex) = rest.strip_prefix('$') {
+                (16, hex)
+            } else if let Some(hex) = rest.strip_prefix("0x") {
+                (16, hex)
+            } else {
+                (10, rest)
+            };
+            let len = digits
+                .find(|c: char| !c.is_digit(radix))
+                .unwrap_or(digits.len());
+            let val = i64::from_str_radix(&digits[..len], radix)
+                .map_err(|_| ParseError(format!("Invalid number near '{}'", rest)))?;
+            tokens.push(Token::Num(val));
+            rest = &digits[len..];
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || "_.#".contains(c)))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..len].to_string()));
+            rest = &rest[len..];
+        } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        } else {
+            return Err(ParseError(format!("Unexpected character '{}'", c)));
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+// Binary operators from lowest to highest precedence
+const PRECEDENCE: [&[(&str, BinaryOp)]; 9] = [
+    &[("||", BinaryOp::Or)],
+    &[("&&", BinaryOp::And)],
+    &[
+        ("==", BinaryOp::Eq),
+        ("!=", BinaryOp::Ne),
+        ("<=", BinaryOp::Le),
+        (">=", BinaryOp::Ge),
+        ("<", BinaryOp::Lt),
+        (">", BinaryOp::Gt),
+    ],
+    &[("|", BinaryOp::BitOr)],
+    &[("^", BinaryOp::BitXor)],
+    &[("&", BinaryOp::BitAnd)],
+    &[("<<", BinaryOp::Shl), (">>", BinaryOp::Shr)],
+    &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+    &[],
+];
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    symbols: &'a SymbolTable,
+}
+
+impl<'a> Parser<'a> {
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), ParseError> {
+        if self.peek_op() == Some(op) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(ParseError(format!("Expected '{}'", op)))
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, ParseError> {
+        if PRECEDENCE[level].is_empty() {
+            return self.unary();
+        }
+
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(&(_, op)) = self
+            .peek_op()
+            .and_then(|tok| PRECEDENCE[level].iter().find(|(name, _)| *name == tok))
+        {
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, ParseError> {
+        let op = match self.peek_op() {
+            Some("!") => UnaryOp::Not,
+            Some("-") => UnaryOp::Neg,
+            Some("~") => UnaryOp::BitNot,
+            _ => return self.primary(),
+        };
+        self.pos += 1;
+        Ok(Expr::Unary(op, Box::new(self.unary()?)))
+    }
+
+    fn primary(&mut self) -> Result<Expr, ParseError> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| ParseError("Unexpected end of expression".to_string()))?;
+        self.pos += 1;
+
+        match token {
+            Token::Num(val) => Ok(Expr::Const(val)),
+            Token::Ident(name) => self.resolve(&name),
+            Token::Op("(") => {
+                let inner = self.binary(0)?;
+                self.expect(")")?;
+                Ok(inner)
+            }
+            Token::Op("[") => {
+                let mut addr = self.binary(0)?;
+                if self.peek_op() == Some(",") {
+                    self.pos += 1;
+                    let lo = self.binary(0)?;
+                    let hi = Expr::Binary(BinaryOp::Shl, Box::new(addr), Box::new(Expr::Const(8)));
+                    let lo =
+                        Expr::Binary(BinaryOp::BitAnd, Box::new(lo), Box::new(Expr::Const(0xff)));
+                    addr = Expr::Binary(BinaryOp::BitOr, Box::new(hi), Box::new(lo));
+                }
+                self.expect("]")?;
+                Ok(Expr::Mem(Box::new(addr)))
+            }
+            Token::Op(op) => Err(ParseError(format!("Unexpected '{}'", op))),
+        }
+    }
+
+    fn resolve(&self, name: &str) -> Result<Expr, ParseError> {
+        if let Some(reg) = Register::from_name(name) {
+            return Ok(Expr::Reg(reg));
+        }
+        if let Some(var) = self.symbols.find_var(name) {
+            return Ok(Expr::Const(var.address as i64));
+        }
+        if let Some(addr) = self.symbols.find_label(name) {
+            return Ok(Expr::Const(addr as i64));
+        }
+        Err(ParseError(format!("Unknown name '{}'", name)))
+    }
+}
+
+impl Expr {
+    pub fn parse(text: &str, symbols: &SymbolTable) -> Result<Self, ParseError> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            pos: 0,
+            symbols,
+        };
+        let expr = parser.binary(0)?;
+        if parser.pos != parser.tokens.len() {
+            return Err(ParseError("Unexpected text after expression".to_string()));
+        }
+        Ok(expr)
+    }
+
+    pub fn eval(&self, cpu: &Cpu) -> i64 {
+        let reg = &cpu.state.reg;
+        match self {
+            Self::Const(val) => *val,
+            Self::Reg(r) => match r {
+                Register::Pc => cpu.state.queued_pc as i64,
+                Register::Ir => reg.ir.0 as i64,
+                Register::D => reg.d as i64,
+                Register::Ac => reg.ac as i64,
+                Register::X => reg.x as i64,
+                Register::Y => reg.y as i64,
+                Register::Out => reg.out as i64,
+                Register::Xout => reg.xout as i64,
+                Register::In => cpu.input as i64,
+            },
+            Self::Mem(addr) => cpu.state.ram[(addr.eval(cpu) & 0x7fff) as usize] as i64,
+            Self::Unary(op, val) => {
+                let val = val.eval(cpu);
+                match op {
+                    UnaryOp::Not => (val == 0) as i64,
+                    UnaryOp::Neg => val.wrapping_neg(),
+                    UnaryOp::BitNot => !val,
+                }
+            }
+            Self::Binary(BinaryOp::Or, lhs, rhs) => (lhs.is_true(cpu) || rhs.is_true(cpu)) as i64,
+            Self::Binary(BinaryOp::And, lhs, rhs) => (lhs.is_true(cpu) && rhs.is_true(cpu)) as i64,
+            Self::Binary(op, lhs, rhs) => {
+                let (a, b) = (lhs.eval(cpu), rhs.eval(cpu));
+                match op {
+                    BinaryOp::Eq => (a == b) as i64,
+                    BinaryOp::Ne => (a != b) as i64,
+                    BinaryOp::Lt => (a < b) as i64,
+                    BinaryOp::Le => (a <= b) as i64,
+                    BinaryOp::Gt => (a > b) as i64,
+                    BinaryOp::Ge => (a >= b) as i64,
+                    BinaryOp::BitOr => a | b,
+                    BinaryOp::BitXor => a ^ b,
+                    BinaryOp::BitAnd => a & b,
+                    BinaryOp::Shl => a.wrapping_shl(b as u32),
+                    BinaryOp::Shr => a.wrapping_shr(b as u32),
+                    BinaryOp::Add => a.wrapping_add(b),
+                    BinaryOp::Sub => a.wrapping_sub(b),
+                    BinaryOp::Or | BinaryOp::And => unreachable!(),
+                }
+            }
+        }
+    }
+
+    pub fn is_true(&self, cpu: &Cpu) -> bool {
+        self.eval(cpu) != 0
+    }
+
+    // If the expression can only be true at one instruction, because it is
+    // "pc == N" or an && chain containing that, returns the address N. This
+    // lets the debugger skip evaluating it everywhere else.
+    pub fn required_pc(&self) -> Option<u16> {
+        match self {
+            Self::Binary(BinaryOp::Eq, lhs, rhs) => match (lhs.as_ref(), rhs.as_ref()) {
+                (Self::Reg(Register::Pc), Self::Const(addr))
+                | (Self::Const(addr), Self::Reg(Register::Pc)) => u16::try_from(*addr).ok(),
+                _ => None,
+            },
+            Self::Binary(BinaryOp::And, lhs, rhs) => lhs.required_pc().or(rhs.required_pc()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::{
+        cpu::{OpCode, PowerOn, PowerOnFill, RomWord},
+        symbols::ZeroPageVariable,
+    };
+
+    fn symbols() -> SymbolTable {
+        SymbolTable {
+            zero_page: vec![ZeroPageVariable {
+                address: 0x2a,
+                length: 1,
+                name: "pieceY".to_string(),
+            }],
+            labels: BTreeMap::from([(0x0123, "drawPiece".to_string())]),
+            placeholders: BTreeMap::new(),
+        }
+    }
+
+    fn cpu() -> Cpu {
+        let rom = vec![RomWord {
+            inst: OpCode(0),
+            data: 0,
+        }];
+        let mut cpu = Cpu::new(rom, PowerOn::new(Some(0), PowerOnFill::Zero));
+        cpu.state.queued_pc = 0x0123;
+        cpu.state.reg.x = 5;
+        cpu.state.reg.y = 1;
+        cpu.state.ram[0x2a] = 19;
+        cpu.state.ram[0x0105] = 7;
+        cpu
+    }
+
+    fn eval(text: &str) -> i64 {
+        Expr::parse(text, &symbols()).unwrap().eval(&cpu())
+    }
+
+    #[test]
+    fn conditions() {
+        assert_eq!(eval("pc == drawPiece && [pieceY] > 18"), 1);
+        assert_eq!(eval("pc == drawPiece && [pieceY] > 19"), 0);
+        assert_eq!(eval("[y,x]"), 7);
+        assert_eq!(eval("[$105] == 0x7 || 0"), 1);
+        assert_eq!(eval("1 + 2 & 3 << 1"), 2);
+        assert_eq!(eval("!(x - 5)"), 1);
+        assert_eq!(eval("~0 & $ff"), 255);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(Expr::parse("pc ==", &symbols()).is_err());
+        assert!(Expr::parse("nothere", &symbols()).is_err());
+        assert!(Expr::parse("[1", &symbols()).is_err());
+        assert!(Expr::parse("1 2", &symbols()).is_err());
+    }
+
+    #[test]
+    fn required_pc() {
+        let expr = Expr::parse("[pieceY] > 3 && drawPiece == pc", &symbols()).unwrap();
+        assert_eq!(expr.required_pc(), Some(0x0123));
+        let expr = Expr::parse("pc == drawPiece || x", &symbols()).unwrap();
+        assert_eq!(expr.required_pc(), None);
+    }
+}
@@ -0,0 +1,584 @@
+use std::{
+    io::{BufReader, BufWriter, Error, Read, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+};
+
+use crate::{
+    cpu::{Cpu, MemOperation, RomWord},
+    debugger::{self, BreakpointList, Watch, WatchAction},
+    vga::Vga,
+};
+
+// GDB remote serial protocol stub, so scripts and standard tooling can drive
+// the emulator.
+//
+// RAM is mapped at address 0. ROM is mapped at ROM_BASE with two bytes per
+// word, the instruction followed by its operand. Registers are, in order:
+// pc (16 bit little endian), ac, x, y, out, ir, d and in (the controller
+// port), also described by the target.xml served through qXfer.
+// Breakpoints (Z0) take a ROM word address like pc, watchpoints (Z2/Z3/Z4)
+// take RAM addresses. One cycle is one step.
+
+pub const ROM_BASE: u32 = 0x100_0000;
+pub const REGISTER_NAMES: [&str; 8] = ["pc", "ac", "x", "y", "out", "ir", "d", "in"];
+
+// How many cycles run between checks for an interrupt from the client
+const INTERRUPT_POLL_CYCLES: u64 = 1 << 16;
+
+pub enum Reply {
+    Packet(String),
+    // Send the packet, then end the session
+    Close(Option<String>),
+}
+
+pub struct GdbStub {
+    pub cpu: Cpu,
+    pub vga: Vga,
+    pub breakpoints: BreakpointList,
+    pub watches: Vec<Watch>,
+
+    last_stop: String,
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, b| sum.wrapping_add(*b))
+}
+
+// Describes the registers in the order of the g packet
+fn target_xml() -> String {
+    let regs: String = REGISTER_NAMES
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let (bits, ty) = if i == 0 {
+                (16, "code_ptr")
+            } else {
+                (8, "uint8")
+            };
+            format!(
+                "<reg name=\"{}\" bitsize=\"{}\" type=\"{}\"/>",
+                name, bits, ty
+            )
+        })
+        .collect();
+    format!(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\"><feature name=\"org.gigatron.cpu\">{}</feature></target>",
+        regs
+    )
+}
+
+impl GdbStub {
+    pub fn new(cpu: Cpu, vga: Vga) -> Self {
+        Self {
+            cpu,
+            vga,
+            breakpoints: BreakpointList::new(),
+            watches: Vec::new(),
+            last_stop: "S05".to_string(),
+        }
+    }
+
+    // Waits for one client on localhost and serves it until it detaches
+    pub fn serve(&mut self, port: u16) -> Result<(), Error> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        println!("Waiting for GDB on port {}", port);
+        let (stream, addr) = listener.accept()?;
+        println!("GDB connected from {}", addr);
+        stream.set_nodelay(true)?;
+
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream.try_clone()?);
+        while let Some(packet) = read_packet(&mut reader, &mut writer)? {
+            let reply = self.handle(&packet, &mut || poll_interrupt(&stream));
+            match reply {
+                Reply::Packet(data) => write_packet(&mut writer, &data)?,
+                Reply::Close(data) => {
+                    if let Some(data) = data {
+                        write_packet(&mut writer, &data)?;
+                    }
+                    break;
+                }
+            }
+        }
+        println!("GDB disconnected");
+        Ok(())
+    }
+
+    // Handles one unescaped packet. While running, interrupted is polled to
+    // check if the client asked to stop.
+    pub fn handle(&mut self, packet: &[u8], interrupted: &mut dyn FnMut() -> bool) -> Reply {
+        // Only X packets carry binary data
+        if let Some(args) = packet.strip_prefix(b"X") {
+            return Reply::Packet(self.write_binary(args));
+        }
+        let Ok(packet) = std::str::from_utf8(packet) else {
+            return Reply::Packet("E01".to_string());
+        };
+
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => self.last_stop.clone(),
+            Some(b'g') => hex_bytes(&self.read_registers()),
+            Some(b'G') => self.write_registers(&packet[1..]),
+            Some(b'p') => self.read_register(&packet[1..]),
+            Some(b'P') => self.write_register(&packet[1..]),
+            Some(b'm') => self.read_memory(&packet[1..]),
+            Some(b'M') => self.write_memory(&packet[1..]),
+            Some(b's') => self.resume(true, interrupted),
+            Some(b'c') => self.resume(false, interrupted),
+            Some(b'Z') => self.set_point(&packet[1..], true),
+            Some(b'z') => self.set_point(&packet[1..], false),
+            Some(b'H') | Some(b'T') => "OK".to_string(),
+            Some(b'D') => return Reply::Close(Some("OK".to_string())),
+            Some(b'k') => return Reply::Close(None),
+            _ if packet.starts_with("qSupported") => {
+                "PacketSize=4000;qXfer:features:read+".to_string()
+            }
+            _ if packet.starts_with("qXfer:features:read:") => {
+                read_features(&packet["qXfer:features:read:".len()..])
+            }
+            _ if packet == "qAttached" => "1".to_string(),
+            _ => String::new(),
+        };
+        Reply::Packet(reply)
+    }
+
+    fn read_registers(&self) -> Vec<u8> {
+        let reg = &self.cpu.state.reg;
+        let mut bytes = self.cpu.state.queued_pc.to_le_bytes().to_vec();
+        bytes.extend([
+            reg.ac,
+            reg.x,
+            reg.y,
+            reg.out,
+            reg.ir.0,
+            reg.d,
+            self.cpu.input,
+        ]);
+        bytes
+    }
+
+    fn set_register(&mut self, index: usize, val: u16) -> bool {
+        let reg = &mut self.cpu.state.reg;
+        match index {
+            // Jump by refilling the pipeline as if the instruction at val
+            // had just been fetched
+            0 => {
+                let RomWord { inst, data } = self.cpu.rom[val as usize % self.cpu.rom.len()];
+                self.cpu.state.queued_pc = val;
+                reg.pc = val.wrapping_add(1);
+                reg.ir = inst;
+                reg.d = data;
+            }
+            1 => reg.ac = val as u8,
+            2 => reg.x = val as u8,
+            3 => reg.y = val as u8,
+            4 => reg.out = val as u8,
+            5 => reg.ir.0 = val as u8,
+            6 => reg.d = val as u8,
+            7 => self.cpu.input = val as u8,
+            _ => return false,
+        }
+        true
+    }
+
+    fn write_registers(&mut self, data: &str) -> String {
+        match parse_hex_bytes(data) {
+            Some(bytes) if bytes.len() == REGISTER_NAMES.len() + 1 => {
+                self.set_register(0, u16::from_le_bytes([bytes[0], bytes[1]]));
+                for (i, val) in bytes[2..].iter().enumerate() {
+                    self.set_register(i + 1, *val as u16);
+                }
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        let bytes = self.read_registers();
+        match parse_hex(args).map(|i| i as usize) {
+            Some(0) => hex_bytes(&bytes[..2]),
+            Some(i) if i < REGISTER_NAMES.len() => hex_bytes(&bytes[i + 1..i + 2]),
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let parsed = args.split_once('=').and_then(|(index, val)| {
+            let bytes = parse_hex_bytes(val)?;
+            let val = match bytes[..] {
+                [lo] => lo as u16,
+                [lo, hi] => u16::from_le_bytes([lo, hi]),
+                _ => return None,
+            };
+            Some((parse_hex(index)? as usize, val))
+        });
+        match parsed {
+            Some((index, val)) if self.set_register(index, val) => "OK".to_string(),
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn memory_byte(&mut self, addr: u32) -> Option<&mut u8> {
+        if addr >= ROM_BASE {
+            let word = self.cpu.rom.get_mut(((addr - ROM_BASE) / 2) as usize)?;
+            Some(if addr.is_multiple_of(2) {
+                &mut word.inst.0
+            } else {
+                &mut word.data
+            })
+        } else if addr <= 0xffff {
+            Some(&mut self.cpu.state.ram[(addr & 0x7fff) as usize])
+        } else {
+            None
+        }
+    }
+
+    fn read_memory(&mut self, args: &str) -> String {
+        let Some((addr, len)) = args
+            .split_once(',')
+            .and_then(|(addr, len)| Some((parse_hex(addr)?, parse_hex(len)?)))
+        else {
+            return "E01".to_string();
+        };
+
+        let bytes: Option<Vec<u8>> = (addr..addr.saturating_add(len))
+            .map(|a| self.memory_byte(a).map(|b| *b))
+            .collect();
+        match bytes {
+            Some(bytes) => hex_bytes(&bytes),
+            None => "E02".to_string(),
+        }
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        match args
+            .split_once(':')
+            .and_then(|(range, data)| Some((range, parse_hex_bytes(data)?)))
+        {
+            Some((range, bytes)) => self.store_bytes(range, &bytes),
+            None => "E01".to_string(),
+        }
+    }
+
+    fn write_binary(&mut self, args: &[u8]) -> String {
+        let Some(colon) = args.iter().position(|&b| b == b':') else {
+            return "E01".to_string();
+        };
+        match std::str::from_utf8(&args[..colon]) {
+            Ok(range) => self.store_bytes(range, &args[colon + 1..]),
+            Err(_) => "E01".to_string(),
+        }
+    }
+
+    // Writes to an "addr,len" range, len has to match the bytes given
+    fn store_bytes(&mut self, range: &str, bytes: &[u8]) -> String {
+        let Some(addr) = range.split_once(',').and_then(|(addr, len)| {
+            (parse_hex(len)? as usize == bytes.len()).then_some(parse_hex(addr)?)
+        }) else {
+            return "E01".to_string();
+        };
+
+        for (a, &val) in (addr..).zip(bytes) {
+            match self.memory_byte(a) {
+                Some(b) => *b = val,
+                None => return "E02".to_string(),
+            }
+        }
+        "OK".to_string()
+    }
+
+    fn set_point(&mut self, args: &str, insert: bool) -> String {
+        let fields: Option<Vec<u32>> = args.split(',').map(parse_hex).collect();
+        let Some(&[kind, addr, len]) = fields.as_deref() else {
+            return "E01".to_string();
+        };
+
+        let (read, write) = match kind {
+            0 => {
+                let Ok(addr) = u16::try_from(addr) else {
+                    return "E01".to_string();
+                };
+                self.breakpoints.set_unconditional(addr, insert);
+                return "OK".to_string();
+            }
+            2 => (false, true),
+            3 => (true, false),
+            4 => (true, true),
+            _ => return String::new(),
+        };
+        let end = addr.checked_add(len.max(1) - 1).map(u16::try_from);
+        let (Ok(start), Some(Ok(end))) = (u16::try_from(addr), end) else {
+            return "E01".to_string();
+        };
+
+        let watch = Watch {
+            start,
+            end,
+            read,
+            write,
+            action: WatchAction::Break,
+            value: None,
+        };
+        if insert {
+            self.watches.push(watch);
+        } else if let Some(i) = self.watches.iter().position(|w| *w == watch) {
+            self.watches.remove(i);
+        }
+        "OK".to_string()
+    }
+
+    // Runs one cycle, returns a stop reply if a watchpoint triggered
+    fn clock(&mut self) -> Option<String> {
+        let info = self.cpu.clock();
+        self.vga.update(&self.cpu.state.reg);
+
+        let access = info.mem_access?;
+        if debugger::watch_action(&self.watches, &access) != Some(WatchAction::Break) {
+            return None;
+        }
+        let kind = match access.op {
+            MemOperation::Read { .. } => "rwatch",
+            MemOperation::Write { .. } => "watch",
+        };
+        Some(format!("T05{}:{:x};", kind, access.addr))
+    }
+
+    fn resume(&mut self, step: bool, interrupted: &mut dyn FnMut() -> bool) -> String {
+        let mut cycles = 0;
+        let stop = loop {
+            if let Some(stop) = self.clock() {
+                break stop;
+            }
+            if step {
+                break "S05".to_string();
+            }
+            if self.breakpoints.should_break(&self.cpu) {
+                break "T05swbreak:;".to_string();
+            }
+
+            cycles += 1;
+            if cycles % INTERRUPT_POLL_CYCLES == 0 && interrupted() {
+                break "S02".to_string();
+            }
+        };
+        self.last_stop = stop.clone();
+        stop
+    }
+}
+
+// Serves part of the target description, args are "annex:offset,length"
+fn read_features(args: &str) -> String {
+    let Some((offset, len)) = args.strip_prefix("target.xml:").and_then(|range| {
+        let (offset, len) = range.split_once(',')?;
+        Some((parse_hex(offset)? as usize, parse_hex(len)? as usize))
+    }) else {
+        return "E00".to_string();
+    };
+
+    let xml = target_xml();
+    let start = offset.min(xml.len());
+    let end = start.saturating_add(len).min(xml.len());
+    let more = if end < xml.len() { 'm' } else { 'l' };
+    format!("{}{}", more, &xml[start..end])
+}
+
+// Undoes the escaping of binary data, where '}' is followed by the original
+// byte XORed with 0x20
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(data.len());
+    let mut iter = data.iter();
+    while let Some(&b) = iter.next() {
+        bytes.push(match b {
+            b'}' => iter.next().map_or(b, |&escaped| escaped ^ 0x20),
+            _ => b,
+        });
+    }
+    bytes
+}
+
+// Reads the next packet, acknowledging it, and returns its unescaped
+// contents. Returns None when the client disconnects.
+fn read_packet(r: &mut impl Read, w: &mut impl Write) -> Result<Option<Vec<u8>>, Error> {
+    let mut byte = [0];
+    loop {
+        // Skip acks and interrupts that arrive while stopped
+        loop {
+            if r.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+
+        let mut data = vec![];
+        loop {
+            r.read_exact(&mut byte)?;
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+        let mut sum = [0; 2];
+        r.read_exact(&mut sum)?;
+
+        let valid = std::str::from_utf8(&sum)
+            .ok()
+            .and_then(|s| u8::from_str_radix(s, 16).ok())
+            == Some(checksum(&data));
+        w.write_all(if valid { b"+" } else { b"-" })?;
+        w.flush()?;
+        if valid {
+            return Ok(Some(unescape(&data)));
+        }
+    }
+}
+
+fn write_packet(w: &mut impl Write, data: &str) -> Result<(), Error> {
+    write!(w, "${}#{:02x}", data, checksum(data.as_bytes()))?;
+    w.flush()
+}
+
+// Checks for a Ctrl-C (0x03) from the client without blocking
+fn poll_interrupt(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let mut byte = [0];
+    let interrupted = matches!((&*stream).read(&mut byte), Ok(1) if byte[0] == 0x03);
+    stream.set_nonblocking(false).is_ok() && interrupted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cpu::{OpCode, PowerOn, PowerOnFill},
+        vga,
+    };
+
+    fn stub() -> GdbStub {
+        // ld $05; st [$30]; bra $00; nop
+        let rom = [(0x00, 0x05), (0xc2, 0x30), (0xfc, 0x00), (0x02, 0x00)]
+            .into_iter()
+            .map(|(inst, data)| RomWord {
+                inst: OpCode(inst),
+                data,
+            })
+            .chain(std::iter::repeat(RomWord {
+                inst: OpCode(0),
+                data: 0,
+            }))
+            .take(256)
+            .collect();
+        let cpu = Cpu::new(rom, PowerOn::new(Some(3), PowerOnFill::Zero));
+        let vga = Vga::new(&vga::HORIZ_TIMING, &vga::VERT_TIMING);
+        GdbStub::new(cpu, vga)
+    }
+
+    fn send(stub: &mut GdbStub, packet: &str) -> String {
+        match stub.handle(packet.as_bytes(), &mut || false) {
+            Reply::Packet(data) => data,
+            Reply::Close(_) => panic!("Session closed"),
+        }
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let mut stub = stub();
+        assert_eq!(send(&mut stub, "P1=2a"), "OK");
+        assert_eq!(send(&mut stub, "p1"), "2a");
+        assert_eq!(send(&mut stub, "P0=0200"), "OK");
+        assert_eq!(&send(&mut stub, "g")[..6], "02002a");
+
+        assert_eq!(send(&mut stub, "M7ffe,2:abcd"), "OK");
+        assert_eq!(send(&mut stub, "m7ffe,2"), "abcd");
+        assert_eq!(send(&mut stub, "m1000002,2"), "c230");
+        assert_eq!(send(&mut stub, "m20000,1"), "E02");
+
+        // Binary writes arrive unescaped, so any byte can follow the colon
+        let reply = stub.handle(b"X7ffe,2:#}", &mut || false);
+        assert!(matches!(reply, Reply::Packet(data) if data == "OK"));
+        assert_eq!(send(&mut stub, "m7ffe,2"), "237d");
+    }
+
+    #[test]
+    fn reads_escaped_packets() {
+        let packet = b"X0,1:}]";
+        let sum = checksum(packet);
+        let mut input = format!("+${}#{:02x}", std::str::from_utf8(packet).unwrap(), sum);
+        input.push_str("$?#00");
+        let mut r = input.as_bytes();
+        let mut acks = vec![];
+
+        let data = read_packet(&mut r, &mut acks).unwrap().unwrap();
+        assert_eq!(data, b"X0,1:}");
+        assert_eq!(acks, b"+");
+        // A bad checksum is nacked, then the client disconnects
+        assert!(read_packet(&mut r, &mut acks).unwrap().is_none());
+        assert_eq!(acks, b"+-");
+    }
+
+    #[test]
+    fn serves_target_description() {
+        let mut stub = stub();
+        assert!(send(&mut stub, "qSupported:xmlRegisters=i386").contains("qXfer:features:read+"));
+
+        let mut xml = String::new();
+        loop {
+            let reply = send(
+                &mut stub,
+                &format!("qXfer:features:read:target.xml:{:x},40", xml.len()),
+            );
+            xml.push_str(&reply[1..]);
+            if reply.starts_with('l') {
+                break;
+            }
+        }
+        assert_eq!(xml, target_xml());
+        for name in REGISTER_NAMES {
+            assert!(xml.contains(&format!("name=\"{}\"", name)));
+        }
+        assert_eq!(send(&mut stub, "qXfer:features:read:other.xml:0,40"), "E00");
+    }
+
+    #[test]
+    fn breakpoints_and_watchpoints() {
+        let mut stub = stub();
+        assert_eq!(send(&mut stub, "Z2,30,1"), "OK");
+        assert_eq!(send(&mut stub, "c"), "T05watch:30;");
+        assert_eq!(send(&mut stub, "m30,1"), "05");
+
+        assert_eq!(send(&mut stub, "z2,30,1"), "OK");
+        assert_eq!(send(&mut stub, "Z0,2,0"), "OK");
+        assert_eq!(send(&mut stub, "c"), "T05swbreak:;");
+        assert_eq!(&send(&mut stub, "g")[..4], "0200");
+        assert_eq!(send(&mut stub, "?"), "T05swbreak:;");
+
+        assert_eq!(send(&mut stub, "s"), "S05");
+        assert_eq!(&send(&mut stub, "g")[..4], "0300");
+
+        assert_eq!(send(&mut stub, "Z2,fffffffe,4"), "E01");
+        assert_eq!(send(&mut stub, "Z2,ffff,2"), "E01");
+    }
+}
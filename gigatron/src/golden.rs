@@ -0,0 +1,164 @@
+use std::{
+    fmt::Display,
+    io::{Error, ErrorKind},
+    path::Path,
+};
+
+use crate::screenshot;
+
+// Per-pixel comparison of a frame against a reference image
+pub struct FrameDiff {
+    pub size: (u32, u32),
+    pub mismatched: usize,
+    // Bounding box of the mismatched pixels as (min x, min y, max x, max y)
+    pub bounds: Option<(u32, u32, u32, u32)>,
+    pub first: Option<(u32, u32, [u8; 3], [u8; 3])>,
+
+    // Mismatched pixels in red over a dimmed copy of the actual frame
+    pub image: Vec<u8>,
+}
+
+impl FrameDiff {
+    // Both images are RGBA8 of the given size
+    pub fn compare(expected: &[u8], actual: &[u8], size: (u32, u32)) -> Self {
+        let mut diff = Self {
+            size,
+            mismatched: 0,
+            bounds: None,
+            first: None,
+            image: Vec::with_capacity(actual.len()),
+        };
+
+        let pixels = expected.chunks_exact(4).zip(actual.chunks_exact(4));
+        for (i, (e, a)) in pixels.enumerate() {
+            if e[..3] == a[..3] {
+                diff.image.extend([a[0] / 4, a[1] / 4, a[2] / 4, 255]);
+                continue;
+            }
+
+            diff.image.extend([255, 0, 0, 255]);
+            diff.mismatched += 1;
+            let (x, y) = (i as u32 % size.0, i as u32 / size.0);
+            diff.first
+                .get_or_insert((x, y, [e[0], e[1], e[2]], [a[0], a[1], a[2]]));
+            diff.bounds = Some(match diff.bounds {
+                Some((x0, y0, x1, y1)) => (x0.min(x), y0.min(y), x1.max(x), y1.max(y)),
+                None => (x, y, x, y),
+            });
+        }
+        diff
+    }
+
+    pub fn is_match(&self) -> bool {
+        self.mismatched == 0
+    }
+}
+
+impl Display for FrameDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let total = (self.size.0 * self.size.1) as usize;
+        write!(f, "{} of {} pixels differ", self.mismatched, total)?;
+        if let Some((x0, y0, x1, y1)) = self.bounds {
+            write!(f, " within ({}, {})-({}, {})", x0, y0, x1, y1)?;
+        }
+        if let Some((x, y, e, a)) = self.first {
+            write!(
+                f,
+                ", first at ({}, {}): expected #{:02x}{:02x}{:02x}, got #{:02x}{:02x}{:02x}",
+                x, y, e[0], e[1], e[2], a[0], a[1], a[2]
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub enum GoldenResult {
+    Matched,
+    // The reference did not exist or was replaced
+    Updated,
+    Mismatch(FrameDiff),
+}
+
+// Compares a frame against "<dir>/<name>.png". On a mismatch the diff image
+// and the actual frame are written next to it as "<name>.diff.png" and
+// "<name>.actual.png". With update set, a missing or different reference is
+// overwritten instead.
+pub fn check_frame(
+    dir: &str,
+    name: &str,
+    size: (u32, u32),
+    rgba: &[u8],
+    update: bool,
+) -> Result<GoldenResult, Error> {
+    let dir = Path::new(dir);
+    let path = |suffix: &str| dir.join(format!("{}{}.png", name, suffix));
+    let write = |suffix: &str, pixels: &[u8]| -> Result<(), Error> {
+        let file = std::fs::File::create(path(suffix))?;
+        screenshot::write_png(std::io::BufWriter::new(file), size, pixels)
+    };
+
+    let reference = path("");
+    if !reference.exists() {
+        if !update {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("Missing reference image {}", reference.display()),
+            ));
+        }
+        std::fs::create_dir_all(dir)?;
+        write("", rgba)?;
+        return Ok(GoldenResult::Updated);
+    }
+
+    let file = std::io::BufReader::new(std::fs::File::open(&reference)?);
+    let (expected_size, expected) = screenshot::read_png(file)?;
+    if expected_size != size {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "{} is {}x{}, frame is {}x{}",
+                reference.display(),
+                expected_size.0,
+                expected_size.1,
+                size.0,
+                size.1
+            ),
+        ));
+    }
+
+    let diff = FrameDiff::compare(&expected, rgba, size);
+    if diff.is_match() {
+        Ok(GoldenResult::Matched)
+    } else if update {
+        write("", rgba)?;
+        Ok(GoldenResult::Updated)
+    } else {
+        write(".diff", &diff.image)?;
+        write(".actual", rgba)?;
+        Ok(GoldenResult::Mismatch(diff))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_mismatched_pixels() {
+        let expected = [[0, 0, 0, 255]; 6].concat();
+        let mut actual = expected.clone();
+        actual[4 * 4..4 * 4 + 3].copy_from_slice(&[255, 85, 0]);
+        actual[5 * 4] = 85;
+
+        let diff = FrameDiff::compare(&expected, &actual, (3, 2));
+        assert_eq!(diff.mismatched, 2);
+        assert_eq!(diff.bounds, Some((1, 1, 2, 1)));
+        assert_eq!(&diff.image[4 * 4..4 * 4 + 4], &[255, 0, 0, 255]);
+        assert_eq!(
+            diff.to_string(),
+            "2 of 6 pixels differ within (1, 1)-(2, 1), \
+             first at (1, 1): expected #000000, got #ff5500"
+        );
+        assert!(FrameDiff::compare(&expected, &expected, (3, 2)).is_match());
+    }
+}
@@ -0,0 +1,147 @@
+use std::{error::Error, fmt::Display};
+
+use crate::{
+    cpu::{self, Cpu, PowerOn},
+    input::NO_BUTTONS,
+    symbols::SymbolTable,
+    vga::{Vga, VideoMode},
+};
+
+// Drives a ROM from Rust code, for automated tests. Buttons are held until
+// released, and RAM is read and written by zero-page variable name.
+//
+//   let mut m = Machine::load("main.rom", "main.sym", power_on)?;
+//   m.run_frames(10)?;
+//   m.press(BUTTON_UP);
+//   m.run_frames(1)?;
+//   assert_eq!(m.var("swapAxes"), 1);
+pub struct Machine {
+    pub cpu: Cpu,
+    pub vga: Vga,
+    pub symbols: SymbolTable,
+
+    max_frame_cycles: u64,
+}
+
+#[derive(Debug)]
+pub struct RunError(String);
+
+impl Display for RunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for RunError {}
+
+impl Machine {
+    pub fn new(rom: Vec<cpu::RomWord>, symbols: SymbolTable, power_on: PowerOn) -> Self {
+        let mut cpu = Cpu::new(rom, power_on);
+        cpu.input = NO_BUTTONS;
+        Self {
+            cpu,
+            vga: Vga::from_mode(&VideoMode::DEFAULT),
+            symbols,
+            max_frame_cycles: VideoMode::DEFAULT.total_frame_cycles() as u64 * 2,
+        }
+    }
+
+    // Replaces the monitor, for ROMs that output a different mode
+    pub fn set_video_mode(&mut self, mode: &VideoMode) {
+        self.vga = Vga::from_mode(mode);
+        self.max_frame_cycles = mode.total_frame_cycles() as u64 * 2;
+    }
+
+    pub fn load(rom_file: &str, sym_file: &str, power_on: PowerOn) -> Result<Self, Box<dyn Error>> {
+        let rom = cpu::load_rom(rom_file)?;
+        let symbols = SymbolTable::load(sym_file)?;
+        Ok(Self::new(rom, symbols, power_on))
+    }
+
+    // Holds down the given BUTTON_* bits in addition to any already held
+    pub fn press(&mut self, buttons: u8) {
+        self.cpu.input &= !buttons;
+    }
+
+    pub fn release(&mut self, buttons: u8) {
+        self.cpu.input |= buttons;
+    }
+
+    pub fn release_all(&mut self) {
+        self.cpu.input = NO_BUTTONS;
+    }
+
+    // Runs one cycle, returns whether it started a new frame
+    pub fn step(&mut self) -> bool {
+        if let Some(pc) = self.cpu.clock().out_pc {
+            self.vga.set_out_pc(pc);
+        }
+        self.vga.update(&self.cpu.state.reg).should_render
+    }
+
+    pub fn run_frames(&mut self, frames: u64) -> Result<(), RunError> {
+        for _ in 0..frames {
+            let mut cycles = 0;
+            while !self.step() {
+                cycles += 1;
+                if cycles >= self.max_frame_cycles {
+                    return Err(RunError(format!(
+                        "CPU failed to produce frame {} in time",
+                        self.vga.frame_count() + 1
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Runs until the instruction about to execute is at the given label, and
+    // returns the number of cycles taken
+    pub fn run_until_label(&mut self, label: &str, max_cycles: u64) -> Result<u64, RunError> {
+        let addr = self
+            .symbols
+            .find_label(label)
+            .ok_or_else(|| RunError(format!("No label named '{}'", label)))?;
+        self.run_until(|cpu| cpu.state.queued_pc == addr, max_cycles)
+            .map_err(|_| {
+                RunError(format!(
+                    "Did not reach '{}' in {} cycles",
+                    label, max_cycles
+                ))
+            })
+    }
+
+    pub fn run_until(
+        &mut self,
+        mut pred: impl FnMut(&Cpu) -> bool,
+        max_cycles: u64,
+    ) -> Result<u64, RunError> {
+        for cycles in 0..max_cycles {
+            if pred(&self.cpu) {
+                return Ok(cycles);
+            }
+            self.step();
+        }
+        Err(RunError(format!(
+            "Condition not met in {} cycles",
+            max_cycles
+        )))
+    }
+
+    fn var_addr(&self, name: &str) -> usize {
+        match self.symbols.find_var(name) {
+            Some(var) => var.address as usize,
+            None => panic!("No zero-page variable named '{}'", name),
+        }
+    }
+
+    // Reads a zero-page variable, panicking if it does not exist
+    pub fn var(&self, name: &str) -> u8 {
+        self.cpu.state.ram[self.var_addr(name)]
+    }
+
+    pub fn set_var(&mut self, name: &str, val: u8) {
+        let addr = self.var_addr(name);
+        self.cpu.state.ram[addr] = val;
+    }
+}
@@ -0,0 +1,64 @@
+use std::collections::VecDeque;
+
+use crate::{
+    cpu::CycleDiff,
+    vga::{BeamState, DiscardedFrame},
+};
+
+pub const DEFAULT_CAPACITY: usize = 1 << 20;
+
+pub struct HistoryEntry {
+    pub reverse: CycleDiff,
+    pub beam: BeamState,
+
+    // Whether this cycle started a new frame on the monitor
+    pub frame_start: bool,
+    // Frame that start replaced, so the monitor can show it again
+    pub discarded: Option<DiscardedFrame>,
+}
+
+// Bounded ring of reverse diffs, oldest entries are dropped first
+pub struct History {
+    entries: VecDeque<HistoryEntry>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, entry: HistoryEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        while self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    pub fn pop(&mut self) -> Option<HistoryEntry> {
+        self.entries.pop_back()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
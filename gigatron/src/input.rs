@@ -0,0 +1,85 @@
+use std::error::Error;
+
+// Controller buttons as bits of the input port, which is active low
+pub const BUTTON_RIGHT: u8 = 0b00000001;
+pub const BUTTON_LEFT: u8 = 0b00000010;
+pub const BUTTON_DOWN: u8 = 0b00000100;
+pub const BUTTON_UP: u8 = 0b00001000;
+pub const BUTTON_START: u8 = 0b00010000;
+pub const BUTTON_SELECT: u8 = 0b00100000;
+pub const BUTTON_B: u8 = 0b01000000;
+pub const BUTTON_A: u8 = 0b10000000;
+
+// Input port value when no buttons are held
+pub const NO_BUTTONS: u8 = 0xff;
+
+pub const BUTTON_NAMES: [(&str, u8); 8] = [
+    ("Right", BUTTON_RIGHT),
+    ("Left", BUTTON_LEFT),
+    ("Down", BUTTON_DOWN),
+    ("Up", BUTTON_UP),
+    ("Start", BUTTON_START),
+    ("Select", BUTTON_SELECT),
+    ("B", BUTTON_B),
+    ("A", BUTTON_A),
+];
+
+// Parses either a raw port value ("$f7") or held buttons ("Up+A", "none")
+pub fn parse_input(text: &str) -> Option<u8> {
+    if let Some(hex) = text.strip_prefix('$') {
+        return u8::from_str_radix(hex, 16).ok();
+    }
+    if text.eq_ignore_ascii_case("none") {
+        return Some(NO_BUTTONS);
+    }
+
+    let mut val = NO_BUTTONS;
+    for name in text.split('+') {
+        let (_, bit) = BUTTON_NAMES
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))?;
+        val &= !bit;
+    }
+    Some(val)
+}
+
+// Input changes keyed to frame numbers. Each line of a script is
+// "<frame> <input>", and the input is held until the next line applies.
+pub struct InputScript {
+    events: Vec<(u64, u8)>,
+}
+
+impl InputScript {
+    pub fn load(file_name: &str) -> Result<Self, Box<dyn Error>> {
+        Self::parse(&std::fs::read_to_string(file_name)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, Box<dyn Error>> {
+        let mut events = vec![];
+        for (line_no, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut tokens = line.split_whitespace();
+            let frame = tokens.next().and_then(|t| t.parse().ok());
+            let input = tokens.next().and_then(parse_input);
+            match (frame, input) {
+                (Some(frame), Some(input)) => events.push((frame, input)),
+                _ => return Err(format!("Invalid input on line {}: {}", line_no + 1, line).into()),
+            }
+        }
+        events.sort_by_key(|(frame, _)| *frame);
+
+        Ok(Self { events })
+    }
+
+    pub fn input_at(&self, frame: u64) -> u8 {
+        self.events
+            .iter()
+            .take_while(|(f, _)| *f <= frame)
+            .last()
+            .map_or(NO_BUTTONS, |(_, input)| *input)
+    }
+}
@@ -0,0 +1,21 @@
+pub mod asm;
+pub mod audio;
+pub mod cfg;
+pub mod cpu;
+pub mod debugger;
+pub mod expr;
+pub mod gdb;
+pub mod golden;
+pub mod harness;
+pub mod history;
+pub mod input;
+pub mod movie;
+pub mod patch;
+pub mod profile;
+pub mod project;
+pub mod savestate;
+pub mod screenshot;
+pub mod symbols;
+pub mod timing;
+pub mod vga;
+pub mod video;
@@ -1,254 +1,348 @@
 // TODO: Move things out of this file
 
 use std::{
-    collections::{BTreeMap, LinkedList},
-    error::Error,
+    collections::{HashMap, LinkedList},
     fmt::Display,
-    io::BufRead,
 };
 
-use bit_set::BitSet;
-use cpu::{MemAccess, MemOperation, OpCode, RomWord};
-use itertools::Itertools;
+use clap::Parser;
+#[cfg(feature = "playback")]
+use gigatron::audio::Playback;
+use gigatron::{
+    asm::{self, assembler},
+    audio::{self, Resampler, SampleStream, WavWriter},
+    cfg::{BlockGraph, ControlFlow, EdgeKind, ObservedJumps, PathTiming},
+    cpu,
+    cpu::{MemAccess, MemOperation, PowerOn, PowerOnFill, RomWord},
+    debugger::{self, Breakpoint, BreakpointList, Watch, WatchAction},
+    expr::Expr,
+    history::{self, History, HistoryEntry},
+    input::{
+        BUTTON_A, BUTTON_B, BUTTON_DOWN, BUTTON_LEFT, BUTTON_RIGHT, BUTTON_SELECT, BUTTON_START,
+        BUTTON_UP, NO_BUTTONS,
+    },
+    movie::Movie,
+    patch::RomPatches,
+    profile::Profile,
+    project::{self, BreakpointConfig, Project},
+    savestate::{self, SaveState},
+    symbols::{Placeholder, SymbolTable},
+    timing::{FrameTiming, LineErrors, LineTiming, TimingAnalyser},
+    vga::{TimingResult, Vga, VideoMode},
+};
 use packed_struct::PackedStruct;
 
-use crate::vga::{SyncTiming, Vga};
-
-pub mod asm;
-pub mod cpu;
-pub mod ui_context;
-pub mod vga;
-
-fn load_rom(file_name: &str) -> Result<Vec<RomWord>, std::io::Error> {
-    let bytes = std::fs::read(file_name)?;
+use crate::monitor::{Monitor, MonitorEvent};
 
-    let rom = bytes
-        .into_iter()
-        .tuples()
-        .map(|(opcode, data)| RomWord {
-            inst: OpCode(opcode),
-            data,
-        })
-        .collect_vec();
+mod monitor;
+mod ui_context;
 
-    Ok(rom)
+fn show_ram_view(ui: &imgui::Ui, ram: &mut [u8]) {
+    ui.window("RAM View").build(|| {
+        for row in 0..(cpu::RAM_SIZE / 16) {
+            let addr_base = row * 16;
+            ui.text(format!("{:04x}: ", addr_base));
+            for col in 0..16 {
+                let addr = addr_base + col;
+                ui.same_line();
+                ui.text(format!("{:02x}", ram[addr]));
+            }
+        }
+    });
 }
 
-struct ZeroPageVariable {
-    address: u8,
-    length: u8,
-    name: String,
+// Gathered from every executed instruction for the Control Flow and
+// Profiler windows. Stepping back doesn't rewind the profile, so undone
+// cycles stay counted.
+#[derive(Default)]
+struct ExecStats {
+    jumps: ObservedJumps,
+    profile: Profile,
 }
 
-pub enum Placeholder {
-    Literal {
-        val: String,
-    },
-    Unary {
-        name: String,
-        val: Box<Placeholder>,
-    },
-    Binary {
-        name: String,
-        lhs: Box<Placeholder>,
-        rhs: Box<Placeholder>,
-    },
+const PATCHED_COLOR: [f32; 4] = [1.0, 0.8, 0.3, 1.0];
+
+// Instruction marked from elsewhere in the UI, which the ROM View opens and
+// scrolls to once
+#[derive(Default)]
+struct RomViewTarget {
+    addr: Option<u16>,
+    scroll: bool,
 }
 
-impl Display for Placeholder {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Literal { val } => write!(f, "{}", val),
-            Self::Unary { name, val } => write!(f, "{}({})", name, val),
-            Self::Binary { name, lhs, rhs } => write!(f, "{} {} {}", lhs, name, rhs),
-        }
-    }
-}
-
-impl Placeholder {
-    fn parse(tokens: &[&str]) -> Result<(Self, usize), Box<dyn Error>> {
-        match tokens[0] {
-            token @ ("hi" | "lo") => {
-                let (val, val_len) = Self::parse(&tokens[1..])?;
-                Ok((
-                    Self::Unary {
-                        name: token.to_string(),
-                        val: Box::new(val),
-                    },
-                    val_len + 1,
-                ))
-            }
-            token @ "add" => {
-                let (lhs, lhs_len) = Self::parse(&tokens[1..])?;
-                let (rhs, rhs_len) = Self::parse(&tokens[(1 + lhs_len)..])?;
-                Ok((
-                    Self::Binary {
-                        name: token.to_string(),
-                        lhs: Box::new(lhs),
-                        rhs: Box::new(rhs),
-                    },
-                    lhs_len + rhs_len + 1,
-                ))
-            }
-            "zp" => {
-                let (val, val_len) = Self::parse(&tokens[1..])?;
-                Ok((val, val_len + 1))
-            }
-            token => Ok((
-                Self::Literal {
-                    val: token.to_string(),
-                },
-                1,
-            )),
-        }
+impl RomViewTarget {
+    fn jump_to(&mut self, addr: u16) {
+        self.addr = Some(addr);
+        self.scroll = true;
     }
 }
 
-struct SymbolTable {
-    zero_page: Vec<ZeroPageVariable>,
-    labels: BTreeMap<u16, String>, // FIXME there can be multiple labels on same address
-    placeholders: BTreeMap<u16, Placeholder>,
+// Instructions edited in the ROM View, with undo and export
+struct PatchPanel {
+    patches: RomPatches,
+    editing: Option<u16>,
+    edit_text: String,
+    // Disassembly the edit started from, entering it unchanged is a no-op
+    // so that unused data bytes aren't rewritten
+    original_text: String,
+    edit_error: Option<String>,
+    focus_edit: bool,
+
+    export_file: String,
+    status: Option<String>,
+    // Set whenever the ROM contents change
+    changed: bool,
 }
 
-impl SymbolTable {
-    fn load(file_name: &str) -> Result<Self, std::io::Error> {
-        // TODO: Clean up
+impl PatchPanel {
+    fn new(rom_file: &str) -> Self {
+        Self {
+            patches: RomPatches::new(),
+            editing: None,
+            edit_text: String::new(),
+            original_text: String::new(),
+            edit_error: None,
+            focus_edit: false,
+            export_file: format!("{}-patched.rom", rom_file),
+            status: None,
+            changed: false,
+        }
+    }
 
-        let file = std::fs::File::open(file_name)?;
-        let lines = std::io::BufReader::new(file).lines();
+    fn start_edit(&mut self, addr: u16, word: &RomWord) {
+        let inst = asm::Instruction::unpack(&[word.inst.0]).unwrap();
+        self.editing = Some(addr);
+        // Without the address in front
+        self.edit_text = inst.disassemble(addr, word.data, None)[6..].to_string();
+        self.original_text = self.edit_text.clone();
+        self.edit_error = None;
+        self.focus_edit = true;
+    }
 
-        let mut zero_page = vec![];
-        let mut labels = BTreeMap::new();
-        let mut placeholders = BTreeMap::new();
+    // Shows the edit box in place of the instruction being edited, returning
+    // the assembled instruction once Enter is pressed
+    fn edit_row(
+        &mut self,
+        ui: &imgui::Ui,
+        symbols: &SymbolTable,
+    ) -> Option<(RomWord, Option<Placeholder>)> {
+        if std::mem::take(&mut self.focus_edit) {
+            ui.set_keyboard_focus_here();
+        }
+        ui.set_next_item_width(-1.0);
+        let entered = ui
+            .input_text("##edit", &mut self.edit_text)
+            .enter_returns_true(true)
+            .build();
+        // Escape or clicking elsewhere cancels the edit
+        let cancelled = !entered && ui.is_item_deactivated();
+        if let Some(err) = &self.edit_error {
+            ui.text_colored([1.0, 0.3, 0.3, 1.0], err);
+        }
 
-        for line in lines.filter_map(Result::ok) {
-            let tokens = line.split(" ").collect_vec();
-            if tokens.is_empty() {
-                continue;
+        if cancelled || (entered && self.edit_text == self.original_text) {
+            self.editing = None;
+        }
+        if !entered || self.editing.is_none() {
+            return None;
+        }
+        match assembler::assemble_line(&self.edit_text, symbols) {
+            Ok(patch) => {
+                self.editing = None;
+                Some(patch)
             }
+            Err(e) => {
+                self.edit_error = Some(e);
+                self.focus_edit = true;
+                None
+            }
+        }
+    }
 
-            match tokens[0] {
-                "z" => {
-                    if tokens.len() != 4 {
-                        continue;
-                    }
-
-                    if let Ok(addr) = tokens[1].parse() {
-                        if let Ok(length) = tokens[2].parse() {
-                            zero_page.push(ZeroPageVariable {
-                                address: addr,
-                                length,
-                                name: tokens[3].to_string(),
-                            })
-                        }
+    fn show_ui(
+        &mut self,
+        ui: &imgui::Ui,
+        rom: &mut [RomWord],
+        symbols: &mut SymbolTable,
+        target: &mut RomViewTarget,
+    ) {
+        ui.window("ROM Patches").build(|| {
+            ui.text_disabled("Double-click an instruction in the ROM View to edit it");
+            {
+                let _disabled = ui.begin_disabled(self.patches.patches().is_empty());
+                if ui.button("Undo") {
+                    if let Some(addr) = self.patches.undo(rom, symbols) {
+                        target.jump_to(addr);
+                        self.status = None;
+                        self.changed = true;
                     }
                 }
-                "l" => {
-                    if tokens.len() != 3 {
-                        continue;
-                    }
+            }
+            ui.same_line();
+            if ui.button("Export") {
+                self.status = Some(match cpu::save_rom(&self.export_file, rom) {
+                    Ok(()) => format!("Saved {}", self.export_file),
+                    Err(e) => format!("Failed to write {}: {}", self.export_file, e),
+                });
+            }
+            ui.same_line();
+            ui.input_text("##export", &mut self.export_file).build();
+            if let Some(status) = &self.status {
+                ui.text(status);
+            }
 
-                    if let Ok(addr) = tokens[1].parse() {
-                        labels.insert(addr, tokens[2].to_string());
-                    }
-                }
-                "p" => {
-                    if let Ok(addr) = tokens[1].parse() {
-                        if let Ok((placeholder, _)) = Placeholder::parse(&tokens[2..]) {
-                            placeholders.insert(addr, placeholder);
-                        }
+            if let Some(_t) = ui.begin_table_with_flags("patches", 3, imgui::TableFlags::BORDERS_V)
+            {
+                ui.table_setup_column("Addr");
+                ui.table_setup_column("Old");
+                ui.table_setup_column("New");
+                ui.table_headers_row();
+
+                // Newest first, in the order they are undone
+                for (i, patch) in self.patches.patches().iter().enumerate().rev() {
+                    let _id = ui.push_id_usize(i);
+                    let disassemble = |word: &RomWord| {
+                        let inst = asm::Instruction::unpack(&[word.inst.0]).unwrap();
+                        inst.disassemble(patch.addr, word.data, None)[6..].to_string()
+                    };
+
+                    ui.table_next_column();
+                    if ui.selectable(format!("{:04x}", patch.addr)) {
+                        target.jump_to(patch.addr);
                     }
+                    ui.table_next_column();
+                    ui.text(disassemble(&patch.old));
+                    ui.table_next_column();
+                    ui.text(disassemble(&patch.new));
                 }
-                _ => {}
             }
-        }
-
-        Ok(Self {
-            zero_page,
-            labels,
-            placeholders,
-        })
-    }
-
-    fn find_label_before(&self, addr: u16) -> Option<u16> {
-        self.labels
-            .range(..addr)
-            .next_back()
-            .map(|t| t.0.to_owned())
+        });
     }
 }
 
-fn show_ram_view(ui: &imgui::Ui, ram: &mut Vec<u8>) {
-    ui.window("RAM View").build(|| {
-        for row in 0..(cpu::RAM_SIZE / 16) {
-            let addr_base = row * 16;
-            ui.text(format!("{:04x}: ", addr_base));
-            for col in 0..16 {
-                let addr = addr_base + col;
-                ui.same_line();
-                ui.text(format!("{:02x}", ram[addr]));
-            }
-        }
-    });
+// Share of the profiled cycles, shaded by how hot the instruction is
+fn show_heat(ui: &imgui::Ui, cycles: u64, max: u64, total: u64) {
+    if cycles == 0 {
+        return;
+    }
+    let heat = cycles as f32 / max as f32;
+    ui.table_set_bg_color(
+        imgui::TableBgTarget::CELL_BG,
+        [0.4 + 0.6 * heat, 0.3 * (1.0 - heat), 0.1, 0.2 + 0.6 * heat],
+    );
+    ui.text(format!("{:.2}%", cycles as f64 * 100.0 / total as f64));
 }
 
 fn show_rom_view(
     ui: &imgui::Ui,
-    rom: &Vec<RomWord>,
-    symbols: &SymbolTable,
-    highlight: u16,
+    cpu: &mut cpu::Cpu,
+    symbols: &mut SymbolTable,
     debugger: &mut Debugger,
+    target: &mut RomViewTarget,
+    patcher: &mut PatchPanel,
+    profile: &Profile,
 ) {
+    let highlight = cpu.state.reg.pc;
+    let rom = &mut cpu.rom;
+    let max_cycles = profile.max_count();
+    let label_cycles: HashMap<u16, u64> = profile
+        .by_label(symbols)
+        .into_iter()
+        .map(|l| (l.addr, l.cycles))
+        .collect();
+    let mut patch = None;
     ui.window("ROM View").build(|| {
-        if let Some(_t) = ui.begin_table_with_flags("rom", 2, imgui::TableFlags::BORDERS_V) {
+        if let Some(_t) = ui.begin_table_with_flags("rom", 3, imgui::TableFlags::BORDERS_V) {
             ui.table_setup_column("Instruction");
+            let mut cycles_col = imgui::TableColumnSetup::new("Cycles");
+            cycles_col.flags = imgui::TableColumnFlags::WIDTH_FIXED;
+            cycles_col.init_width_or_weight = 60.0;
+            ui.table_setup_column_with(cycles_col);
             let mut bp_col = imgui::TableColumnSetup::new("Breakpoint");
             bp_col.flags = imgui::TableColumnFlags::WIDTH_FIXED;
             bp_col.init_width_or_weight = 50.0;
             ui.table_setup_column_with(bp_col);
 
-            let mut addr = 0;
-
             let highlight_label = symbols.find_label_before(highlight);
+            // Label whose tree holds the target, None for the start tree
+            let target_label = target
+                .addr
+                .map(|addr| symbols.labels.range(..=addr).next_back().map(|(&a, _)| a));
+            let scroll = target.scroll;
+            let opens_target = move |label: Option<u16>| scroll && target_label == Some(label);
 
             ui.table_next_column();
-            let mut current_tree = ui
+            let mut tree = ui
                 .tree_node_config("start:")
-                .selected(highlight_label.is_none())
-                .push();
+                .selected(highlight_label.is_none());
+            if opens_target(None) {
+                tree = tree.opened(true, imgui::Condition::Always);
+            }
+            let mut current_tree = tree.push();
+            ui.table_next_column();
+            if !symbols.labels.contains_key(&0) {
+                show_heat(ui, label_cycles[&0], u64::MAX, profile.total());
+            }
             ui.table_next_column();
             ui.text_disabled("--");
-            for word in rom {
+            for (addr, word) in rom.iter().enumerate() {
+                let addr = addr as u16;
                 if let Some(label) = symbols.labels.get(&addr) {
                     ui.table_next_column();
                     if let Some(t) = current_tree {
                         t.pop();
                     }
 
-                    current_tree = ui
+                    let mut tree = ui
                         .tree_node_config(format!("{}:", label))
-                        .selected(highlight_label.map_or(false, |a| addr == a))
-                        .push();
+                        .selected(highlight_label == Some(addr));
+                    if opens_target(Some(addr)) {
+                        tree = tree.opened(true, imgui::Condition::Always);
+                    }
+                    current_tree = tree.push();
+                    ui.table_next_column();
+                    show_heat(ui, label_cycles[&addr], u64::MAX, profile.total());
                     ui.table_next_column();
                     ui.text_disabled("--");
                 }
 
-                if let Some(_) = current_tree {
+                if current_tree.is_some() {
                     let inst = asm::Instruction::unpack(&[word.inst.0]).unwrap();
                     let data = word.data;
 
                     ui.table_next_column();
                     let _id = ui.push_id_int(addr as i32);
-                    ui.align_text_to_frame_padding();
-                    ui.tree_node_config(inst.disassemble(
-                        addr,
-                        data,
-                        symbols.placeholders.get(&addr),
-                    ))
-                    .leaf(true)
-                    .tree_push_on_open(false)
-                    .selected(addr == highlight)
-                    .push();
+                    if patcher.editing == Some(addr) {
+                        if let Some(p) = patcher.edit_row(ui, symbols) {
+                            patch = Some((addr, p));
+                        }
+                    } else {
+                        let _color = patcher
+                            .patches
+                            .is_patched(addr)
+                            .then(|| ui.push_style_color(imgui::StyleColor::Text, PATCHED_COLOR));
+                        ui.align_text_to_frame_padding();
+                        ui.tree_node_config(inst.disassemble(
+                            addr,
+                            data,
+                            symbols.placeholders.get(&addr),
+                        ))
+                        .leaf(true)
+                        .tree_push_on_open(false)
+                        .selected(addr == highlight || target.addr == Some(addr))
+                        .push();
+                        if ui.is_item_hovered()
+                            && ui.is_mouse_double_clicked(imgui::MouseButton::Left)
+                        {
+                            patcher.start_edit(addr, word);
+                        }
+                    }
+                    if target.scroll && target.addr == Some(addr) {
+                        ui.set_scroll_here_y_with_ratio(0.5);
+                        target.scroll = false;
+                    }
+                    ui.table_next_column();
+                    show_heat(ui, profile.count(addr), max_cycles, profile.total());
                     ui.table_next_column();
 
                     let mut bp = debugger.has_breakpoint(addr);
@@ -256,16 +350,19 @@ fn show_rom_view(
                         debugger.set_breakpoint(addr, bp);
                     }
                 }
-
-                addr += 1;
             }
         }
     });
+
+    if let Some((addr, (word, placeholder))) = patch {
+        patcher.patches.apply(rom, symbols, addr, word, placeholder);
+        patcher.changed = true;
+    }
 }
 
 fn show_zero_page_vars(
     ui: &imgui::Ui,
-    ram: &mut Vec<u8>,
+    ram: &mut [u8],
     sym_tbl: &SymbolTable,
     watches: &mut WatchesPanel,
 ) {
@@ -301,7 +398,7 @@ fn show_zero_page_vars(
 
                 ui.table_next_column();
                 if ui.button("Watch") {
-                    watches.add_watch(var.address as u16, WatchType::Write);
+                    watches.add_write_watch(var.address as u16);
                 }
             }
         }
@@ -311,14 +408,19 @@ fn show_zero_page_vars(
 enum RunState {
     FullSpeed,
     Step,
+    StepBack,
+    FrameBack,
+    RunBack,
     Paused,
 }
 
 enum PauseReason {
     Manual,
     Breakpoint,
+    Watch,
     FrameTimeout,
     HorizCycleErr,
+    VertTimingErr,
 }
 
 impl Display for PauseReason {
@@ -326,8 +428,10 @@ impl Display for PauseReason {
         f.write_str(match self {
             Self::Manual => "Manual",
             Self::Breakpoint => "Hit Breakpoint",
+            Self::Watch => "Hit Watch",
             Self::FrameTimeout => "Frame Timeout",
             Self::HorizCycleErr => "Horizontal Cycle Error",
+            Self::VertTimingErr => "Vertical Timing Error",
         })
     }
 }
@@ -335,23 +439,44 @@ impl Display for PauseReason {
 // TODO: Merge with debugger?
 struct RunControl {
     paused: Option<PauseReason>,
+    // Set while paused before an instruction whose breakpoint was already
+    // checked, so resuming doesn't break on it again
+    skip_break: bool,
     break_on_horiz_cycle_errors: bool,
+    break_on_vert_timing_errors: bool,
+
+    rom_file: String,
+    state_slot: u32,
+    state_status: Option<String>,
 }
 
 impl RunControl {
-    fn new() -> Self {
+    fn new(rom_file: &str) -> Self {
         Self {
             paused: None,
+            skip_break: false,
             break_on_horiz_cycle_errors: false,
+            break_on_vert_timing_errors: false,
+
+            rom_file: rom_file.to_string(),
+            state_slot: 1,
+            state_status: None,
         }
     }
 
     fn pause(&mut self, reason: PauseReason) {
+        self.skip_break = matches!(reason, PauseReason::Breakpoint);
         self.paused = Some(reason);
     }
 
-    fn show_ui(&mut self, ui: &imgui::Ui, cpu: &mut cpu::Cpu) -> RunState {
-        let mut step = false;
+    fn show_ui(
+        &mut self,
+        ui: &imgui::Ui,
+        cpu: &mut cpu::Cpu,
+        vga: &mut Vga,
+        history: &mut History,
+    ) -> RunState {
+        let mut state = None;
         if let Some(_w) = ui.window("Run Control").begin() {
             if let Some(reason) = &self.paused {
                 ui.text(format!("Paused: {}", reason));
@@ -360,7 +485,20 @@ impl RunControl {
                 } else {
                     ui.same_line();
                     if ui.button("Step") {
-                        step = true;
+                        state = Some(RunState::Step);
+                    }
+
+                    let _disabled = ui.begin_disabled(history.is_empty());
+                    if ui.button("Step Back") {
+                        state = Some(RunState::StepBack);
+                    }
+                    ui.same_line();
+                    if ui.button("Back to previous frame") {
+                        state = Some(RunState::FrameBack);
+                    }
+                    ui.same_line();
+                    if ui.button("Run backwards to breakpoint") {
+                        state = Some(RunState::RunBack);
                     }
                 }
             } else {
@@ -375,21 +513,83 @@ impl RunControl {
             ui.same_line();
             if ui.button("Soft") {
                 cpu.soft_reset();
+                history.clear();
+                self.skip_break = false;
             }
             ui.same_line();
             if ui.button("Hard") {
                 cpu.hard_reset();
+                history.clear();
+                self.skip_break = false;
+            }
+
+            ui.input_scalar("Seed", &mut cpu.power_on.seed).build();
+            ui.same_line();
+            if ui.button("New") {
+                cpu.power_on.seed = rand::random();
+            }
+
+            let mut fill_idx = PowerOnFill::ALL
+                .iter()
+                .position(|&fill| fill == cpu.power_on.fill)
+                .unwrap_or(0);
+            let fill_names = PowerOnFill::ALL.map(|fill| fill.to_string());
+            if ui.combo_simple_string("Power-on fill", &mut fill_idx, &fill_names) {
+                cpu.power_on.fill = PowerOnFill::ALL[fill_idx];
             }
 
+            ui.spacing();
+            ui.text("Quick save:");
+            for slot in 1..=9 {
+                ui.same_line();
+                if ui.radio_button_bool(format!("{}", slot), self.state_slot == slot) {
+                    self.state_slot = slot;
+                }
+            }
+            let file_name = savestate::slot_file_name(&self.rom_file, self.state_slot);
+            if ui.button("Save state") {
+                self.state_status = Some(match SaveState::capture(cpu, vga).save(&file_name) {
+                    Ok(()) => format!("Saved {}", file_name),
+                    Err(e) => format!("Failed to save {}: {}", file_name, e),
+                });
+            }
+            ui.same_line();
+            if ui.button("Load state") {
+                let result = SaveState::load(&file_name).and_then(|s| s.restore(cpu, vga));
+                self.state_status = Some(match result {
+                    Ok(()) => {
+                        history.clear();
+                        self.skip_break = false;
+                        format!("Loaded {}", file_name)
+                    }
+                    Err(e) => format!("Failed to load {}: {}", file_name, e),
+                });
+            }
+            if let Some(status) = &self.state_status {
+                ui.text(status);
+            }
+
+            ui.spacing();
             ui.checkbox(
                 "Break on horizontal cycle errors",
                 &mut self.break_on_horiz_cycle_errors,
             );
+            ui.checkbox(
+                "Break on vertical timing errors",
+                &mut self.break_on_vert_timing_errors,
+            );
+
+            ui.text(format!(
+                "History: {} / {} cycles",
+                history.len(),
+                history.capacity()
+            ));
         }
 
-        if step {
-            RunState::Step
-        } else if let Some(_) = self.paused {
+        if let Some(state) = state {
+            self.skip_break = false;
+            state
+        } else if self.paused.is_some() {
             RunState::Paused
         } else {
             RunState::FullSpeed
@@ -440,6 +640,7 @@ fn show_registers(ui: &imgui::Ui, reg: &mut cpu::RegisterFile) {
             show(ui, "X", reg.x);
             show(ui, "Y", reg.y);
             show(ui, "OUT", reg.out);
+            show(ui, "XOUT", reg.xout);
         }
 
         ui.spacing();
@@ -459,6 +660,384 @@ fn show_registers(ui: &imgui::Ui, reg: &mut cpu::RegisterFile) {
     });
 }
 
+struct AudioPanel {
+    scope: SampleStream,
+
+    wav_file: String,
+    wav: Option<(WavWriter, Resampler)>,
+    #[cfg(feature = "playback")]
+    playback: Option<(Playback, Resampler)>,
+
+    // Host samples produced since the last UI frame
+    wav_pending: Vec<i16>,
+    playback_pending: Vec<i16>,
+    status: Option<String>,
+}
+
+impl AudioPanel {
+    fn new() -> Self {
+        Self {
+            scope: SampleStream::new(AUDIO_SCOPE_SAMPLES),
+            wav_file: "capture.wav".to_string(),
+            wav: None,
+            #[cfg(feature = "playback")]
+            playback: None,
+            wav_pending: Vec::new(),
+            playback_pending: Vec::new(),
+            status: None,
+        }
+    }
+
+    fn update(&mut self, info: &cpu::CycleInfo, reg: &cpu::RegisterFile) {
+        self.scope.update(info, reg);
+
+        if let Some((_, resampler)) = &mut self.wav {
+            if let Some(sample) = resampler.update(reg) {
+                self.wav_pending.push(sample);
+            }
+        }
+        #[cfg(feature = "playback")]
+        if let Some((_, resampler)) = &mut self.playback {
+            if let Some(sample) = resampler.update(reg) {
+                self.playback_pending.push(sample);
+            }
+        }
+    }
+
+    // Hands the samples of this UI frame to the WAV file and audio device
+    fn flush(&mut self) {
+        if let Some((wav, _)) = &mut self.wav {
+            let result = self
+                .wav_pending
+                .iter()
+                .try_for_each(|&sample| wav.write_sample(sample));
+            if let Err(e) = result {
+                self.status = Some(format!("Failed to write {}: {}", self.wav_file, e));
+                self.wav = None;
+            }
+        }
+        self.wav_pending.clear();
+
+        #[cfg(feature = "playback")]
+        if let Some((playback, _)) = &self.playback {
+            playback.push(&self.playback_pending);
+        }
+        self.playback_pending.clear();
+    }
+
+    fn start_wav(&mut self, file_name: String) {
+        self.stop_wav();
+        self.wav_file = file_name;
+        match WavWriter::create(&self.wav_file, audio::DEFAULT_SAMPLE_RATE) {
+            Ok(wav) => {
+                self.wav = Some((wav, Resampler::new(audio::DEFAULT_SAMPLE_RATE)));
+                self.status = Some(format!("Recording {}", self.wav_file));
+            }
+            Err(e) => {
+                self.status = Some(format!("Failed to create {}: {}", self.wav_file, e));
+            }
+        }
+    }
+
+    fn stop_wav(&mut self) {
+        self.flush();
+        if let Some((wav, resampler)) = self.wav.take() {
+            if let Err(e) = wav.finish(resampler.sample_rate()) {
+                self.status = Some(format!("Failed to write {}: {}", self.wav_file, e));
+            } else {
+                self.status = Some(format!("Saved {}", self.wav_file));
+            }
+        }
+    }
+
+    fn show_ui(&mut self, ui: &imgui::Ui, reg: &cpu::RegisterFile) {
+        ui.window("Extended Output").build(|| {
+            ui.text("LEDs:");
+            let draw_list = ui.get_window_draw_list();
+            let [x, y] = ui.cursor_screen_pos();
+            let radius = 8.0;
+            for i in 0..4 {
+                let center = [x + radius + i as f32 * radius * 3.0, y + radius];
+                let color = if reg.leds() & (1 << i) != 0 {
+                    [1.0, 0.1, 0.1, 1.0]
+                } else {
+                    [0.3, 0.1, 0.1, 1.0]
+                };
+                draw_list
+                    .add_circle(center, radius, color)
+                    .filled(true)
+                    .build();
+            }
+            ui.dummy([radius * 12.0, radius * 2.0]);
+
+            ui.text(format!("Audio sample: {:x}", reg.audio_sample()));
+            let values = self.scope.samples().map(|s| s as f32).collect::<Vec<_>>();
+            ui.plot_lines("##audio", &values)
+                .scale_min(0.0)
+                .scale_max(15.0)
+                .graph_size([0.0, 80.0])
+                .build();
+
+            ui.spacing();
+            match &self.wav {
+                Some(_) => {
+                    if ui.button("Stop recording") {
+                        self.stop_wav();
+                    }
+                }
+                None => {
+                    ui.input_text("##wav", &mut self.wav_file).build();
+                    ui.same_line();
+                    if ui.button("Record WAV") {
+                        self.start_wav(self.wav_file.clone());
+                    }
+                }
+            }
+
+            #[cfg(feature = "playback")]
+            {
+                let mut playing = self.playback.is_some();
+                if ui.checkbox("Play audio", &mut playing) {
+                    self.playback = None;
+                    if playing {
+                        match Playback::new() {
+                            Ok(playback) => {
+                                let resampler = Resampler::new(playback.sample_rate());
+                                self.playback = Some((playback, resampler));
+                            }
+                            Err(e) => self.status = Some(format!("Failed to play audio: {}", e)),
+                        }
+                    }
+                }
+            }
+
+            if let Some(status) = &self.status {
+                ui.text(status);
+            }
+        });
+    }
+}
+
+// Shows the sync timing of the last completed frame, as a grid with one cell
+// per line and a table of the measurements
+struct TimingPanel {
+    frame: Option<FrameTiming>,
+    hold: bool,
+    only_errors: bool,
+
+    custom_mode: String,
+    mode_err: Option<String>,
+}
+
+// Cells per row of the line grid
+const TIMING_GRID_COLUMNS: usize = 35;
+const TIMING_CELL_SIZE: f32 = 8.0;
+
+impl TimingPanel {
+    fn new() -> Self {
+        Self {
+            frame: None,
+            hold: false,
+            only_errors: true,
+            custom_mode: String::new(),
+            mode_err: None,
+        }
+    }
+
+    fn line_color(line: &LineTiming, errors: &LineErrors) -> [f32; 4] {
+        if errors.pulse || errors.total {
+            [0.9, 0.1, 0.1, 1.0]
+        } else if errors.early_visible {
+            [0.9, 0.6, 0.1, 1.0]
+        } else if line.vsync {
+            [0.2, 0.3, 0.9, 1.0]
+        } else {
+            [0.1, 0.5, 0.1, 1.0]
+        }
+    }
+
+    fn show_line_tooltip(ui: &imgui::Ui, index: usize, line: &LineTiming, errors: &LineErrors) {
+        let opt = |v: Option<i32>| v.map_or("-".to_string(), |v| v.to_string());
+        ui.tooltip(|| {
+            ui.text(format!("Line {}", index));
+            ui.text(format!("Pulse: {}", opt(line.pulse)));
+            ui.text(format!("Back porch: {}", opt(line.back_porch())));
+            ui.text(format!("Visible start: {}", opt(line.visible_start)));
+            ui.text(format!("Total: {}", line.total));
+            if line.vsync {
+                ui.text("In VSYNC");
+            }
+            if errors.any() {
+                ui.text_colored([1.0, 0.3, 0.3, 1.0], "Deviates from expected timing");
+            }
+        });
+    }
+
+    // Returns a newly selected video mode
+    fn show_mode_ui(&mut self, ui: &imgui::Ui, current: VideoMode) -> Option<VideoMode> {
+        let mut names: Vec<&str> = VideoMode::NAMED.iter().map(|(name, _)| *name).collect();
+        names.push("custom");
+        let mut idx = VideoMode::NAMED
+            .iter()
+            .position(|(_, mode)| *mode == current)
+            .unwrap_or(names.len() - 1);
+
+        let mut selected = None;
+        if ui.combo_simple_string("Video mode", &mut idx, &names) {
+            match VideoMode::NAMED.get(idx) {
+                Some((_, mode)) => selected = Some(*mode),
+                None => self.custom_mode = current.to_string(),
+            }
+        }
+        if current.name().is_none() || idx == names.len() - 1 {
+            ui.input_text("##custom_mode", &mut self.custom_mode)
+                .hint("front,pulse,back,visible/front,pulse,back,visible")
+                .build();
+            ui.same_line();
+            if ui.button("Apply") {
+                match self.custom_mode.parse() {
+                    Ok(mode) => {
+                        selected = Some(mode);
+                        self.mode_err = None;
+                    }
+                    Err(e) => self.mode_err = Some(e),
+                }
+            }
+            if let Some(err) = &self.mode_err {
+                ui.text_colored([1.0, 0.3, 0.3, 1.0], err);
+            }
+        }
+        selected
+    }
+
+    fn show_ui(&mut self, ui: &imgui::Ui, timing: &TimingAnalyser) -> Option<VideoMode> {
+        if !self.hold {
+            self.frame = timing.last_frame().cloned();
+        }
+        let (horiz, vert) = (&timing.horiz, &timing.vert);
+
+        let mut selected = None;
+        ui.window("Sync Timing").build(|| {
+            selected = self.show_mode_ui(
+                ui,
+                VideoMode {
+                    horiz: *horiz,
+                    vert: *vert,
+                },
+            );
+            ui.checkbox("Hold", &mut self.hold);
+            let Some(frame) = &self.frame else {
+                ui.text("No complete frame yet");
+                return;
+            };
+
+            let vert_color = if frame.vert_error(horiz, vert) {
+                [1.0, 0.3, 0.3, 1.0]
+            } else {
+                [1.0, 1.0, 1.0, 1.0]
+            };
+            ui.text_colored(
+                vert_color,
+                format!("Lines: {} (expected {})", frame.lines.len(), vert.total()),
+            );
+            ui.text_colored(
+                vert_color,
+                format!(
+                    "VSYNC: {:.2} lines (expected {})",
+                    frame.vsync_lines(horiz),
+                    vert.pulse
+                ),
+            );
+            ui.text(format!(
+                "Lines deviating from {} pulse, {} back porch, {} total: {}",
+                horiz.pulse,
+                horiz.back_porch,
+                horiz.total(),
+                frame.bad_lines(horiz)
+            ));
+
+            let rows = frame.lines.len().div_ceil(TIMING_GRID_COLUMNS);
+            let [x, y] = ui.cursor_screen_pos();
+            let size = [
+                TIMING_GRID_COLUMNS as f32 * TIMING_CELL_SIZE,
+                rows as f32 * TIMING_CELL_SIZE,
+            ];
+            let draw_list = ui.get_window_draw_list();
+            for (i, line) in frame.lines.iter().enumerate() {
+                let cx = x + (i % TIMING_GRID_COLUMNS) as f32 * TIMING_CELL_SIZE;
+                let cy = y + (i / TIMING_GRID_COLUMNS) as f32 * TIMING_CELL_SIZE;
+                let color = Self::line_color(line, &line.errors(horiz));
+                draw_list
+                    .add_rect(
+                        [cx, cy],
+                        [cx + TIMING_CELL_SIZE - 1.0, cy + TIMING_CELL_SIZE - 1.0],
+                        color,
+                    )
+                    .filled(true)
+                    .build();
+            }
+            ui.invisible_button("##timing_grid", size);
+            if ui.is_item_hovered() {
+                let [mx, my] = ui.io().mouse_pos;
+                let col = ((mx - x) / TIMING_CELL_SIZE) as usize;
+                let row = ((my - y) / TIMING_CELL_SIZE) as usize;
+                let index = row * TIMING_GRID_COLUMNS + col;
+                if let Some(line) = frame.lines.get(index).filter(|_| col < TIMING_GRID_COLUMNS) {
+                    Self::show_line_tooltip(ui, index, line, &line.errors(horiz));
+                }
+            }
+
+            ui.checkbox("Only lines with errors", &mut self.only_errors);
+            let lines: Vec<(usize, &LineTiming)> = frame
+                .lines
+                .iter()
+                .enumerate()
+                .filter(|(_, line)| !self.only_errors || line.errors(horiz).any())
+                .collect();
+            let flags = imgui::TableFlags::BORDERS | imgui::TableFlags::SCROLL_Y;
+            let Some(_t) = ui.begin_table_with_sizing("timing", 6, flags, [0.0, 300.0], 0.0) else {
+                return;
+            };
+            ui.table_setup_scroll_freeze(0, 1);
+            for name in [
+                "Line",
+                "Pulse",
+                "Back porch",
+                "Visible start",
+                "Total",
+                "VSYNC",
+            ] {
+                ui.table_setup_column(name);
+            }
+            ui.table_headers_row();
+
+            let red = [1.0, 0.3, 0.3, 1.0];
+            let cell = |text: String, bad: bool| {
+                ui.table_next_column();
+                if bad {
+                    ui.text_colored(red, text);
+                } else {
+                    ui.text(text);
+                }
+            };
+            let opt = |v: Option<i32>| v.map_or("-".to_string(), |v| v.to_string());
+            let clipper = imgui::ListClipper::new(lines.len() as i32).begin(ui);
+            for row in clipper.iter() {
+                let (index, line) = lines[row as usize];
+                let errors = line.errors(horiz);
+                ui.table_next_row();
+                cell(index.to_string(), false);
+                cell(opt(line.pulse), errors.pulse);
+                cell(opt(line.back_porch()), errors.early_visible);
+                cell(opt(line.visible_start), errors.early_visible);
+                cell(line.total.to_string(), errors.total);
+                cell(if line.vsync { "yes" } else { "" }.to_string(), false);
+            }
+        });
+        selected
+    }
+}
+
 fn show_controller_input(ui: &imgui::Ui) -> u8 {
     if let Some(_w) = ui.window("Controller").begin() {
         let input = |name: &str, bit: u8, key: imgui::Key| {
@@ -470,31 +1049,162 @@ fn show_controller_input(ui: &imgui::Ui) -> u8 {
             }
         };
 
-        let val = input("Right", 0b00000001, imgui::Key::RightArrow)
-            | input("Left", 0b00000010, imgui::Key::LeftArrow)
-            | input("Down", 0b00000100, imgui::Key::DownArrow)
-            | input("Up", 0b00001000, imgui::Key::UpArrow)
-            | input("Start", 0b00010000, imgui::Key::Enter)
-            | input("Select", 0b00100000, imgui::Key::RightShift)
-            | input("B", 0b01000000, imgui::Key::Z)
-            | input("A", 0b10000000, imgui::Key::X);
+        let val = input("Right", BUTTON_RIGHT, imgui::Key::RightArrow)
+            | input("Left", BUTTON_LEFT, imgui::Key::LeftArrow)
+            | input("Down", BUTTON_DOWN, imgui::Key::DownArrow)
+            | input("Up", BUTTON_UP, imgui::Key::UpArrow)
+            | input("Start", BUTTON_START, imgui::Key::Enter)
+            | input("Select", BUTTON_SELECT, imgui::Key::RightShift)
+            | input("B", BUTTON_B, imgui::Key::Z)
+            | input("A", BUTTON_A, imgui::Key::X);
 
         ui.text(format!("Value: {:02x}: {:08b}", val, val));
 
         val
     } else {
-        255
+        NO_BUTTONS
     }
 }
 
-enum WatchType {
-    Read,
-    Write,
+enum MovieMode {
+    Live,
+    Recording(Movie),
+    Playing { movie: Movie, frame: u64 },
 }
 
-struct Watch {
-    read: bool,
-    write: bool,
+// Records or replays controller input. While a movie is active the input only
+// changes at the start of a frame, so the session can be replayed exactly.
+struct MoviePanel {
+    mode: MovieMode,
+    live_input: u8,
+
+    file_name: String,
+    status: Option<String>,
+}
+
+impl MoviePanel {
+    fn new(rom_file: &str) -> Self {
+        Self {
+            mode: MovieMode::Live,
+            live_input: NO_BUTTONS,
+            file_name: format!("{}.movie", rom_file),
+            status: None,
+        }
+    }
+
+    fn set_live_input(&mut self, cpu: &mut cpu::Cpu, input: u8) {
+        self.live_input = input;
+        if let MovieMode::Live = self.mode {
+            cpu.input = input;
+        }
+    }
+
+    // Called when a VSYNC falling edge starts a new frame
+    fn frame_start(&mut self, cpu: &mut cpu::Cpu) {
+        match &mut self.mode {
+            MovieMode::Live => {}
+            MovieMode::Recording(movie) => {
+                cpu.input = self.live_input;
+                movie.inputs.push(self.live_input);
+            }
+            MovieMode::Playing { movie, frame } => {
+                *frame += 1;
+                match movie.input_at(*frame) {
+                    Some(input) => cpu.input = input,
+                    None => {
+                        self.status = Some(format!("Finished playing {} frames", movie.len()));
+                        self.mode = MovieMode::Live;
+                    }
+                }
+            }
+        }
+    }
+
+    // Called when stepping back undoes the start of a frame
+    fn frame_undone(&mut self, cpu: &mut cpu::Cpu) {
+        match &mut self.mode {
+            MovieMode::Live => {}
+            MovieMode::Recording(movie) => {
+                if movie.len() > 1 {
+                    movie.inputs.pop();
+                }
+                cpu.input = *movie.inputs.last().unwrap();
+            }
+            MovieMode::Playing { movie, frame } => {
+                *frame = frame.saturating_sub(1);
+                cpu.input = movie.input_at(*frame).unwrap_or(NO_BUTTONS);
+            }
+        }
+    }
+
+    // Starting a movie also resets the monitor, so that frames are counted
+    // from power on the same way as when the movie is replayed
+    fn show_ui(
+        &mut self,
+        ui: &imgui::Ui,
+        cpu: &mut cpu::Cpu,
+        vga: &mut Vga,
+        history: &mut History,
+    ) {
+        ui.window("Movie").build(|| {
+            ui.input_text("File", &mut self.file_name).build();
+
+            match &self.mode {
+                MovieMode::Live => {
+                    if ui.button("Record from power on") {
+                        let mut movie = Movie::new(cpu);
+                        movie.inputs.push(self.live_input);
+                        cpu.hard_reset();
+                        *vga = Vga::from_mode(vga.mode());
+                        history.clear();
+                        cpu.input = self.live_input;
+                        self.mode = MovieMode::Recording(movie);
+                        self.status = None;
+                    }
+                    ui.same_line();
+                    if ui.button("Play") {
+                        let result = Movie::load(&self.file_name).and_then(|movie| {
+                            movie.start(cpu)?;
+                            Ok(movie)
+                        });
+                        match result {
+                            Ok(movie) => {
+                                *vga = Vga::from_mode(vga.mode());
+                                history.clear();
+                                cpu.input = movie.input_at(0).unwrap_or(NO_BUTTONS);
+                                self.mode = MovieMode::Playing { movie, frame: 0 };
+                                self.status = None;
+                            }
+                            Err(e) => {
+                                self.status =
+                                    Some(format!("Failed to load {}: {}", self.file_name, e))
+                            }
+                        }
+                    }
+                }
+                MovieMode::Recording(movie) => {
+                    ui.text(format!("Recording frame {}", movie.len()));
+                    if ui.button("Stop and save") {
+                        self.status = Some(match movie.save(&self.file_name) {
+                            Ok(()) => format!("Saved {} frames to {}", movie.len(), self.file_name),
+                            Err(e) => format!("Failed to save {}: {}", self.file_name, e),
+                        });
+                        self.mode = MovieMode::Live;
+                    }
+                }
+                MovieMode::Playing { movie, frame } => {
+                    ui.text(format!("Playing frame {} / {}", frame, movie.len()));
+                    if ui.button("Stop") {
+                        self.mode = MovieMode::Live;
+                    }
+                }
+            }
+
+            if let Some(status) = &self.status {
+                ui.text(status);
+            }
+        });
+    }
 }
 
 struct WatchEntry {
@@ -503,32 +1213,69 @@ struct WatchEntry {
 }
 
 struct WatchesPanel {
-    watches: BTreeMap<u16, Watch>,
+    watches: Vec<Watch>,
     log: LinkedList<WatchEntry>,
 
+    // Set when a Break watch matches, cleared by the main loop
+    triggered: bool,
+    // Set when the watch list is edited, so the project can be saved
+    changed: bool,
+
     new_op_idx: usize,
-    new_addr: u16,
+    new_start: u16,
+    new_end: u16,
+    new_action_idx: usize,
+    new_use_value: bool,
+    new_value: u8,
 }
 
 impl WatchesPanel {
     fn new() -> Self {
         Self {
-            watches: BTreeMap::new(),
+            watches: Vec::new(),
             log: LinkedList::new(),
+            triggered: false,
+            changed: false,
             new_op_idx: 0,
-            new_addr: 0,
+            new_start: 0,
+            new_end: 0,
+            new_action_idx: 1,
+            new_use_value: false,
+            new_value: 0,
         }
     }
 
-    fn add_watch(&mut self, addr: u16, ty: WatchType) {
-        let mut entry = self.watches.entry(addr).or_insert(Watch {
-            read: false,
-            write: false,
-        });
+    fn add_write_watch(&mut self, addr: u16) {
+        let idx = match self
+            .watches
+            .iter()
+            .position(|w| w.start == addr && w.end == addr && w.value.is_none())
+        {
+            Some(idx) => idx,
+            None => {
+                self.watches.push(Watch {
+                    start: addr,
+                    end: addr,
+                    read: false,
+                    write: false,
+                    action: WatchAction::Log,
+                    value: None,
+                });
+                self.watches.len() - 1
+            }
+        };
+        self.watches[idx].write = true;
+        self.changed = true;
+    }
 
-        match ty {
-            WatchType::Read => entry.read = true,
-            WatchType::Write => entry.write = true,
+    fn check_access(&mut self, pc: u16, access: MemAccess) {
+        match debugger::watch_action(&self.watches, &access) {
+            Some(WatchAction::Break) => {
+                self.triggered = true;
+                self.append_log(WatchEntry { pc, access });
+            }
+            Some(WatchAction::Log) => self.append_log(WatchEntry { pc, access }),
+            Some(WatchAction::Ignore) | None => {}
         }
     }
 
@@ -542,45 +1289,92 @@ impl WatchesPanel {
 
 fn show_watches_panel(ui: &mut imgui::Ui, panel: &mut WatchesPanel) {
     ui.window("Watches").build(|| {
-        if let Some(_t) = ui.begin_table("inputs", 3) {
+        if let Some(_t) = ui.begin_table("inputs", 6) {
             ui.table_next_column();
             ui.combo_simple_string("##op", &mut panel.new_op_idx, &["Read", "Write"]);
             ui.table_next_column();
-            ui.input_scalar("##addr", &mut panel.new_addr).build();
+            ui.input_scalar("##start", &mut panel.new_start)
+                .display_format("%04x")
+                .chars_hexadecimal(true)
+                .build();
+            ui.table_next_column();
+            ui.input_scalar("##end", &mut panel.new_end)
+                .display_format("%04x")
+                .chars_hexadecimal(true)
+                .build();
+            ui.table_next_column();
+            ui.checkbox("Value", &mut panel.new_use_value);
+            ui.same_line();
+            ui.input_scalar("##value", &mut panel.new_value)
+                .display_format("%02x")
+                .chars_hexadecimal(true)
+                .build();
+            ui.table_next_column();
+            ui.combo_simple_string("##action", &mut panel.new_action_idx, &WatchAction::NAMES);
             ui.table_next_column();
             if ui.button("Add") {
-                let ty = if panel.new_op_idx != 0 {
-                    WatchType::Write
-                } else {
-                    WatchType::Read
-                };
-
-                panel.add_watch(panel.new_addr, ty);
+                let write = panel.new_op_idx != 0;
+                panel.watches.push(Watch {
+                    start: panel.new_start,
+                    end: panel.new_end.max(panel.new_start),
+                    read: !write,
+                    write,
+                    action: WatchAction::ALL[panel.new_action_idx],
+                    value: panel.new_use_value.then_some(panel.new_value),
+                });
+                panel.changed = true;
             }
         }
 
         if ui.collapsing_header("Current Watches", imgui::TreeNodeFlags::empty()) {
-            if let Some(_t) = ui.begin_table_with_flags("watches", 3, imgui::TableFlags::BORDERS) {
+            let mut remove = None;
+            if let Some(_t) = ui.begin_table_with_flags("watches", 6, imgui::TableFlags::BORDERS) {
                 ui.table_setup_column("Addr");
                 ui.table_setup_column("Read");
                 ui.table_setup_column("Write");
+                ui.table_setup_column("Value");
+                ui.table_setup_column("Action");
+                ui.table_setup_column("Remove");
                 ui.table_headers_row();
 
-                let mut i = 0;
-                for watch in panel.watches.iter_mut() {
-                    let _id = ui.push_id_int(i);
-                    i += 1;
+                for (i, watch) in panel.watches.iter_mut().enumerate() {
+                    let _id = ui.push_id_usize(i);
 
                     ui.table_next_column();
-                    ui.text(format!("{:04x}", watch.0));
+                    if watch.start == watch.end {
+                        ui.text(format!("{:04x}", watch.start));
+                    } else {
+                        ui.text(format!("{:04x}-{:04x}", watch.start, watch.end));
+                    }
+                    ui.table_next_column();
+                    panel.changed |= ui.checkbox("##read", &mut watch.read);
                     ui.table_next_column();
-                    ui.checkbox("##read", &mut watch.1.read);
+                    panel.changed |= ui.checkbox("##write", &mut watch.write);
+                    ui.table_next_column();
+                    match watch.value {
+                        Some(val) => ui.text(format!("{:02x}", val)),
+                        None => ui.text_disabled("any"),
+                    }
                     ui.table_next_column();
-                    ui.checkbox("##write", &mut watch.1.write);
+                    let mut action_idx = WatchAction::ALL
+                        .iter()
+                        .position(|a| *a == watch.action)
+                        .unwrap();
+                    if ui.combo_simple_string("##action", &mut action_idx, &WatchAction::NAMES) {
+                        watch.action = WatchAction::ALL[action_idx];
+                        panel.changed = true;
+                    }
+                    ui.table_next_column();
+                    if ui.button("Remove") {
+                        remove = Some(i);
+                    }
                 }
             }
+            if let Some(i) = remove {
+                panel.watches.remove(i);
+                panel.changed = true;
+            }
         }
-
         ui.child_window("log").build(|| {
             if let Some(_t) = ui.begin_table("log", 4) {
                 ui.table_setup_column("Type");
@@ -615,141 +1409,779 @@ fn show_watches_panel(ui: &mut imgui::Ui, panel: &mut WatchesPanel) {
     });
 }
 
-// TODO: Each breakpoint should have option: Break, Log, Ignore
-//       Add mem read/write breakpoints, then watches are integrated into debugger
 struct Debugger {
-    breakpoints: BitSet,
+    breakpoints: BreakpointList,
     breakpoints_enabled: bool,
 
-    new_addr: u16,
+    // Set when the breakpoint list is edited, so the project can be saved
+    changed: bool,
+
+    new_addr: String,
+    new_condition: String,
+    new_ignore_count: u32,
+    input_err: Option<String>,
+}
+
+// Parses a ROM label or a hex address
+fn parse_rom_addr(text: &str, sym_tbl: &SymbolTable) -> Result<u16, String> {
+    let text = text.trim();
+    if let Some(addr) = sym_tbl.find_label(text) {
+        return Ok(addr);
+    }
+    let hex = text.strip_prefix('$').unwrap_or(text);
+    u16::from_str_radix(hex, 16).map_err(|_| format!("Unknown label '{}'", text))
 }
 
 impl Debugger {
     fn new() -> Self {
         Self {
-            breakpoints: BitSet::with_capacity(cpu::ROM_SIZE),
+            breakpoints: BreakpointList::new(),
             breakpoints_enabled: true,
-            new_addr: 0,
+            changed: false,
+            new_addr: String::new(),
+            new_condition: String::new(),
+            new_ignore_count: 0,
+            input_err: None,
         }
     }
 
-    fn set_breakpoint(&mut self, addr: u16, enabled: bool) {
-        if enabled {
-            self.breakpoints.insert(addr as usize);
-        } else {
-            self.breakpoints.remove(addr as usize);
-        }
+    fn add_breakpoint(&mut self, addr: Option<u16>, condition: Option<(String, Expr)>) {
+        self.breakpoints
+            .add(Breakpoint::new(addr, condition, self.new_ignore_count));
+        self.changed = true;
     }
 
-    fn should_break(&self, addr: u16) -> bool {
-        self.breakpoints_enabled && self.has_breakpoint(addr)
+    fn set_breakpoint(&mut self, addr: u16, enabled: bool) {
+        self.breakpoints.set_unconditional(addr, enabled);
+        self.changed = true;
     }
 
     fn has_breakpoint(&self, addr: u16) -> bool {
-        self.breakpoints.contains(addr as usize)
+        self.breakpoints.has_unconditional(addr)
+    }
+
+    fn should_break(&mut self, cpu: &cpu::Cpu) -> bool {
+        self.breakpoints_enabled && self.breakpoints.should_break(cpu)
+    }
+
+    // Checks breakpoints without counting hits, for running backwards
+    fn breaks_at(&self, cpu: &cpu::Cpu) -> bool {
+        self.breakpoints_enabled && self.breakpoints.matches(cpu)
+    }
+
+    fn configs(&self) -> Vec<BreakpointConfig> {
+        self.breakpoints
+            .list()
+            .iter()
+            .map(|bp| BreakpointConfig {
+                enabled: bp.enabled,
+                addr: bp.addr,
+                ignore_count: bp.ignore_count,
+                condition: bp.condition.as_ref().map(|(text, _)| text.clone()),
+            })
+            .collect()
+    }
+
+    fn load_configs(&mut self, configs: &[BreakpointConfig], sym_tbl: &SymbolTable) {
+        for config in configs {
+            let condition = match &config.condition {
+                Some(text) => match Expr::parse(text, sym_tbl) {
+                    Ok(expr) => Some((text.clone(), expr)),
+                    Err(e) => {
+                        eprintln!("Skipping breakpoint '{}': {}", text, e);
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            let mut bp = Breakpoint::new(config.addr, condition, config.ignore_count);
+            bp.enabled = config.enabled;
+            self.breakpoints.add(bp);
+        }
     }
 
-    fn show_ui(&mut self, ui: &imgui::Ui) {
+    fn show_ui(&mut self, ui: &imgui::Ui, sym_tbl: &SymbolTable) {
         ui.window("Debugger").build(|| {
             ui.text("Breakpoints:");
             ui.checkbox("Enabled", &mut self.breakpoints_enabled);
 
-            ui.input_scalar("##addr", &mut self.new_addr)
-                .display_format("%04x")
-                .chars_hexadecimal(true)
+            ui.input_scalar("Ignore count", &mut self.new_ignore_count)
+                .build();
+
+            let submitted = ui
+                .input_text("##addr", &mut self.new_addr)
+                .hint("Label or hex address")
+                .enter_returns_true(true)
                 .build();
             ui.same_line();
-            if ui.button("Add") {
-                self.set_breakpoint(self.new_addr, true);
+            if ui.button("Add") || submitted {
+                match parse_rom_addr(&self.new_addr, sym_tbl) {
+                    Ok(addr) => {
+                        self.add_breakpoint(Some(addr), None);
+                        self.new_addr.clear();
+                        self.input_err = None;
+                    }
+                    Err(e) => self.input_err = Some(e),
+                }
             }
 
-            if let Some(_t) =
-                ui.begin_table_with_flags("breakpoints", 2, imgui::TableFlags::BORDERS)
-            {
+            let submitted = ui
+                .input_text("##condition", &mut self.new_condition)
+                .hint("pc == drawPiece && [pieceY] > 18")
+                .enter_returns_true(true)
+                .build();
+            ui.same_line();
+            if ui.button("Add condition") || submitted {
+                match Expr::parse(&self.new_condition, sym_tbl) {
+                    Ok(expr) => {
+                        let text = std::mem::take(&mut self.new_condition);
+                        self.add_breakpoint(None, Some((text, expr)));
+                        self.input_err = None;
+                    }
+                    Err(e) => self.input_err = Some(e.to_string()),
+                }
+            }
+            if let Some(err) = &self.input_err {
+                ui.text_colored([1.0, 0.3, 0.3, 1.0], err);
+            }
+
+            let changed = self.breakpoints.edit(|breakpoints| {
+                let mut remove = None;
+                let mut changed = false;
+                let Some(_t) =
+                    ui.begin_table_with_flags("breakpoints", 6, imgui::TableFlags::BORDERS)
+                else {
+                    return false;
+                };
+                ui.table_setup_column("On");
                 ui.table_setup_column("Addr");
+                ui.table_setup_column("Condition");
+                ui.table_setup_column("Hits");
+                ui.table_setup_column("Ignore");
                 ui.table_setup_column("Remove");
                 ui.table_headers_row();
 
-                for addr_usize in self.breakpoints.iter() {
-                    let addr = addr_usize as u16;
-                    let _id = ui.push_id_usize(addr_usize);
+                for (i, bp) in breakpoints.iter_mut().enumerate() {
+                    let _id = ui.push_id_usize(i);
 
                     ui.table_next_column();
-                    ui.text(format!("{:04x}", addr));
+                    changed |= ui.checkbox("##enabled", &mut bp.enabled);
+                    ui.table_next_column();
+                    match bp.addr {
+                        Some(addr) => match sym_tbl.labels.get(&addr) {
+                            Some(label) => ui.text(format!("{:04x} {}", addr, label)),
+                            None => ui.text(format!("{:04x}", addr)),
+                        },
+                        None => ui.text("any"),
+                    }
+                    ui.table_next_column();
+                    if let Some((text, _)) = &bp.condition {
+                        ui.text(text);
+                    }
+                    ui.table_next_column();
+                    ui.text(format!("{}", bp.hits));
+                    ui.same_line();
+                    if ui.small_button("Reset") {
+                        bp.hits = 0;
+                    }
+                    ui.table_next_column();
+                    ui.set_next_item_width(60.0);
+                    changed |= ui.input_scalar("##ignore", &mut bp.ignore_count).build();
                     ui.table_next_column();
                     if ui.button("Remove") {
-                        // TODO
+                        remove = Some(i);
                     }
                 }
-            }
+                if let Some(i) = remove {
+                    breakpoints.remove(i);
+                }
+                changed || remove.is_some()
+            });
+            self.changed |= changed;
         });
     }
 }
 
-fn clock_cpu(cpu: &mut cpu::Cpu, watches: &mut WatchesPanel) {
-    let pc = cpu.queued_pc;
-    let info = cpu.clock();
+const TAKEN_COLOR: [f32; 4] = [0.4, 0.9, 0.4, 1.0];
+const FALL_THROUGH_COLOR: [f32; 4] = [0.7, 0.7, 0.7, 1.0];
+const RUNTIME_COLOR: [f32; 4] = [1.0, 0.6, 0.2, 1.0];
+const PC_COLOR: [f32; 4] = [1.0, 1.0, 0.3, 1.0];
+
+// One basic block with its neighbours, predecessors on the left and
+// successors on the right. Clicking a neighbour moves to it.
+struct ControlFlowPanel {
+    graph: Option<BlockGraph>,
+    focus: u16,
+    back: Vec<u16>,
+    follow_pc: bool,
+}
 
-    if let Some(access) = info.mem_access {
-        if let Some(watch) = watches.watches.get(&access.addr) {
-            let entry = WatchEntry { pc, access };
+impl ControlFlowPanel {
+    fn new() -> Self {
+        Self {
+            graph: None,
+            focus: 0,
+            back: vec![],
+            follow_pc: true,
+        }
+    }
+
+    // Rebuilds the graph next time it is shown
+    fn rom_changed(&mut self) {
+        self.graph = None;
+    }
+
+    fn show_ui(
+        &mut self,
+        ui: &imgui::Ui,
+        rom: &[RomWord],
+        sym_tbl: &SymbolTable,
+        pc: u16,
+        observed: &mut ObservedJumps,
+        target: &mut RomViewTarget,
+    ) {
+        ui.window("Control Flow").build(|| {
+            let graph = self
+                .graph
+                .get_or_insert_with(|| BlockGraph::build(&ControlFlow::new(rom, sym_tbl)));
+
+            ui.checkbox("Follow PC", &mut self.follow_pc);
+            if self.follow_pc {
+                if let Some(block) = graph.block_at(pc) {
+                    self.focus = block.start;
+                }
+            }
+            ui.same_line();
+            {
+                let _disabled = ui.begin_disabled(self.back.is_empty());
+                if ui.button("Back") {
+                    self.focus = self.back.pop().unwrap();
+                    self.follow_pc = false;
+                }
+            }
+            ui.same_line();
+            if ui.button("Show in ROM View") {
+                target.jump_to(self.focus);
+            }
+            ui.same_line();
+            if ui.button("Forget runtime jumps") {
+                observed.clear();
+            }
+
+            let Some(block) = graph.block_at(self.focus) else {
+                ui.text_disabled("No instruction at this address");
+                return;
+            };
+
+            // Neighbours with the colour of the edge joining them
+            let edge_color = |kind| match kind {
+                EdgeKind::Taken => TAKEN_COLOR,
+                EdgeKind::FallThrough => FALL_THROUGH_COLOR,
+                EdgeKind::Runtime => RUNTIME_COLOR,
+            };
+            let mut preds = vec![];
+            for &start in graph.predecessors(block.start) {
+                let pred = graph.block_at(start).unwrap();
+                for edge in pred.edges.iter().filter(|e| block.contains(e.target)) {
+                    preds.push((start, edge_color(edge.kind)));
+                }
+            }
+            for jump in observed.sources(block.start) {
+                preds.push((jump, RUNTIME_COLOR));
+            }
+            let mut succs = block
+                .edges
+                .iter()
+                .map(|e| (e.target, edge_color(e.kind)))
+                .collect::<Vec<_>>();
+            if let Some(jump) = block.jump {
+                succs.extend(observed.targets(jump).map(|t| (t, RUNTIME_COLOR)));
+            }
 
-            match entry.access.op {
-                MemOperation::Read { .. } if watch.read => {
-                    watches.append_log(entry);
+            const BOX_WIDTH: f32 = 200.0;
+            const CENTER_WIDTH: f32 = 300.0;
+            const GAP: f32 = 60.0;
+            let [x, y] = ui.cursor_screen_pos();
+            let center_x = x + BOX_WIDTH + GAP;
+            let right_x = center_x + CENTER_WIDTH + GAP;
+
+            // The block itself, clicking an instruction shows it in the ROM View
+            ui.set_cursor_screen_pos([center_x, y]);
+            ui.group(|| {
+                ui.text(sym_tbl.format_addr(block.start));
+                ui.separator();
+                for addr in block.addrs() {
+                    let word = rom[addr as usize];
+                    let inst = asm::Instruction::unpack(&[word.inst.0]).unwrap();
+                    let text = inst.disassemble(addr, word.data, sym_tbl.placeholders.get(&addr));
+                    let _color = (addr == pc)
+                        .then(|| ui.push_style_color(imgui::StyleColor::Text, PC_COLOR));
+                    if ui
+                        .selectable_config(format!("{}##{}", text, addr))
+                        .size([CENTER_WIDTH, 0.0])
+                        .build()
+                    {
+                        target.jump_to(addr);
+                    }
+                }
+                if block.computed {
+                    ui.text_colored(RUNTIME_COLOR, "Computed jump, targets seen when run");
                 }
-                MemOperation::Write { .. } if watch.write => {
-                    watches.append_log(entry);
+            });
+            let center_min = ui.item_rect_min();
+            let center_max = ui.item_rect_max();
+
+            // Buttons for the neighbours, returning where edges attach
+            let mut show_column = |col_x: f32, blocks: &[(u16, [f32; 4])], id: &str| {
+                let mut ends = vec![];
+                let mut col_y = y;
+                for (i, &(addr, color)) in blocks.iter().enumerate() {
+                    let Some(neighbour) = graph.block_at(addr) else {
+                        continue;
+                    };
+                    ui.set_cursor_screen_pos([col_x, col_y]);
+                    let label = format!(
+                        "{}\n{} instructions##{}{}",
+                        sym_tbl.format_addr(neighbour.start),
+                        neighbour.len,
+                        id,
+                        i
+                    );
+                    let _color = neighbour
+                        .contains(pc)
+                        .then(|| ui.push_style_color(imgui::StyleColor::Text, PC_COLOR));
+                    if ui.button_with_size(label, [BOX_WIDTH, 0.0]) {
+                        self.back.push(self.focus);
+                        self.focus = neighbour.start;
+                        self.follow_pc = false;
+                    }
+                    let (min, max) = (ui.item_rect_min(), ui.item_rect_max());
+                    ends.push((min, max, color));
+                    col_y = max[1] + 8.0;
+                }
+                (ends, col_y)
+            };
+            let (pred_ends, pred_bottom) = show_column(x, &preds, "pred");
+            let (succ_ends, succ_bottom) = show_column(right_x, &succs, "succ");
+
+            let draw_list = ui.get_window_draw_list();
+            let border = if block.contains(pc) {
+                PC_COLOR
+            } else {
+                FALL_THROUGH_COLOR
+            };
+            let (left, right) = (center_min[0] - 4.0, center_max[0] + 4.0);
+            draw_list
+                .add_rect(
+                    [left, center_min[1] - 4.0],
+                    [right, center_max[1] + 4.0],
+                    border,
+                )
+                .build();
+            let center_mid = (center_min[1] + center_max[1]) / 2.0;
+            for (min, max, color) in pred_ends {
+                let mid = (min[1] + max[1]) / 2.0;
+                draw_list
+                    .add_line([max[0], mid], [left, center_mid], color)
+                    .thickness(2.0)
+                    .build();
+            }
+            for (min, max, color) in succ_ends {
+                let mid = (min[1] + max[1]) / 2.0;
+                draw_list
+                    .add_line([right, center_mid], [min[0], mid], color)
+                    .thickness(2.0)
+                    .build();
+            }
+
+            // Reserve the space drawn over so the window scrolls
+            let bottom = pred_bottom.max(succ_bottom).max(center_max[1] + 4.0);
+            ui.set_cursor_screen_pos([x, y]);
+            ui.dummy([right_x + BOX_WIDTH - x, bottom - y]);
+        });
+    }
+}
+
+// Hot spots by label, sorted by the column picked in the table
+struct ProfilerPanel {
+    sort_column: usize,
+    ascending: bool,
+
+    csv_file: String,
+    status: Option<String>,
+}
+
+impl ProfilerPanel {
+    fn new(rom_file: &str) -> Self {
+        Self {
+            sort_column: 2,
+            ascending: false,
+            csv_file: format!("{}-profile.csv", rom_file),
+            status: None,
+        }
+    }
+
+    fn show_ui(
+        &mut self,
+        ui: &imgui::Ui,
+        profile: &mut Profile,
+        sym_tbl: &SymbolTable,
+        target: &mut RomViewTarget,
+    ) {
+        ui.window("Profiler").build(|| {
+            ui.text(format!(
+                "{} cycles over {} frames, {:.0} per frame",
+                profile.total(),
+                profile.frames(),
+                profile.per_frame(profile.total())
+            ));
+            if ui.button("Reset") {
+                profile.reset();
+                self.status = None;
+            }
+            ui.same_line();
+            if ui.button("Export CSV") {
+                self.status = Some(match profile.save_csv(&self.csv_file, sym_tbl) {
+                    Ok(()) => format!("Saved {}", self.csv_file),
+                    Err(e) => format!("Failed to write {}: {}", self.csv_file, e),
+                });
+            }
+            ui.same_line();
+            ui.input_text("##csv", &mut self.csv_file).build();
+            if let Some(status) = &self.status {
+                ui.text(status);
+            }
+
+            let flags = imgui::TableFlags::BORDERS_V
+                | imgui::TableFlags::SORTABLE
+                | imgui::TableFlags::SCROLL_Y
+                | imgui::TableFlags::RESIZABLE;
+            let Some(_t) = ui.begin_table_with_flags("profile", 4, flags) else {
+                return;
+            };
+            ui.table_setup_scroll_freeze(0, 1);
+            ui.table_setup_column("Label");
+            ui.table_setup_column("Addr");
+            let mut cycles_col = imgui::TableColumnSetup::new("Cycles");
+            cycles_col.flags = imgui::TableColumnFlags::DEFAULT_SORT
+                | imgui::TableColumnFlags::PREFER_SORT_DESCENDING;
+            ui.table_setup_column_with(cycles_col);
+            ui.table_setup_column("Per frame");
+            ui.table_headers_row();
+
+            if let Some(specs) = ui.table_sort_specs_mut() {
+                specs.conditional_sort(|specs| {
+                    if let Some(spec) = specs.iter().next() {
+                        self.sort_column = spec.column_idx();
+                        self.ascending =
+                            spec.sort_direction() == Some(imgui::TableSortDirection::Ascending);
+                    }
+                });
+            }
+
+            // Counts change every frame, so sort every time
+            let mut labels = profile.by_label(sym_tbl);
+            match self.sort_column {
+                0 => labels.sort_by(|a, b| a.name.cmp(&b.name)),
+                1 => labels.sort_by_key(|l| l.addr),
+                _ => labels.sort_by_key(|l| l.cycles),
+            }
+            if !self.ascending {
+                labels.reverse();
+            }
+
+            let total = profile.total().max(1);
+            for label in labels {
+                ui.table_next_column();
+                if ui.selectable(&label.name) {
+                    target.jump_to(label.addr);
                 }
-                _ => {}
+                ui.table_next_column();
+                ui.text(format!("{:04x}", label.addr));
+                ui.table_next_column();
+                ui.text(format!(
+                    "{} ({:.1}%)",
+                    label.cycles,
+                    label.cycles as f64 * 100.0 / total as f64
+                ));
+                ui.table_next_column();
+                ui.text(format!("{:.1}", profile.per_frame(label.cycles)));
             }
+        });
+    }
+}
+
+// Static cycle counts between two ROM addresses
+struct CycleTimingPanel {
+    from: String,
+    to: String,
+    result: Option<(u16, u16, PathTiming)>,
+    input_err: Option<String>,
+}
+
+impl CycleTimingPanel {
+    fn new() -> Self {
+        Self {
+            from: String::new(),
+            to: String::new(),
+            result: None,
+            input_err: None,
         }
     }
+
+    fn show_ui(
+        &mut self,
+        ui: &imgui::Ui,
+        rom: &[RomWord],
+        sym_tbl: &SymbolTable,
+        target: &mut RomViewTarget,
+    ) {
+        ui.window("Cycle Timing").build(|| {
+            ui.input_text("From", &mut self.from)
+                .hint("Label or hex address")
+                .build();
+            let submitted = ui
+                .input_text("To", &mut self.to)
+                .hint("Same as From to time a loop")
+                .enter_returns_true(true)
+                .build();
+            if ui.button("Analyse") || submitted {
+                let from = parse_rom_addr(&self.from, sym_tbl);
+                let to = match self.to.trim() {
+                    "" => from.clone(),
+                    text => parse_rom_addr(text, sym_tbl),
+                };
+                match from.and_then(|from| Ok((from, to?))) {
+                    Ok((from, to)) => {
+                        let timing = ControlFlow::new(rom, sym_tbl).path_timing(from, to);
+                        self.result = Some((from, to, timing));
+                        self.input_err = None;
+                    }
+                    Err(e) => self.input_err = Some(e),
+                }
+            }
+            if let Some(err) = &self.input_err {
+                ui.text_colored([1.0, 0.3, 0.3, 1.0], err);
+            }
+
+            let Some((from, to, timing)) = &self.result else {
+                return;
+            };
+            ui.separator();
+            let red = [1.0, 0.3, 0.3, 1.0];
+            match timing.cycles {
+                Some(cycles) if !timing.is_bounded() => ui.text_colored(
+                    red,
+                    format!(
+                        "{} to {}: at least {} cycles, loops can repeat",
+                        sym_tbl.format_addr(*from),
+                        sym_tbl.format_addr(*to),
+                        cycles.min
+                    ),
+                ),
+                Some(cycles) if cycles.min == cycles.max => ui.text(format!(
+                    "{} to {}: {} cycles",
+                    sym_tbl.format_addr(*from),
+                    sym_tbl.format_addr(*to),
+                    cycles
+                )),
+                Some(cycles) => ui.text_colored(
+                    red,
+                    format!(
+                        "{} to {}: {} cycles, paths are unbalanced",
+                        sym_tbl.format_addr(*from),
+                        sym_tbl.format_addr(*to),
+                        cycles
+                    ),
+                ),
+                None => ui.text_colored(red, "No path reaches the end"),
+            }
+
+            // Addresses jump to the ROM View when clicked
+            let mut show_addrs = |title: &str, addrs: &[u16]| {
+                if addrs.is_empty() {
+                    return;
+                }
+                ui.text(title);
+                for &addr in addrs {
+                    if ui.selectable(format!("  {}", sym_tbl.format_addr(addr))) {
+                        target.jump_to(addr);
+                    }
+                }
+            };
+            show_addrs("Computed jumps, not followed:", &timing.unresolved);
+            show_addrs("Loops that never reach the end:", &timing.loops);
+
+            if timing.unbalanced.is_empty() {
+                return;
+            }
+            ui.text("Unbalanced branches:");
+            if let Some(_t) =
+                ui.begin_table_with_flags("unbalanced", 3, imgui::TableFlags::BORDERS_V)
+            {
+                ui.table_setup_column("Branch");
+                ui.table_setup_column("Taken");
+                ui.table_setup_column("Not taken");
+                ui.table_headers_row();
+                for branch in &timing.unbalanced {
+                    ui.table_next_column();
+                    if ui.selectable(sym_tbl.format_addr(branch.addr)) {
+                        target.jump_to(branch.addr);
+                    }
+                    ui.table_next_column();
+                    ui.text(branch.taken.to_string());
+                    ui.table_next_column();
+                    ui.text(branch.not_taken.to_string());
+                }
+            }
+        });
+    }
+}
+
+fn clock_cpu(
+    cpu: &mut cpu::Cpu,
+    vga: &mut Vga,
+    audio: &mut AudioPanel,
+    watches: &mut WatchesPanel,
+    movie: &mut MoviePanel,
+    history: &mut History,
+    stats: &mut ExecStats,
+) -> TimingResult {
+    let pc = cpu.state.queued_pc;
+    stats.jumps.record(&cpu.rom, pc);
+    stats.profile.record(pc);
+    let beam = vga.beam();
+    let info = cpu.clock();
+    if let Some(out_pc) = info.out_pc {
+        vga.set_out_pc(out_pc);
+    }
+    let vga_timing = vga.update(&cpu.state.reg);
+    audio.update(&info, &cpu.state.reg);
+    if vga_timing.should_render {
+        movie.frame_start(cpu);
+        stats.profile.frame();
+    }
+
+    history.push(HistoryEntry {
+        reverse: info.reverse,
+        beam,
+        frame_start: vga_timing.should_render,
+        discarded: vga.take_discarded(),
+    });
+
+    if let Some(access) = info.mem_access {
+        watches.check_access(pc, access);
+    }
+
+    vga_timing
+}
+
+// Undoes the most recent cycle, returns whether that cycle started a frame
+fn step_back(
+    cpu: &mut cpu::Cpu,
+    vga: &mut Vga,
+    movie: &mut MoviePanel,
+    history: &mut History,
+    stats: &mut ExecStats,
+) -> Option<bool> {
+    let entry = history.pop()?;
+    cpu.apply_diff(entry.reverse);
+    // Otherwise running on would look like a jump from the undone cycle
+    stats.jumps.forget_last();
+    vga.restore_beam(entry.beam);
+    if let Some(discarded) = entry.discarded {
+        vga.undo_render(discarded);
+    }
+    if entry.frame_start {
+        movie.frame_undone(cpu);
+    }
+    Some(entry.frame_start)
+}
+
+// Scanlines of audio shown in the scope, a bit over two frames
+const AUDIO_SCOPE_SAMPLES: usize = 1024;
+
+/// Gigatron emulator and debugger
+#[derive(Parser)]
+struct Args {
+    /// ROM file to load
+    #[arg(default_value = "../main.rom")]
+    rom: String,
+
+    /// Symbol file produced alongside the ROM
+    #[arg(long, default_value = "../main.sym")]
+    symbols: String,
+
+    /// Seed for the power-on state and floating bus values
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Power-on contents of RAM and registers: random, zero or pattern
+    #[arg(long, default_value = "random")]
+    fill: PowerOnFill,
+
+    /// Save state to resume from instead of powering on
+    #[arg(long)]
+    load_state: Option<String>,
+
+    /// Sync timing the monitor expects: default, vesa-640x480, vga-640x400,
+    /// vga-640x350 or "front,pulse,back,visible/front,pulse,back,visible".
+    /// Overrides the mode saved in the project.
+    #[arg(long)]
+    video_mode: Option<VideoMode>,
 }
 
 fn main() {
     let ctx = ui_context::UiContext::new(1280, 720, "Gigatron Emulator");
 
-    let vert_timing = SyncTiming {
-        front_porch: 6,
-        pulse: 8,
-        back_porch: 27,
-        visible: 480,
-    };
-    let horiz_timing = SyncTiming {
-        front_porch: 16,
-        pulse: 96,
-        back_porch: 48,
-        visible: 640,
-    };
-
-    let total_frame_cycles = (horiz_timing.front_porch
-        + horiz_timing.pulse
-        + horiz_timing.back_porch
-        + horiz_timing.visible)
-        * (vert_timing.front_porch
-            + vert_timing.pulse
-            + vert_timing.back_porch
-            + vert_timing.visible)
-        / 4;
-
-    let args = std::env::args().collect_vec();
-    let rom_file = if args.len() < 2 {
-        "../main.rom"
-    } else {
-        &args[1]
-    };
-    println!("Loading {}", rom_file);
-    let sym_tbl = SymbolTable::load("../main.sym").expect("Failed to read symbols file"); // TODO: arg
-
-    let rom = load_rom(&rom_file).expect("Failed to read ROM file");
-    let mut cpu = cpu::Cpu::new(rom);
+    let args = Args::parse();
+    println!("Loading {}", args.rom);
+    let mut sym_tbl = SymbolTable::load(&args.symbols).expect("Failed to read symbols file");
 
-    let mut vga = Vga::new(&horiz_timing, &vert_timing);
-    let mut run_control = RunControl::new();
+    let rom = cpu::load_rom(&args.rom).expect("Failed to read ROM file");
+    let power_on = PowerOn::new(args.seed, args.fill);
+    println!("Power-on seed {} ({} fill)", power_on.seed, power_on.fill);
+    let mut cpu = cpu::Cpu::new(rom, power_on);
 
     let mut watches = WatchesPanel::new();
+    let mut movie = MoviePanel::new(&args.rom);
     let mut debugger = Debugger::new();
+    // Only a mode picked in the UI is saved, not one from the command line
+    let mut project_mode = None;
+    let project_file = project::project_file_name(&args.rom, cpu::rom_hash(&cpu.rom));
+    if std::path::Path::new(&project_file).exists() {
+        match Project::load(&project_file) {
+            Ok(project) => {
+                println!("Loaded project {}", project_file);
+                debugger.load_configs(&project.breakpoints, &sym_tbl);
+                watches.watches = project.watches;
+                project_mode = project.video_mode;
+            }
+            Err(e) => eprintln!("Failed to load project {}: {}", project_file, e),
+        }
+    }
+
+    let video_mode = args.video_mode.or(project_mode).unwrap_or_default();
+    println!("Video mode {}", video_mode);
+    let mut total_frame_cycles = video_mode.total_frame_cycles();
+    let mut vga = Vga::from_mode(&video_mode);
+    let mut monitor = Monitor::new(&args.rom);
+    let mut audio = AudioPanel::new();
+    let mut timing = TimingPanel::new();
+    if let Some(file_name) = &args.load_state {
+        SaveState::load(file_name)
+            .and_then(|s| s.restore(&mut cpu, &mut vga))
+            .expect("Failed to load save state");
+    }
+    let mut run_control = RunControl::new(&args.rom);
+
+    let mut history = History::new(history::DEFAULT_CAPACITY);
+    let mut rom_target = RomViewTarget::default();
+    let mut patcher = PatchPanel::new(&args.rom);
+    let mut cycle_timing = CycleTimingPanel::new();
+    let mut control_flow = ControlFlowPanel::new();
+    let mut profiler = ProfilerPanel::new(&args.rom);
+    let mut stats = ExecStats::default();
 
     let mut open = true;
     ctx.run_main_loop(move |ctx, ui| {
@@ -757,22 +2189,37 @@ fn main() {
 
         ui.show_demo_window(&mut open);
 
-        cpu.input = show_controller_input(ui);
-        match run_control.show_ui(ui, &mut cpu) {
+        movie.set_live_input(&mut cpu, show_controller_input(ui));
+        match run_control.show_ui(ui, &mut cpu, &mut vga, &mut history) {
             RunState::FullSpeed => {
                 let mut i = 0;
+                let mut skip_break = std::mem::take(&mut run_control.skip_break);
                 loop {
-                    if debugger.should_break(cpu.queued_pc) {
+                    if !std::mem::take(&mut skip_break) && debugger.should_break(&cpu) {
                         run_control.pause(PauseReason::Breakpoint);
                         break;
                     }
-                    clock_cpu(&mut cpu, &mut watches);
-
-                    let vga_timing = vga.update(ctx, &cpu.reg);
+                    let vga_timing = clock_cpu(
+                        &mut cpu,
+                        &mut vga,
+                        &mut audio,
+                        &mut watches,
+                        &mut movie,
+                        &mut history,
+                        &mut stats,
+                    );
+                    if std::mem::take(&mut watches.triggered) {
+                        run_control.pause(PauseReason::Watch);
+                        break;
+                    }
                     if run_control.break_on_horiz_cycle_errors && vga_timing.horiz_cycle_err {
                         run_control.pause(PauseReason::HorizCycleErr);
                         break;
                     }
+                    if run_control.break_on_vert_timing_errors && vga_timing.vert_timing_err {
+                        run_control.pause(PauseReason::VertTimingErr);
+                        break;
+                    }
                     if vga_timing.should_render {
                         break;
                     }
@@ -786,17 +2233,105 @@ fn main() {
                 }
             }
             RunState::Step => {
-                clock_cpu(&mut cpu, &mut watches);
-                vga.update(ctx, &cpu.reg);
+                clock_cpu(
+                    &mut cpu,
+                    &mut vga,
+                    &mut audio,
+                    &mut watches,
+                    &mut movie,
+                    &mut history,
+                    &mut stats,
+                );
+                watches.triggered = false;
+            }
+            RunState::StepBack => {
+                step_back(&mut cpu, &mut vga, &mut movie, &mut history, &mut stats);
+            }
+            RunState::FrameBack => {
+                while let Some(frame_start) =
+                    step_back(&mut cpu, &mut vga, &mut movie, &mut history, &mut stats)
+                {
+                    if frame_start {
+                        break;
+                    }
+                }
+            }
+            RunState::RunBack => {
+                while step_back(&mut cpu, &mut vga, &mut movie, &mut history, &mut stats).is_some()
+                {
+                    if debugger.breaks_at(&cpu) {
+                        run_control.pause(PauseReason::Breakpoint);
+                        break;
+                    }
+                }
             }
             RunState::Paused => {}
         }
-        vga.show_ui(ui);
-        show_registers(ui, &mut cpu.reg);
-        show_ram_view(ui, &mut cpu.ram);
-        show_rom_view(ui, &cpu.rom, &sym_tbl, cpu.reg.pc, &mut debugger);
-        show_zero_page_vars(ui, &mut cpu.ram, &sym_tbl, &mut watches);
+        let paused = run_control.paused.is_some();
+        monitor.update(ctx, &vga, paused);
+        match monitor.show_ui(ui, &vga, &sym_tbl, paused) {
+            Some(MonitorEvent::VideoStarted { wav_file }) => audio.start_wav(wav_file),
+            Some(MonitorEvent::VideoStopped) => audio.stop_wav(),
+            Some(MonitorEvent::GoTo(addr)) => rom_target.jump_to(addr),
+            None => {}
+        }
+        let mut mode_changed = false;
+        if let Some(mode) = timing.show_ui(ui, vga.timing()) {
+            // A video keeps the frame size it was started with
+            if let Some(MonitorEvent::VideoStopped) = monitor.stop_video() {
+                audio.stop_wav();
+            }
+            // The beam positions in the history belong to the old mode
+            vga = Vga::from_mode(&mode);
+            total_frame_cycles = mode.total_frame_cycles();
+            history.clear();
+            project_mode = Some(mode);
+            mode_changed = true;
+        }
+        show_registers(ui, &mut cpu.state.reg);
+        audio.flush();
+        audio.show_ui(ui, &cpu.state.reg);
+        show_ram_view(ui, &mut cpu.state.ram);
+        show_rom_view(
+            ui,
+            &mut cpu,
+            &mut sym_tbl,
+            &mut debugger,
+            &mut rom_target,
+            &mut patcher,
+            &stats.profile,
+        );
+        patcher.show_ui(ui, &mut cpu.rom, &mut sym_tbl, &mut rom_target);
+        cycle_timing.show_ui(ui, &cpu.rom, &sym_tbl, &mut rom_target);
+        if std::mem::take(&mut patcher.changed) {
+            control_flow.rom_changed();
+        }
+        control_flow.show_ui(
+            ui,
+            &cpu.rom,
+            &sym_tbl,
+            cpu.state.reg.pc,
+            &mut stats.jumps,
+            &mut rom_target,
+        );
+        profiler.show_ui(ui, &mut stats.profile, &sym_tbl, &mut rom_target);
+        show_zero_page_vars(ui, &mut cpu.state.ram, &sym_tbl, &mut watches);
         show_watches_panel(ui, &mut watches);
-        debugger.show_ui(ui);
+        debugger.show_ui(ui, &sym_tbl);
+        movie.show_ui(ui, &mut cpu, &mut vga, &mut history);
+
+        if std::mem::take(&mut debugger.changed)
+            | std::mem::take(&mut watches.changed)
+            | mode_changed
+        {
+            let project = Project {
+                breakpoints: debugger.configs(),
+                watches: watches.watches.clone(),
+                video_mode: project_mode,
+            };
+            if let Err(e) = project.save(&project_file) {
+                eprintln!("Failed to save project {}: {}", project_file, e);
+            }
+        }
     });
 }
@@ -0,0 +1,329 @@
+use std::{borrow::Cow, rc::Rc};
+
+use gigatron::{screenshot, symbols::SymbolTable, vga::Vga, video::VideoWriter};
+use glium::texture::RawImage2d;
+
+use crate::ui_context::RenderContext;
+
+// Displays the frames produced by a Vga in an imgui window
+pub struct Monitor {
+    pub tex_id: Option<imgui::TextureId>,
+    // Frame number and, for a partial frame, how far the beam had drawn
+    uploaded: Option<(u64, Option<usize>)>,
+    recorded_frame: Option<u64>,
+
+    // While paused, show the frame being drawn and where the beam is
+    beam_overlay: bool,
+
+    // Pixel clicked on to find the instruction that output it
+    selected_pixel: Option<(u32, u32)>,
+
+    // Screenshots are saved as "<prefix>-<frame>.png"
+    screenshot_prefix: String,
+    screenshot_status: Option<String>,
+
+    video_file: String,
+    video: Option<VideoWriter>,
+    video_audio: bool,
+    video_status: Option<String>,
+}
+
+pub enum MonitorEvent {
+    // Tells the audio panel to record alongside the video
+    VideoStarted { wav_file: String },
+    VideoStopped,
+    // Show the instruction at this address in the ROM View
+    GoTo(u16),
+}
+
+impl Monitor {
+    pub fn new(screenshot_prefix: &str) -> Self {
+        Self {
+            tex_id: None,
+            uploaded: None,
+            recorded_frame: None,
+            beam_overlay: true,
+            selected_pixel: None,
+            screenshot_prefix: screenshot_prefix.to_string(),
+            screenshot_status: None,
+            video_file: format!("{}-video.png", screenshot_prefix),
+            video: None,
+            video_audio: true,
+            video_status: None,
+        }
+    }
+
+    // Records each completed frame once, so a video gets every frame the
+    // emulator produces while recording
+    fn record(&mut self, vga: &Vga) {
+        if self.recorded_frame == Some(vga.frame_count()) {
+            return;
+        }
+        self.recorded_frame = Some(vga.frame_count());
+
+        if let Some(video) = &mut self.video {
+            if let Err(e) = video.write_frame(vga.last_frame()) {
+                self.video_status = Some(format!("Failed to write {}: {}", self.video_file, e));
+                self.video = None;
+            }
+        }
+    }
+
+    fn show_partial_frame(&self, paused: bool) -> bool {
+        paused && self.beam_overlay
+    }
+
+    // Uploads the frame to display if it has changed since the last upload
+    pub fn update(&mut self, ctx: &mut RenderContext, vga: &Vga, paused: bool) {
+        self.record(vga);
+
+        let partial = self.show_partial_frame(paused);
+        let key = (vga.frame_count(), partial.then(|| vga.beam().pixel));
+        if self.uploaded == Some(key) {
+            return;
+        }
+        self.uploaded = Some(key);
+
+        let (width, height) = vga.size();
+        let tex_data: RawImage2d<u8> = RawImage2d {
+            data: if partial {
+                Cow::Owned(vga.partial_frame())
+            } else {
+                Cow::Borrowed(vga.last_frame())
+            },
+            width,
+            height,
+            format: glium::texture::ClientFormat::U8U8U8U8,
+        };
+
+        let tex_opt = glium::Texture2d::with_format(
+            &ctx.gl_ctx,
+            tex_data,
+            glium::texture::UncompressedFloatFormat::F32F32F32F32,
+            glium::texture::MipmapsOption::NoMipmap,
+        )
+        .ok();
+
+        if let Some(tex_2d) = tex_opt {
+            let tex = imgui_glium_renderer::Texture {
+                texture: Rc::new(tex_2d),
+                sampler: glium::uniforms::SamplerBehavior {
+                    magnify_filter: glium::uniforms::MagnifySamplerFilter::Nearest,
+                    minify_filter: glium::uniforms::MinifySamplerFilter::Nearest,
+                    ..Default::default()
+                },
+            };
+
+            let textures = ctx.imgui_renderer.textures();
+            match self.tex_id {
+                Some(id) => {
+                    textures.replace(id, tex);
+                }
+                None => {
+                    self.tex_id = Some(textures.insert(tex));
+                }
+            }
+        }
+    }
+
+    // Finishes the video being recorded, if there is one. Has to be called
+    // before the frame size changes.
+    pub fn stop_video(&mut self) -> Option<MonitorEvent> {
+        let video = self.video.take()?;
+        let frames = video.frame_count();
+        self.video_status = Some(match video.finish() {
+            Ok(()) => format!("Saved {} ({} frames)", self.video_file, frames),
+            Err(e) => format!("Failed to write {}: {}", self.video_file, e),
+        });
+        self.video_audio.then_some(MonitorEvent::VideoStopped)
+    }
+
+    fn show_video_ui(&mut self, ui: &imgui::Ui, vga: &Vga) -> Option<MonitorEvent> {
+        let mut event = None;
+        match &self.video {
+            Some(video) => {
+                let frames = video.frame_count();
+                if ui.button("Stop video") {
+                    event = self.stop_video();
+                } else {
+                    ui.same_line();
+                    ui.text(format!("Recording, {} frames", frames));
+                }
+            }
+            None => {
+                ui.input_text("##video", &mut self.video_file).build();
+                ui.same_line();
+                if ui.button("Record video") {
+                    match VideoWriter::create(
+                        &self.video_file,
+                        vga.size(),
+                        vga.mode().total_frame_cycles() as u32,
+                    ) {
+                        Ok(video) => {
+                            self.video = Some(video);
+                            self.video_status = None;
+                            if self.video_audio {
+                                let wav_file = match self.video_file.strip_suffix(".png") {
+                                    Some(base) => format!("{}.wav", base),
+                                    None => format!("{}.wav", self.video_file),
+                                };
+                                event = Some(MonitorEvent::VideoStarted { wav_file });
+                            }
+                        }
+                        Err(e) => {
+                            self.video_status =
+                                Some(format!("Failed to create {}: {}", self.video_file, e));
+                        }
+                    }
+                }
+                ui.same_line();
+                ui.checkbox("Include audio", &mut self.video_audio);
+            }
+        }
+        if let Some(status) = &self.video_status {
+            ui.text(status);
+        }
+        event
+    }
+
+    // Draws the whole line and frame period with the blanking regions around
+    // the image, HSYNC and VSYNC darker, and lines crossing at the beam.
+    // Returns the screen position of the image.
+    fn show_beam_overlay(&self, ui: &imgui::Ui, tex: imgui::TextureId, vga: &Vga) -> [f32; 2] {
+        let mode = vga.mode();
+        let (horiz, vert) = (&mode.horiz, &mode.vert);
+        let size = [horiz.total() as f32, vert.total() as f32];
+        let [x, y] = ui.cursor_screen_pos();
+        let draw_list = ui.get_window_draw_list();
+
+        let blanking = [0.15, 0.15, 0.2, 1.0];
+        let sync = [0.05, 0.05, 0.1, 1.0];
+        draw_list
+            .add_rect([x, y], [x + size[0], y + size[1]], blanking)
+            .filled(true)
+            .build();
+        draw_list
+            .add_rect([x, y], [x + horiz.pulse as f32, y + size[1]], sync)
+            .filled(true)
+            .build();
+        draw_list
+            .add_rect([x, y], [x + size[0], y + vert.pulse as f32], sync)
+            .filled(true)
+            .build();
+
+        // Rows and columns count from the start of the sync pulses
+        let left = x + (horiz.pulse + horiz.back_porch) as f32;
+        let top = y + (vert.pulse + vert.back_porch) as f32;
+        draw_list
+            .add_image(
+                tex,
+                [left, top],
+                [left + horiz.visible as f32, top + vert.visible as f32],
+            )
+            .build();
+
+        let beam = vga.beam();
+        let beam_x = x + (beam.col as f32).clamp(0.0, size[0]);
+        let beam_y = y + (beam.row as f32).clamp(0.0, size[1]);
+        let color = [1.0, 1.0, 0.0, 0.5];
+        draw_list
+            .add_line([beam_x, y], [beam_x, y + size[1]], color)
+            .build();
+        draw_list
+            .add_line([x, beam_y], [x + size[0], beam_y], color)
+            .build();
+        draw_list
+            .add_rect(
+                [beam_x - 3.0, beam_y - 3.0],
+                [beam_x + 3.0, beam_y + 3.0],
+                [1.0, 1.0, 0.0, 1.0],
+            )
+            .build();
+
+        ui.dummy(size);
+        [left, top]
+    }
+
+    fn show_pixel_info(
+        &mut self,
+        ui: &imgui::Ui,
+        vga: &Vga,
+        symbols: &SymbolTable,
+        partial: bool,
+    ) -> Option<MonitorEvent> {
+        let (x, y) = self.selected_pixel?;
+        let mut event = None;
+        match vga.pixel_pc(x, y, partial) {
+            Some(pc) => {
+                ui.text(format!(
+                    "Pixel ({}, {}) output by {}",
+                    x,
+                    y,
+                    symbols.format_addr(pc)
+                ));
+                ui.same_line();
+                if ui.button("Show in ROM View") {
+                    event = Some(MonitorEvent::GoTo(pc));
+                }
+            }
+            None => ui.text(format!("Pixel ({}, {}) has no recorded output", x, y)),
+        }
+        event
+    }
+
+    pub fn show_ui(
+        &mut self,
+        ui: &imgui::Ui,
+        vga: &Vga,
+        symbols: &SymbolTable,
+        paused: bool,
+    ) -> Option<MonitorEvent> {
+        let (width, height) = vga.size();
+        let mut event = None;
+        ui.window("VGA Monitor").build(|| {
+            if ui.button("Save screenshot") {
+                let file_name = format!("{}-{}.png", self.screenshot_prefix, vga.frame_count());
+                self.screenshot_status = Some(match screenshot::save_png(&file_name, vga) {
+                    Ok(()) => format!("Saved {}", file_name),
+                    Err(e) => format!("Failed to save {}: {}", file_name, e),
+                });
+            }
+            if let Some(status) = &self.screenshot_status {
+                ui.same_line();
+                ui.text(status);
+            }
+            event = self.show_video_ui(ui, vga);
+            ui.checkbox("Show beam when paused", &mut self.beam_overlay);
+
+            let partial = self.show_partial_frame(paused);
+            let origin = match self.tex_id {
+                Some(tex) if partial => {
+                    let beam = vga.beam();
+                    ui.text(format!("Beam: row {}, col {}", beam.row, beam.col));
+                    self.show_beam_overlay(ui, tex, vga)
+                }
+                Some(tex) => {
+                    let origin = ui.cursor_screen_pos();
+                    imgui::Image::new(tex, [width as f32, height as f32]).build(ui);
+                    origin
+                }
+                None => {
+                    ui.text("Image go here");
+                    return;
+                }
+            };
+
+            if ui.is_item_clicked() {
+                let [mx, my] = ui.io().mouse_pos;
+                let (px, py) = (mx - origin[0], my - origin[1]);
+                if px >= 0.0 && py >= 0.0 && px < width as f32 && py < height as f32 {
+                    self.selected_pixel = Some((px as u32, py as u32));
+                }
+            }
+            if let Some(goto) = self.show_pixel_info(ui, vga, symbols, partial) {
+                event = Some(goto);
+            }
+        });
+        event
+    }
+}
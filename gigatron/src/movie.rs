@@ -0,0 +1,178 @@
+use std::io::{Error, Read, Write};
+
+use crate::{
+    cpu::{rom_hash, Cpu, PowerOn, PowerOnFill},
+    savestate::{invalid, read_bytes, read_u16, read_u64, read_u8},
+};
+
+const MAGIC: &[u8; 4] = b"GTMV";
+const VERSION: u16 = 1;
+
+// Controller input for every frame since power on. Frames start at VSYNC
+// falling edges, and the input is held for the whole frame, so replaying
+// from the same seed reproduces the session exactly.
+pub struct Movie {
+    pub rom_hash: u64,
+    pub power_on: PowerOn,
+    pub inputs: Vec<u8>,
+}
+
+impl Movie {
+    pub fn new(cpu: &Cpu) -> Self {
+        Self {
+            rom_hash: rom_hash(&cpu.rom),
+            power_on: cpu.power_on,
+            inputs: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.inputs.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    // None once the movie has ended
+    pub fn input_at(&self, frame: u64) -> Option<u8> {
+        self.inputs.get(frame as usize).copied()
+    }
+
+    // Hard resets the CPU into the movie's power-on state, ready to play
+    // from frame 0. Fails without touching anything if the movie was
+    // recorded with another ROM.
+    pub fn start(&self, cpu: &mut Cpu) -> Result<(), Error> {
+        if self.rom_hash != rom_hash(&cpu.rom) {
+            return Err(invalid("Movie was recorded with a different ROM"));
+        }
+        cpu.power_on = self.power_on;
+        cpu.hard_reset();
+        Ok(())
+    }
+
+    pub fn write(&self, w: &mut impl Write) -> Result<(), Error> {
+        let fill_idx = PowerOnFill::ALL
+            .iter()
+            .position(|&fill| fill == self.power_on.fill)
+            .unwrap() as u8;
+
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        w.write_all(&self.rom_hash.to_le_bytes())?;
+        w.write_all(&self.power_on.seed.to_le_bytes())?;
+        w.write_all(&[fill_idx])?;
+        w.write_all(&self.len().to_le_bytes())?;
+        w.write_all(&self.inputs)
+    }
+
+    pub fn read(r: &mut impl Read) -> Result<Self, Error> {
+        if &read_bytes::<4>(r)? != MAGIC {
+            return Err(invalid("Not a movie file"));
+        }
+        if read_u16(r)? != VERSION {
+            return Err(invalid("Unsupported movie version"));
+        }
+
+        let rom_hash = read_u64(r)?;
+        let seed = read_u64(r)?;
+        let fill = *PowerOnFill::ALL
+            .get(read_u8(r)? as usize)
+            .ok_or_else(|| invalid("Unknown power-on fill"))?;
+        let len = read_u64(r)?;
+        let mut inputs = vec![];
+        r.take(len).read_to_end(&mut inputs)?;
+        if inputs.len() as u64 != len {
+            return Err(invalid("Movie is truncated"));
+        }
+
+        Ok(Self {
+            rom_hash,
+            power_on: PowerOn { seed, fill },
+            inputs,
+        })
+    }
+
+    pub fn save(&self, file_name: &str) -> Result<(), Error> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(file_name)?);
+        self.write(&mut file)?;
+        file.flush()
+    }
+
+    pub fn load(file_name: &str) -> Result<Self, Error> {
+        let mut file = std::io::BufReader::new(std::fs::File::open(file_name)?);
+        Self::read(&mut file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cpu::{OpCode, RomWord},
+        vga::{self, Vga},
+    };
+
+    fn test_rom() -> Vec<RomWord> {
+        // ld in; st [$30]; ld $c0,out; ld $40,out; bra $00; nop
+        [
+            (0x03, 0),
+            (0xc2, 0x30),
+            (0x18, 0xc0),
+            (0x18, 0x40),
+            (0xfc, 0x00),
+            (0x02, 0),
+        ]
+        .into_iter()
+        .map(|(inst, data)| RomWord {
+            inst: OpCode(inst),
+            data,
+        })
+        .chain(std::iter::repeat(RomWord {
+            inst: OpCode(0),
+            data: 0,
+        }))
+        .take(256)
+        .collect()
+    }
+
+    // Runs frames, feeding input at each VSYNC, and returns RAM after each
+    fn run(cpu: &mut Cpu, frames: usize, mut input: impl FnMut(usize) -> u8) -> Vec<u8> {
+        let mut vga = Vga::new(&vga::HORIZ_TIMING, &vga::VERT_TIMING);
+        let mut trace = vec![];
+        let mut frame = 0;
+        cpu.input = input(frame);
+        while frame < frames {
+            cpu.clock();
+            if vga.update(&cpu.state.reg).should_render {
+                trace.push(cpu.state.ram[0x30]);
+                frame += 1;
+                cpu.input = input(frame);
+            }
+        }
+        trace
+    }
+
+    #[test]
+    fn replays_recorded_session() {
+        let mut cpu = Cpu::new(test_rom(), PowerOn::new(Some(7), PowerOnFill::Random));
+        let mut movie = Movie::new(&cpu);
+        let recorded = run(&mut cpu, 50, |frame| {
+            let input = (frame * 37) as u8;
+            movie.inputs.push(input);
+            input
+        });
+
+        let mut bytes = vec![];
+        movie.write(&mut bytes).unwrap();
+        let movie = Movie::read(&mut bytes.as_slice()).unwrap();
+
+        let mut other = Cpu::new(test_rom(), PowerOn::new(Some(1), PowerOnFill::Zero));
+        movie.start(&mut other).unwrap();
+        let replayed = run(&mut other, 50, |frame| {
+            movie.input_at(frame as u64).unwrap()
+        });
+        assert_eq!(recorded, replayed);
+        assert!(cpu.state.ram == other.state.ram);
+    }
+}
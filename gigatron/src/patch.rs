@@ -0,0 +1,97 @@
+use crate::{
+    cpu::RomWord,
+    symbols::{Placeholder, SymbolTable},
+};
+
+pub struct RomPatch {
+    pub addr: u16,
+    pub old: RomWord,
+    pub new: RomWord,
+    old_placeholder: Option<Placeholder>,
+}
+
+// Instructions changed in a loaded ROM, undone newest first
+#[derive(Default)]
+pub struct RomPatches {
+    patches: Vec<RomPatch>,
+}
+
+impl RomPatches {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn patches(&self) -> &[RomPatch] {
+        &self.patches
+    }
+
+    pub fn is_patched(&self, addr: u16) -> bool {
+        self.patches.iter().any(|p| p.addr == addr)
+    }
+
+    // Writes the word into the ROM and swaps in the placeholder of its
+    // operand, so the ROM View shows the new symbol
+    pub fn apply(
+        &mut self,
+        rom: &mut [RomWord],
+        symbols: &mut SymbolTable,
+        addr: u16,
+        word: RomWord,
+        placeholder: Option<Placeholder>,
+    ) {
+        let old_placeholder = match placeholder {
+            Some(p) => symbols.placeholders.insert(addr, p),
+            None => symbols.placeholders.remove(&addr),
+        };
+        self.patches.push(RomPatch {
+            addr,
+            old: rom[addr as usize],
+            new: word,
+            old_placeholder,
+        });
+        rom[addr as usize] = word;
+    }
+
+    // Restores the word replaced by the newest patch, returning its address
+    pub fn undo(&mut self, rom: &mut [RomWord], symbols: &mut SymbolTable) -> Option<u16> {
+        let patch = self.patches.pop()?;
+        rom[patch.addr as usize] = patch.old;
+        match patch.old_placeholder {
+            Some(p) => symbols.placeholders.insert(patch.addr, p),
+            None => symbols.placeholders.remove(&patch.addr),
+        };
+        Some(patch.addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::OpCode;
+
+    #[test]
+    fn undo_restores_in_reverse_order() {
+        let word = |inst, data| RomWord {
+            inst: OpCode(inst),
+            data,
+        };
+        let mut rom = vec![word(0, 0); 4];
+        let mut symbols = SymbolTable::default();
+        symbols.placeholders.insert(2, "lo start".parse().unwrap());
+
+        let mut patches = RomPatches::new();
+        patches.apply(&mut rom, &mut symbols, 2, word(0x80, 1), None);
+        patches.apply(&mut rom, &mut symbols, 2, word(0x81, 2), None);
+        assert!(patches.is_patched(2));
+        assert_eq!((rom[2].inst, rom[2].data), (OpCode(0x81), 2));
+        assert!(!symbols.placeholders.contains_key(&2));
+
+        assert_eq!(patches.undo(&mut rom, &mut symbols), Some(2));
+        assert_eq!((rom[2].inst, rom[2].data), (OpCode(0x80), 1));
+        assert_eq!(patches.undo(&mut rom, &mut symbols), Some(2));
+        assert_eq!((rom[2].inst, rom[2].data), (OpCode(0), 0));
+        assert_eq!(symbols.placeholders[&2].to_string(), "lo(start)");
+        assert_eq!(patches.undo(&mut rom, &mut symbols), None);
+        assert!(!patches.is_patched(2));
+    }
+}
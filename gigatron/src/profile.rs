@@ -0,0 +1,154 @@
+use std::io::{BufWriter, Write};
+
+use crate::{cpu::ROM_SIZE, symbols::SymbolTable};
+
+pub struct LabelCycles {
+    pub addr: u16,
+    pub name: String,
+    pub cycles: u64,
+}
+
+// Cycles spent executing each ROM address
+pub struct Profile {
+    counts: Vec<u64>,
+    total: u64,
+    frames: u64,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; ROM_SIZE],
+            total: 0,
+            frames: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.counts.fill(0);
+        self.total = 0;
+        self.frames = 0;
+    }
+
+    // Called with the address of every executed instruction
+    pub fn record(&mut self, pc: u16) {
+        self.counts[pc as usize] += 1;
+        self.total += 1;
+    }
+
+    // Called when a frame is drawn, for per-frame averages
+    pub fn frame(&mut self) {
+        self.frames += 1;
+    }
+
+    pub fn count(&self, addr: u16) -> u64 {
+        self.counts[addr as usize]
+    }
+
+    pub fn max_count(&self) -> u64 {
+        self.counts.iter().copied().max().unwrap_or(0)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    // Cycles per frame, or in total before the first frame
+    pub fn per_frame(&self, cycles: u64) -> f64 {
+        cycles as f64 / self.frames.max(1) as f64
+    }
+
+    // Cycles for each label up to the next one, in address order. Code
+    // before the first label is counted as "start" like in the ROM View.
+    pub fn by_label(&self, symbols: &SymbolTable) -> Vec<LabelCycles> {
+        let mut starts = vec![(0, "start".to_string())];
+        for (&addr, name) in &symbols.labels {
+            if addr == 0 {
+                starts.clear();
+            }
+            starts.push((addr as usize, name.clone()));
+        }
+
+        let mut labels = vec![];
+        for (i, (start, name)) in starts.iter().enumerate() {
+            let end = starts.get(i + 1).map_or(ROM_SIZE, |(addr, _)| *addr);
+            labels.push(LabelCycles {
+                addr: *start as u16,
+                name: name.clone(),
+                cycles: self.counts[*start..end].iter().sum(),
+            });
+        }
+        labels
+    }
+
+    pub fn write_csv(&self, w: &mut impl Write, symbols: &SymbolTable) -> std::io::Result<()> {
+        writeln!(w, "label,address,cycles,cycles_per_frame,percent")?;
+        for label in self.by_label(symbols) {
+            writeln!(
+                w,
+                "{},{:04x},{},{:.1},{:.2}",
+                label.name,
+                label.addr,
+                label.cycles,
+                self.per_frame(label.cycles),
+                label.cycles as f64 * 100.0 / self.total.max(1) as f64
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn save_csv(&self, file_name: &str, symbols: &SymbolTable) -> std::io::Result<()> {
+        let mut file = BufWriter::new(std::fs::File::create(file_name)?);
+        self.write_csv(&mut file, symbols)?;
+        file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aggregates_by_label() {
+        let mut symbols = SymbolTable::default();
+        symbols.labels.insert(0x10, "loop".to_string());
+        symbols.labels.insert(0x20, "done".to_string());
+
+        let mut profile = Profile::new();
+        for pc in [0x00, 0x01, 0x10, 0x11, 0x12, 0x10, 0x20] {
+            profile.record(pc);
+        }
+        profile.frame();
+        profile.frame();
+        assert_eq!(profile.max_count(), 2);
+
+        let labels = profile.by_label(&symbols);
+        let cycles = labels
+            .iter()
+            .map(|l| (l.name.as_str(), l.addr, l.cycles))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            cycles,
+            vec![("start", 0, 2), ("loop", 0x10, 4), ("done", 0x20, 1)]
+        );
+
+        let mut csv = vec![];
+        profile.write_csv(&mut csv, &symbols).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().nth(2), Some("loop,0010,4,2.0,57.14"));
+
+        profile.reset();
+        assert_eq!((profile.total(), profile.frames()), (0, 0));
+        assert!(profile.by_label(&symbols).iter().all(|l| l.cycles == 0));
+    }
+}
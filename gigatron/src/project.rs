@@ -0,0 +1,215 @@
+use std::io::{BufRead, Error, ErrorKind, Write};
+
+use crate::{debugger::Watch, vga::VideoMode};
+
+const HEADER: &str = "gigatron-project 1";
+
+// Debugger setup saved between runs. The file is plain text with one item per
+// line so it can be edited by hand:
+//
+//   break <on|off> <addr|-> <ignore count> [condition]
+//   watch <start> <end> <r|w|rw|-> <break|log|ignore> <value|->
+//   mode <video mode>
+//
+// Addresses and values are hex, the condition is the rest of the line.
+#[derive(Debug, Default, PartialEq)]
+pub struct Project {
+    pub breakpoints: Vec<BreakpointConfig>,
+    pub watches: Vec<Watch>,
+    // None to use the default, or whatever is given on the command line
+    pub video_mode: Option<VideoMode>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct BreakpointConfig {
+    pub enabled: bool,
+    pub addr: Option<u16>,
+    pub ignore_count: u32,
+    pub condition: Option<String>,
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn parse_hex<T>(s: &str, line: &str) -> Result<Option<T>, Error>
+where
+    T: TryFrom<u32>,
+{
+    if s == "-" {
+        return Ok(None);
+    }
+    u32::from_str_radix(s, 16)
+        .ok()
+        .and_then(|v| T::try_from(v).ok())
+        .map(Some)
+        .ok_or_else(|| invalid(&format!("Invalid hex value in '{}'", line)))
+}
+
+fn parse_line(project: &mut Project, line: &str) -> Result<(), Error> {
+    let bad_line = || invalid(&format!("Invalid project line '{}'", line));
+    // Breakpoint conditions contain spaces, so they get whatever is left
+    // after the fixed fields
+    let (kind, rest) = line.split_once(' ').ok_or_else(bad_line)?;
+    let mut fields = rest.splitn(if kind == "break" { 4 } else { 5 }, ' ');
+    let mut next = || fields.next().ok_or_else(bad_line);
+
+    match kind {
+        "break" => {
+            let enabled = match next()? {
+                "on" => true,
+                "off" => false,
+                _ => return Err(bad_line()),
+            };
+            let addr = parse_hex(next()?, line)?;
+            let ignore_count = next()?.parse().map_err(|_| bad_line())?;
+            let condition = fields.next().map(str::to_string);
+            project.breakpoints.push(BreakpointConfig {
+                enabled,
+                addr,
+                ignore_count,
+                condition,
+            });
+        }
+        "watch" => {
+            let start = parse_hex(next()?, line)?.ok_or_else(bad_line)?;
+            let end = parse_hex(next()?, line)?.ok_or_else(bad_line)?;
+            let ops = next()?;
+            let action = next()?.parse().map_err(|e: String| invalid(&e))?;
+            let value = parse_hex(next()?, line)?;
+            project.watches.push(Watch {
+                start,
+                end,
+                read: ops.contains('r'),
+                write: ops.contains('w'),
+                action,
+                value,
+            });
+        }
+        "mode" => {
+            project.video_mode = Some(rest.parse().map_err(|e: String| invalid(&e))?);
+        }
+        _ => return Err(bad_line()),
+    }
+    Ok(())
+}
+
+impl Project {
+    pub fn write(&self, w: &mut impl Write) -> Result<(), Error> {
+        writeln!(w, "{}", HEADER)?;
+        for bp in &self.breakpoints {
+            write!(w, "break {} ", if bp.enabled { "on" } else { "off" })?;
+            match bp.addr {
+                Some(addr) => write!(w, "{:04x}", addr)?,
+                None => write!(w, "-")?,
+            }
+            write!(w, " {}", bp.ignore_count)?;
+            if let Some(condition) = &bp.condition {
+                write!(w, " {}", condition)?;
+            }
+            writeln!(w)?;
+        }
+        for watch in &self.watches {
+            let ops = match (watch.read, watch.write) {
+                (true, true) => "rw",
+                (true, false) => "r",
+                (false, true) => "w",
+                (false, false) => "-",
+            };
+            write!(
+                w,
+                "watch {:04x} {:04x} {} {} ",
+                watch.start, watch.end, ops, watch.action
+            )?;
+            match watch.value {
+                Some(val) => writeln!(w, "{:02x}", val)?,
+                None => writeln!(w, "-")?,
+            }
+        }
+        if let Some(mode) = &self.video_mode {
+            writeln!(w, "mode {}", mode)?;
+        }
+        Ok(())
+    }
+
+    pub fn read(r: &mut impl BufRead) -> Result<Self, Error> {
+        let mut lines = r.lines();
+        if lines.next().transpose()?.as_deref() != Some(HEADER) {
+            return Err(invalid("Not a project file"));
+        }
+
+        let mut project = Self::default();
+        for line in lines {
+            let line = line?;
+            let line = line.trim();
+            if !line.is_empty() {
+                parse_line(&mut project, line)?;
+            }
+        }
+        Ok(project)
+    }
+
+    pub fn save(&self, file_name: &str) -> Result<(), Error> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(file_name)?);
+        self.write(&mut file)?;
+        file.flush()
+    }
+
+    pub fn load(file_name: &str) -> Result<Self, Error> {
+        let mut file = std::io::BufReader::new(std::fs::File::open(file_name)?);
+        Self::read(&mut file)
+    }
+}
+
+// Projects are kept next to the ROM and keyed by its hash, so a rebuilt ROM
+// with different addresses does not pick up stale breakpoints
+pub fn project_file_name(rom_file: &str, rom_hash: u64) -> String {
+    format!("{}.{:016x}.project", rom_file, rom_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugger::WatchAction;
+
+    #[test]
+    fn round_trip() {
+        let project = Project {
+            breakpoints: vec![
+                BreakpointConfig {
+                    enabled: true,
+                    addr: Some(0x0123),
+                    ignore_count: 0,
+                    condition: None,
+                },
+                BreakpointConfig {
+                    enabled: false,
+                    addr: None,
+                    ignore_count: 3,
+                    condition: Some("pc == drawPiece && [pieceY] > 18".to_string()),
+                },
+            ],
+            watches: vec![Watch {
+                start: 0x0100,
+                end: 0x01ff,
+                read: false,
+                write: true,
+                action: WatchAction::Break,
+                value: Some(0x07),
+            }],
+            video_mode: Some("16,96,48,640/10,2,12,500".parse().unwrap()),
+        };
+
+        let mut bytes = vec![];
+        project.write(&mut bytes).unwrap();
+        let loaded = Project::read(&mut bytes.as_slice()).unwrap();
+        assert_eq!(project, loaded);
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        let text = format!("{}\nbreak maybe 0123 0\n", HEADER);
+        assert!(Project::read(&mut text.as_bytes()).is_err());
+        assert!(Project::read(&mut "break on 0123 0\n".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,297 @@
+use std::io::{Error, ErrorKind, Read, Write};
+
+use crate::{
+    cpu::{rom_hash, Cpu, OpCode, RegisterFile, RAM_SIZE},
+    vga::{BeamState, SyncTiming, Vga, VideoMode},
+};
+
+const MAGIC: &[u8; 4] = b"GTSS";
+const VERSION: u16 = 3;
+
+// Snapshot of everything needed to resume emulation. The binary layout is
+// the magic, then little endian fields in the order they appear here.
+pub struct SaveState {
+    pub rom_hash: u64,
+    pub reg: RegisterFile,
+    pub queued_pc: u16,
+    pub input: u8,
+    pub rng_state: u64,
+    pub beam: BeamState,
+    pub mode: VideoMode,
+    pub ram: Vec<u8>,
+}
+
+pub(crate) fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+pub(crate) fn read_bytes<const N: usize>(r: &mut impl Read) -> Result<[u8; N], Error> {
+    let mut buf = [0; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+pub(crate) fn read_u8(r: &mut impl Read) -> Result<u8, Error> {
+    Ok(read_bytes::<1>(r)?[0])
+}
+
+pub(crate) fn read_u16(r: &mut impl Read) -> Result<u16, Error> {
+    Ok(u16::from_le_bytes(read_bytes(r)?))
+}
+
+pub(crate) fn read_i32(r: &mut impl Read) -> Result<i32, Error> {
+    Ok(i32::from_le_bytes(read_bytes(r)?))
+}
+
+pub(crate) fn read_u64(r: &mut impl Read) -> Result<u64, Error> {
+    Ok(u64::from_le_bytes(read_bytes(r)?))
+}
+
+fn write_sync_timing(w: &mut impl Write, timing: &SyncTiming) -> Result<(), Error> {
+    for value in [
+        timing.front_porch,
+        timing.pulse,
+        timing.back_porch,
+        timing.visible,
+    ] {
+        w.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_sync_timing(r: &mut impl Read) -> Result<SyncTiming, Error> {
+    Ok(SyncTiming {
+        front_porch: read_i32(r)?,
+        pulse: read_i32(r)?,
+        back_porch: read_i32(r)?,
+        visible: read_i32(r)?,
+    })
+}
+
+impl SaveState {
+    pub fn capture(cpu: &Cpu, vga: &Vga) -> Self {
+        Self {
+            rom_hash: rom_hash(&cpu.rom),
+            reg: cpu.state.reg.clone(),
+            queued_pc: cpu.state.queued_pc,
+            input: cpu.input,
+            rng_state: cpu.rng.state,
+            beam: vga.beam(),
+            mode: *vga.mode(),
+            ram: cpu.state.ram.clone(),
+        }
+    }
+
+    // Fails without touching anything if the state was saved from another ROM
+    // or in another video mode
+    pub fn restore(&self, cpu: &mut Cpu, vga: &mut Vga) -> Result<(), Error> {
+        if self.rom_hash != rom_hash(&cpu.rom) {
+            return Err(invalid("Save state was made with a different ROM"));
+        }
+        if self.mode != *vga.mode() {
+            return Err(invalid(&format!(
+                "Save state was made in video mode {}, not {}",
+                self.mode,
+                vga.mode()
+            )));
+        }
+
+        cpu.state.reg = self.reg.clone();
+        cpu.state.queued_pc = self.queued_pc;
+        cpu.state.ram.copy_from_slice(&self.ram);
+        cpu.input = self.input;
+        cpu.rng.state = self.rng_state;
+        vga.restore_beam(self.beam);
+        Ok(())
+    }
+
+    pub fn write(&self, w: &mut impl Write) -> Result<(), Error> {
+        let reg = &self.reg;
+
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        w.write_all(&self.rom_hash.to_le_bytes())?;
+        w.write_all(&reg.pc.to_le_bytes())?;
+        w.write_all(&[
+            reg.ir.0, reg.d, reg.ac, reg.x, reg.y, reg.out, reg.xout, reg.undef,
+        ])?;
+        w.write_all(&self.queued_pc.to_le_bytes())?;
+        w.write_all(&[self.input])?;
+        w.write_all(&self.rng_state.to_le_bytes())?;
+        w.write_all(&[self.beam.prev_out])?;
+        w.write_all(&self.beam.row.to_le_bytes())?;
+        w.write_all(&self.beam.col.to_le_bytes())?;
+        w.write_all(&(self.beam.pixel as u64).to_le_bytes())?;
+        w.write_all(&self.beam.frame_count.to_le_bytes())?;
+        write_sync_timing(w, &self.mode.horiz)?;
+        write_sync_timing(w, &self.mode.vert)?;
+        w.write_all(&self.ram)
+    }
+
+    pub fn read(r: &mut impl Read) -> Result<Self, Error> {
+        if &read_bytes::<4>(r)? != MAGIC {
+            return Err(invalid("Not a save state file"));
+        }
+        let version = read_u16(r)?;
+        if !(1..=VERSION).contains(&version) {
+            return Err(invalid("Unsupported save state version"));
+        }
+
+        let rom_hash = read_u64(r)?;
+        let reg = RegisterFile {
+            pc: read_u16(r)?,
+            ir: OpCode(read_u8(r)?),
+            d: read_u8(r)?,
+            ac: read_u8(r)?,
+            x: read_u8(r)?,
+            y: read_u8(r)?,
+            out: read_u8(r)?,
+            // Version 1 predates XOUT emulation
+            xout: if version >= 2 { read_u8(r)? } else { 0 },
+            undef: read_u8(r)?,
+        };
+        let queued_pc = read_u16(r)?;
+        let input = read_u8(r)?;
+        let rng_state = read_u64(r)?;
+        let beam = BeamState {
+            prev_out: read_u8(r)?,
+            row: read_i32(r)?,
+            col: read_i32(r)?,
+            pixel: read_u64(r)? as usize,
+            frame_count: read_u64(r)?,
+        };
+        // Older versions predate video modes
+        let mode = if version >= 3 {
+            VideoMode {
+                horiz: read_sync_timing(r)?,
+                vert: read_sync_timing(r)?,
+            }
+        } else {
+            VideoMode::DEFAULT
+        };
+
+        let mut ram = vec![0; RAM_SIZE];
+        r.read_exact(&mut ram)?;
+
+        Ok(Self {
+            rom_hash,
+            reg,
+            queued_pc,
+            input,
+            rng_state,
+            beam,
+            mode,
+            ram,
+        })
+    }
+
+    pub fn save(&self, file_name: &str) -> Result<(), Error> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(file_name)?);
+        self.write(&mut file)?;
+        file.flush()
+    }
+
+    pub fn load(file_name: &str) -> Result<Self, Error> {
+        let mut file = std::io::BufReader::new(std::fs::File::open(file_name)?);
+        Self::read(&mut file)
+    }
+}
+
+// File used for a numbered quick-save slot, kept next to the ROM
+pub fn slot_file_name(rom_file: &str, slot: u32) -> String {
+    format!("{}.state{}", rom_file, slot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cpu::{PowerOn, PowerOnFill, RomWord},
+        vga,
+    };
+
+    fn test_rom() -> Vec<RomWord> {
+        // ld $c0,out; bra $00; ld $40,out
+        [(0x18, 0xc0), (0xfc, 0x00), (0x18, 0x40)]
+            .into_iter()
+            .map(|(inst, data)| RomWord {
+                inst: OpCode(inst),
+                data,
+            })
+            .chain(std::iter::repeat(RomWord {
+                inst: OpCode(0),
+                data: 0,
+            }))
+            .take(256)
+            .collect()
+    }
+
+    #[test]
+    fn round_trip_resumes_identically() {
+        let power_on = PowerOn::new(Some(99), PowerOnFill::Random);
+        let mut cpu = Cpu::new(test_rom(), power_on);
+        let mut vga = Vga::new(&vga::HORIZ_TIMING, &vga::VERT_TIMING);
+        for _ in 0..1000 {
+            cpu.clock();
+            vga.update(&cpu.state.reg);
+        }
+
+        let mut bytes = vec![];
+        SaveState::capture(&cpu, &vga).write(&mut bytes).unwrap();
+
+        let mut loaded_cpu = Cpu::new(test_rom(), PowerOn::new(Some(1), PowerOnFill::Zero));
+        let mut loaded_vga = Vga::new(&vga::HORIZ_TIMING, &vga::VERT_TIMING);
+        SaveState::read(&mut bytes.as_slice())
+            .unwrap()
+            .restore(&mut loaded_cpu, &mut loaded_vga)
+            .unwrap();
+
+        for _ in 0..1000 {
+            cpu.clock();
+            loaded_cpu.clock();
+        }
+        assert_eq!(cpu.state.reg, loaded_cpu.state.reg);
+        assert_eq!(cpu.state.queued_pc, loaded_cpu.state.queued_pc);
+        assert!(cpu.state.ram == loaded_cpu.state.ram);
+    }
+
+    #[test]
+    fn rejects_other_rom() {
+        let power_on = PowerOn::new(Some(5), PowerOnFill::Zero);
+        let cpu = Cpu::new(test_rom(), power_on);
+        let vga = Vga::new(&vga::HORIZ_TIMING, &vga::VERT_TIMING);
+        let state = SaveState::capture(&cpu, &vga);
+
+        let mut other_rom = test_rom();
+        other_rom[0].data = 0;
+        let mut other = Cpu::new(other_rom, power_on);
+        let mut other_vga = Vga::new(&vga::HORIZ_TIMING, &vga::VERT_TIMING);
+        assert!(state.restore(&mut other, &mut other_vga).is_err());
+    }
+
+    #[test]
+    fn rejects_other_video_mode() {
+        let power_on = PowerOn::new(Some(1), PowerOnFill::Zero);
+        let mut cpu = Cpu::new(test_rom(), power_on);
+        let mut vga = Vga::from_mode(&VideoMode::DEFAULT);
+        // Leave the beam past the end of a 350 line frame
+        vga.restore_beam(BeamState {
+            prev_out: 0xc0,
+            row: 334,
+            col: 200,
+            pixel: 768000,
+            frame_count: 1,
+        });
+
+        let mut bytes = vec![];
+        SaveState::capture(&cpu, &vga).write(&mut bytes).unwrap();
+        let state = SaveState::read(&mut bytes.as_slice()).unwrap();
+        assert_eq!(state.mode, VideoMode::DEFAULT);
+
+        let vga_350: VideoMode = "vga-640x350".parse().unwrap();
+        let mut other_vga = Vga::from_mode(&vga_350);
+        let before = other_vga.beam().pixel;
+        assert!(state.restore(&mut cpu, &mut other_vga).is_err());
+        assert_eq!(other_vga.beam().pixel, before);
+    }
+}
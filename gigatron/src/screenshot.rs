@@ -0,0 +1,73 @@
+use std::io::{BufRead, BufWriter, Error, ErrorKind, Seek, Write};
+
+use crate::vga::Vga;
+
+fn encoding_error(e: png::EncodingError) -> Error {
+    match e {
+        png::EncodingError::IoError(e) => e,
+        e => Error::new(ErrorKind::InvalidData, e),
+    }
+}
+
+// Writes RGBA8 pixels as an RGB PNG. The alpha channel is always opaque so
+// it is dropped.
+pub fn write_png(w: impl Write, size: (u32, u32), rgba: &[u8]) -> Result<(), Error> {
+    let mut encoder = png::Encoder::new(w, size.0, size.1);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let rgb: Vec<u8> = rgba
+        .chunks_exact(4)
+        .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]])
+        .collect();
+    let mut writer = encoder.write_header().map_err(encoding_error)?;
+    writer.write_image_data(&rgb).map_err(encoding_error)?;
+    writer.finish().map_err(encoding_error)
+}
+
+// Saves the last completed frame
+pub fn save_png(file_name: &str, vga: &Vga) -> Result<(), Error> {
+    let file = BufWriter::new(std::fs::File::create(file_name)?);
+    write_png(file, vga.size(), vga.last_frame())
+}
+
+fn decoding_error(e: png::DecodingError) -> Error {
+    match e {
+        png::DecodingError::IoError(e) => e,
+        e => Error::new(ErrorKind::InvalidData, e),
+    }
+}
+
+// Reads an 8 bit RGB or RGBA PNG as RGBA8 pixels
+pub fn read_png(r: impl BufRead + Seek) -> Result<((u32, u32), Vec<u8>), Error> {
+    let mut reader = png::Decoder::new(r).read_info().map_err(decoding_error)?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).map_err(decoding_error)?;
+    buf.truncate(info.buffer_size());
+
+    let rgba = match (info.color_type, info.bit_depth) {
+        (png::ColorType::Rgba, png::BitDepth::Eight) => buf,
+        (png::ColorType::Rgb, png::BitDepth::Eight) => buf
+            .chunks_exact(3)
+            .flat_map(|pixel| [pixel[0], pixel[1], pixel[2], 255])
+            .collect(),
+        _ => return Err(Error::new(ErrorKind::InvalidData, "Unsupported PNG format")),
+    };
+    Ok(((info.width, info.height), rgba))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn png_keeps_exact_pixels() {
+        let rgba: Vec<u8> = (0..6u8).flat_map(|i| [i * 40, 255 - i, i, 255]).collect();
+        let mut bytes = vec![];
+        write_png(&mut bytes, (3, 2), &rgba).unwrap();
+
+        let (size, decoded) = read_png(std::io::Cursor::new(bytes)).unwrap();
+        assert_eq!(size, (3, 2));
+        assert_eq!(decoded, rgba);
+    }
+}
@@ -0,0 +1,183 @@
+use std::{collections::BTreeMap, error::Error, fmt::Display, io::BufRead, str::FromStr};
+
+use itertools::Itertools;
+
+pub struct ZeroPageVariable {
+    pub address: u8,
+    pub length: u8,
+    pub name: String,
+}
+
+pub enum Placeholder {
+    Literal {
+        val: String,
+    },
+    Unary {
+        name: String,
+        val: Box<Placeholder>,
+    },
+    Binary {
+        name: String,
+        lhs: Box<Placeholder>,
+        rhs: Box<Placeholder>,
+    },
+}
+
+impl Display for Placeholder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Literal { val } => write!(f, "{}", val),
+            Self::Unary { name, val } => write!(f, "{}({})", name, val),
+            Self::Binary { name, lhs, rhs } => write!(f, "{} {} {}", lhs, name, rhs),
+        }
+    }
+}
+
+impl Placeholder {
+    fn parse(tokens: &[&str]) -> Result<(Self, usize), Box<dyn Error>> {
+        match *tokens.first().ok_or("Missing placeholder value")? {
+            token @ ("hi" | "lo") => {
+                let (val, val_len) = Self::parse(&tokens[1..])?;
+                Ok((
+                    Self::Unary {
+                        name: token.to_string(),
+                        val: Box::new(val),
+                    },
+                    val_len + 1,
+                ))
+            }
+            token @ "add" => {
+                let (lhs, lhs_len) = Self::parse(&tokens[1..])?;
+                let (rhs, rhs_len) = Self::parse(&tokens[(1 + lhs_len)..])?;
+                Ok((
+                    Self::Binary {
+                        name: token.to_string(),
+                        lhs: Box::new(lhs),
+                        rhs: Box::new(rhs),
+                    },
+                    lhs_len + rhs_len + 1,
+                ))
+            }
+            "zp" => {
+                let (val, val_len) = Self::parse(&tokens[1..])?;
+                Ok((val, val_len + 1))
+            }
+            token => Ok((
+                Self::Literal {
+                    val: token.to_string(),
+                },
+                1,
+            )),
+        }
+    }
+}
+
+// Parses the placeholder syntax of .sym files, like "hi table"
+impl FromStr for Placeholder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = s.split(' ').collect_vec();
+        match Self::parse(&tokens) {
+            Ok((placeholder, len)) if len == tokens.len() => Ok(placeholder),
+            _ => Err(format!("Invalid placeholder '{}'", s)),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct SymbolTable {
+    pub zero_page: Vec<ZeroPageVariable>,
+    pub labels: BTreeMap<u16, String>, // FIXME there can be multiple labels on same address
+    pub placeholders: BTreeMap<u16, Placeholder>,
+}
+
+impl SymbolTable {
+    pub fn load(file_name: &str) -> Result<Self, std::io::Error> {
+        // TODO: Clean up
+
+        let file = std::fs::File::open(file_name)?;
+        let lines = std::io::BufReader::new(file).lines();
+
+        let mut zero_page = vec![];
+        let mut labels = BTreeMap::new();
+        let mut placeholders = BTreeMap::new();
+
+        for line in lines.map_while(Result::ok) {
+            let tokens = line.split(' ').collect_vec();
+            if tokens.is_empty() {
+                continue;
+            }
+
+            match tokens[0] {
+                "z" => {
+                    if tokens.len() != 4 {
+                        continue;
+                    }
+
+                    if let Ok(addr) = tokens[1].parse() {
+                        if let Ok(length) = tokens[2].parse() {
+                            zero_page.push(ZeroPageVariable {
+                                address: addr,
+                                length,
+                                name: tokens[3].to_string(),
+                            })
+                        }
+                    }
+                }
+                "l" => {
+                    if tokens.len() != 3 {
+                        continue;
+                    }
+
+                    if let Ok(addr) = tokens[1].parse() {
+                        labels.insert(addr, tokens[2].to_string());
+                    }
+                }
+                "p" => {
+                    if let Ok(addr) = tokens[1].parse() {
+                        if let Ok((placeholder, _)) = Placeholder::parse(&tokens[2..]) {
+                            placeholders.insert(addr, placeholder);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            zero_page,
+            labels,
+            placeholders,
+        })
+    }
+
+    pub fn find_label(&self, name: &str) -> Option<u16> {
+        self.labels
+            .iter()
+            .find(|(_, label)| *label == name)
+            .map(|(addr, _)| *addr)
+    }
+
+    pub fn find_var(&self, name: &str) -> Option<&ZeroPageVariable> {
+        self.zero_page.iter().find(|var| var.name == name)
+    }
+
+    // Address with the label it falls under, e.g. "0123 drawPiece+4"
+    pub fn format_addr(&self, addr: u16) -> String {
+        match self.labels.range(..=addr).next_back() {
+            Some((&label_addr, label)) if label_addr == addr => format!("{:04x} {}", addr, label),
+            Some((&label_addr, label)) => {
+                format!("{:04x} {}+{}", addr, label, addr - label_addr)
+            }
+            None => format!("{:04x}", addr),
+        }
+    }
+
+    pub fn find_label_before(&self, addr: u16) -> Option<u16> {
+        self.labels
+            .range(..addr)
+            .next_back()
+            .map(|t| t.0.to_owned())
+    }
+}
@@ -0,0 +1,211 @@
+use crate::vga::{SyncTiming, HSYNC, PIXELS_PER_CYCLE, VSYNC};
+
+// Measurements of one scanline, in pixels from the start of its HSYNC pulse
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LineTiming {
+    // None if HSYNC was still low when the next line started
+    pub pulse: Option<i32>,
+    // First non-black pixel after the pulse, None for a blank line
+    pub visible_start: Option<i32>,
+    pub total: i32,
+    // Whether VSYNC was active at any point during the line
+    pub vsync: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LineErrors {
+    pub pulse: bool,
+    // Pixels drawn during the back porch, so they would be cut off on screen
+    pub early_visible: bool,
+    pub total: bool,
+}
+
+impl LineErrors {
+    pub fn any(&self) -> bool {
+        self.pulse || self.early_visible || self.total
+    }
+}
+
+impl LineTiming {
+    pub fn back_porch(&self) -> Option<i32> {
+        Some(self.visible_start? - self.pulse?)
+    }
+
+    // Compares against the timing the monitor expects. Black pixels are not
+    // distinguishable from blanking, so only an early visible start counts.
+    pub fn errors(&self, horiz: &SyncTiming) -> LineErrors {
+        LineErrors {
+            pulse: self.pulse != Some(horiz.pulse),
+            early_visible: self
+                .visible_start
+                .is_some_and(|start| start < horiz.pulse + horiz.back_porch),
+            total: self.total != horiz.total(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FrameTiming {
+    pub lines: Vec<LineTiming>,
+    // Length of the VSYNC pulse, which can start or end partway into a line
+    pub vsync_pixels: i32,
+}
+
+impl FrameTiming {
+    pub fn vsync_lines(&self, horiz: &SyncTiming) -> f32 {
+        self.vsync_pixels as f32 / horiz.total() as f32
+    }
+
+    pub fn vert_error(&self, horiz: &SyncTiming, vert: &SyncTiming) -> bool {
+        self.vsync_pixels != vert.pulse * horiz.total() || self.lines.len() as i32 != vert.total()
+    }
+
+    pub fn bad_lines(&self, horiz: &SyncTiming) -> usize {
+        self.lines
+            .iter()
+            .filter(|line| line.errors(horiz).any())
+            .count()
+    }
+}
+
+// Measures the sync signals of every line so deviations from the expected
+// timing can be found. A frame runs from one VSYNC falling edge to the next,
+// and a line from one HSYNC falling edge to the next.
+pub struct TimingAnalyser {
+    pub horiz: SyncTiming,
+    pub vert: SyncTiming,
+
+    prev_out: u8,
+    line: Option<LineTiming>,
+    // The frame in progress when the analyser starts is incomplete
+    in_frame: bool,
+    current: FrameTiming,
+    last: Option<FrameTiming>,
+}
+
+impl TimingAnalyser {
+    pub fn new(horiz: &SyncTiming, vert: &SyncTiming) -> Self {
+        Self {
+            horiz: *horiz,
+            vert: *vert,
+            prev_out: 0,
+            line: None,
+            in_frame: false,
+            current: FrameTiming::default(),
+            last: None,
+        }
+    }
+
+    // Last completed frame, if one has been seen since the analyser started
+    pub fn last_frame(&self) -> Option<&FrameTiming> {
+        self.last.as_ref()
+    }
+
+    // Called with the output register after each cycle. Returns whether a
+    // frame just completed with the wrong number of lines or VSYNC lines.
+    pub fn update(&mut self, out: u8) -> bool {
+        let falling = self.prev_out & !out;
+        let rising = !self.prev_out & out;
+        self.prev_out = out;
+
+        if falling & HSYNC != 0 {
+            if let Some(line) = self.line.take() {
+                self.current.lines.push(line);
+            }
+        }
+
+        let mut vert_error = false;
+        if falling & VSYNC != 0 {
+            let frame = std::mem::take(&mut self.current);
+            if self.in_frame {
+                vert_error = frame.vert_error(&self.horiz, &self.vert);
+                self.last = Some(frame);
+            }
+            self.in_frame = true;
+        }
+
+        if falling & HSYNC != 0 {
+            self.line = Some(LineTiming {
+                pulse: None,
+                visible_start: None,
+                total: 0,
+                vsync: false,
+            });
+        }
+
+        if self.in_frame && out & VSYNC == 0 {
+            self.current.vsync_pixels += PIXELS_PER_CYCLE;
+        }
+        if let Some(line) = &mut self.line {
+            line.vsync |= out & VSYNC == 0;
+            if rising & HSYNC != 0 && line.pulse.is_none() {
+                line.pulse = Some(line.total);
+            }
+            if line.pulse.is_some() && line.visible_start.is_none() && out & 0x3f != 0 {
+                line.visible_start = Some(line.total);
+            }
+            line.total += PIXELS_PER_CYCLE;
+        }
+
+        vert_error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vga::{HORIZ_TIMING, VERT_TIMING};
+
+    const SYNC: u8 = HSYNC | VSYNC;
+
+    // Outputs a line with the given pulse and back porch lengths in cycles
+    fn line(out: &mut Vec<u8>, vsync: bool, pulse: usize, back_porch: usize, total: usize) {
+        let v = if vsync { 0 } else { VSYNC };
+        out.extend(std::iter::repeat_n(v, pulse));
+        out.extend(std::iter::repeat_n(v | HSYNC, back_porch));
+        out.extend(std::iter::repeat_n(
+            v | HSYNC | 0x15,
+            total - pulse - back_porch,
+        ));
+    }
+
+    fn frame(out: &mut Vec<u8>, vsync_lines: usize) {
+        for i in 0..VERT_TIMING.total() as usize {
+            if i == 100 {
+                line(out, false, 20, 10, 200);
+            } else {
+                line(out, i < vsync_lines, 24, 12, 200);
+            }
+        }
+    }
+
+    #[test]
+    fn measures_lines() {
+        let mut out = vec![SYNC];
+        frame(&mut out, 8);
+        frame(&mut out, 7);
+        frame(&mut out, 8);
+
+        let mut analyser = TimingAnalyser::new(&HORIZ_TIMING, &VERT_TIMING);
+        let errors: Vec<bool> = out.iter().map(|&o| analyser.update(o)).collect();
+        let error_count = errors.iter().filter(|&&e| e).count();
+
+        // The first frame starts on the first VSYNC edge, so only the frame
+        // with 7 VSYNC lines gets reported
+        assert_eq!(error_count, 1);
+
+        let frame = analyser.last_frame().unwrap();
+        assert_eq!(frame.lines.len() as i32, VERT_TIMING.total());
+        assert_eq!(frame.vsync_lines(&HORIZ_TIMING), 7.0);
+        assert_eq!(frame.bad_lines(&HORIZ_TIMING), 1);
+
+        let good = frame.lines[0];
+        assert_eq!(good.pulse, Some(96));
+        assert_eq!(good.back_porch(), Some(48));
+        assert_eq!(good.total, 800);
+        assert!(!good.errors(&HORIZ_TIMING).any());
+
+        let bad = frame.lines[100].errors(&HORIZ_TIMING);
+        assert!(bad.pulse && bad.early_visible && !bad.total);
+    }
+}
@@ -1,12 +1,15 @@
-use std::{borrow::Cow, cell::Cell, rc::Rc};
+use std::{fmt::Display, str::FromStr};
 
-use glium::texture::RawImage2d;
-
-use crate::{cpu::RegisterFile, ui_context::RenderContext};
+use crate::{cpu::RegisterFile, timing::TimingAnalyser};
 
 pub const VSYNC: u8 = 0x80;
 pub const HSYNC: u8 = 0x40;
 
+// Each CPU cycle outputs one pixel, which lasts 4 pixels of the 25 MHz clock
+// the SyncTiming values are measured in
+pub const PIXELS_PER_CYCLE: i32 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct SyncTiming {
     pub front_porch: i32,
     pub pulse: i32,
@@ -14,26 +17,232 @@ pub struct SyncTiming {
     pub visible: i32,
 }
 
+impl SyncTiming {
+    pub fn total(&self) -> i32 {
+        self.front_porch + self.pulse + self.back_porch + self.visible
+    }
+}
+
+pub const HORIZ_TIMING: SyncTiming = SyncTiming {
+    front_porch: 16,
+    pulse: 96,
+    back_porch: 48,
+    visible: 640,
+};
+
+pub const VERT_TIMING: SyncTiming = SyncTiming {
+    front_porch: 6,
+    pulse: 8,
+    back_porch: 27,
+    visible: 480,
+};
+
+// Number of CPU cycles in one frame
+pub fn total_frame_cycles(horiz_timing: &SyncTiming, vert_timing: &SyncTiming) -> i32 {
+    horiz_timing.total() * vert_timing.total() / PIXELS_PER_CYCLE
+}
+
+// Timing the monitor expects. Modes are selected by name or given as
+// "front,pulse,back,visible/front,pulse,back,visible" with the horizontal
+// values in pixels and the vertical values in lines.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VideoMode {
+    pub horiz: SyncTiming,
+    pub vert: SyncTiming,
+}
+
+impl VideoMode {
+    pub const DEFAULT: Self = Self {
+        horiz: HORIZ_TIMING,
+        vert: VERT_TIMING,
+    };
+
+    pub const NAMED: [(&'static str, Self); 4] = [
+        ("default", Self::DEFAULT),
+        // Industry standard 640x480, also 525 lines but with a 2 line VSYNC
+        (
+            "vesa-640x480",
+            Self {
+                horiz: HORIZ_TIMING,
+                vert: SyncTiming {
+                    front_porch: 10,
+                    pulse: 2,
+                    back_porch: 33,
+                    visible: 480,
+                },
+            },
+        ),
+        // 449 line modes, normally run at 70 Hz
+        (
+            "vga-640x400",
+            Self {
+                horiz: HORIZ_TIMING,
+                vert: SyncTiming {
+                    front_porch: 12,
+                    pulse: 2,
+                    back_porch: 35,
+                    visible: 400,
+                },
+            },
+        ),
+        (
+            "vga-640x350",
+            Self {
+                horiz: HORIZ_TIMING,
+                vert: SyncTiming {
+                    front_porch: 37,
+                    pulse: 2,
+                    back_porch: 60,
+                    visible: 350,
+                },
+            },
+        ),
+    ];
+
+    pub fn name(&self) -> Option<&'static str> {
+        Self::NAMED
+            .iter()
+            .find(|(_, mode)| mode == self)
+            .map(|(name, _)| *name)
+    }
+
+    pub fn total_frame_cycles(&self) -> i32 {
+        total_frame_cycles(&self.horiz, &self.vert)
+    }
+}
+
+impl Default for VideoMode {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl Display for VideoMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(name) = self.name() {
+            return f.write_str(name);
+        }
+        let (h, v) = (&self.horiz, &self.vert);
+        write!(
+            f,
+            "{},{},{},{}/{},{},{},{}",
+            h.front_porch,
+            h.pulse,
+            h.back_porch,
+            h.visible,
+            v.front_porch,
+            v.pulse,
+            v.back_porch,
+            v.visible
+        )
+    }
+}
+
+fn parse_sync_timing(s: &str) -> Option<SyncTiming> {
+    let values: Vec<i32> = s
+        .split(',')
+        .map(|v| v.trim().parse().ok())
+        .collect::<Option<_>>()?;
+    let [front_porch, pulse, back_porch, visible] = values[..] else {
+        return None;
+    };
+    if values.iter().any(|&v| v < 0) || pulse == 0 || visible == 0 {
+        return None;
+    }
+    Some(SyncTiming {
+        front_porch,
+        pulse,
+        back_porch,
+        visible,
+    })
+}
+
+impl FromStr for VideoMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((_, mode)) = Self::NAMED.iter().find(|(name, _)| *name == s) {
+            return Ok(*mode);
+        }
+
+        let bad_mode = || {
+            let names: Vec<&str> = Self::NAMED.iter().map(|(name, _)| *name).collect();
+            format!(
+                "Invalid video mode '{}', expected one of {} or front,pulse,back,visible/front,pulse,back,visible",
+                s,
+                names.join(", ")
+            )
+        };
+        let (horiz, vert) = s.split_once('/').ok_or_else(bad_mode)?;
+        let horiz = parse_sync_timing(horiz).ok_or_else(bad_mode)?;
+        let vert = parse_sync_timing(vert).ok_or_else(bad_mode)?;
+
+        // The beam moves a whole cycle at a time, so horizontal timing has to
+        // line up with cycles
+        let h = [
+            horiz.front_porch,
+            horiz.pulse,
+            horiz.back_porch,
+            horiz.visible,
+        ];
+        if h.iter().any(|v| v % PIXELS_PER_CYCLE != 0) {
+            return Err(format!(
+                "Horizontal timing of video mode '{}' must be multiples of {} pixels",
+                s, PIXELS_PER_CYCLE
+            ));
+        }
+        Ok(Self { horiz, vert })
+    }
+}
+
+// Position of the beam within the frame, enough to rewind the monitor
+#[derive(Clone, Copy)]
+pub struct BeamState {
+    pub prev_out: u8,
+    pub row: i32,
+    pub col: i32,
+    pub pixel: usize,
+    pub frame_count: u64,
+}
+
+// Completed frame a frame start replaced, enough to undo it
+pub struct DiscardedFrame {
+    frame: Vec<u8>,
+    pcs: Vec<Option<u16>>,
+}
+
 pub struct Vga {
-    pub tex_id: Option<imgui::TextureId>,
-    framebuffer: Cell<Vec<u8>>,
+    framebuffer: Vec<u8>,
+    last_frame: Vec<u8>,
+    // Address of the instruction that loaded OUT for each group of
+    // PIXELS_PER_CYCLE pixels, when the caller reports it
+    pc_buffer: Vec<Option<u16>>,
+    last_pcs: Vec<Option<u16>>,
+    discarded: Option<DiscardedFrame>,
+    out_pc: Option<u16>,
+    frame_count: u64,
     size: (u32, u32),
-    pixel_count: usize,
+    mode: VideoMode,
 
     min_row: i32,
     max_row: i32,
     min_col: i32,
     max_col: i32,
+    line_length: i32,
 
     prev_out: u8,
     row: i32,
     col: i32,
     pixel: usize,
+
+    timing: TimingAnalyser,
 }
 
 pub struct TimingResult {
     pub should_render: bool,
     pub horiz_cycle_err: bool,
+    // A frame just ended with the wrong number of lines or VSYNC lines
+    pub vert_timing_err: bool,
 }
 
 impl Vga {
@@ -44,78 +253,149 @@ impl Vga {
         let pixel_count = (horiz_timing.visible * vert_timing.visible * 4) as usize;
 
         Self {
-            tex_id: None,
-            framebuffer: Cell::new(vec![0; pixel_count]),
+            framebuffer: vec![0; pixel_count],
+            last_frame: vec![0; pixel_count],
+            pc_buffer: vec![None; pixel_count / 4 / PIXELS_PER_CYCLE as usize],
+            last_pcs: vec![None; pixel_count / 4 / PIXELS_PER_CYCLE as usize],
+            discarded: None,
+            out_pc: None,
+            frame_count: 0,
             size: (horiz_timing.visible as u32, vert_timing.visible as u32),
-            pixel_count,
+            mode: VideoMode {
+                horiz: *horiz_timing,
+                vert: *vert_timing,
+            },
 
             min_row,
             max_row: min_row + vert_timing.visible,
             min_col,
             max_col: min_col + horiz_timing.visible,
+            line_length: horiz_timing.total(),
 
             prev_out: 0,
             row: 0,
             col: 0,
             pixel: 0,
+
+            timing: TimingAnalyser::new(horiz_timing, vert_timing),
         }
     }
 
-    fn render(&mut self, ctx: &mut RenderContext) {
-        // Swap framebuffers
-        let fb = self.framebuffer.replace(vec![0; self.pixel_count]);
+    pub fn beam(&self) -> BeamState {
+        BeamState {
+            prev_out: self.prev_out,
+            row: self.row,
+            col: self.col,
+            pixel: self.pixel,
+            frame_count: self.frame_count,
+        }
+    }
+
+    pub fn restore_beam(&mut self, beam: BeamState) {
+        self.prev_out = beam.prev_out;
+        self.row = beam.row;
+        self.col = beam.col;
+        self.pixel = beam.pixel;
+        self.frame_count = beam.frame_count;
+    }
 
-        let tex_data: RawImage2d<u8> = RawImage2d {
-            data: Cow::Owned(fb),
-            width: self.size.0,
-            height: self.size.1,
-            format: glium::texture::ClientFormat::U8U8U8U8,
+    // Swap the completed frame out and start drawing a blank one. The frame
+    // it replaces is kept until the next frame start, for undo_render.
+    fn render(&mut self) {
+        let (mut frame, mut pcs) = match self.discarded.take() {
+            Some(DiscardedFrame { frame, pcs }) => (frame, pcs),
+            None => (
+                vec![0; self.framebuffer.len()],
+                vec![None; self.pc_buffer.len()],
+            ),
         };
+        frame.fill(0);
+        pcs.fill(None);
 
-        let tex_opt = glium::Texture2d::with_format(
-            &ctx.gl_ctx,
-            tex_data,
-            glium::texture::UncompressedFloatFormat::F32F32F32F32,
-            glium::texture::MipmapsOption::NoMipmap,
-        )
-        .ok();
-
-        if let Some(tex_2d) = tex_opt {
-            let tex = imgui_glium_renderer::Texture {
-                texture: Rc::new(tex_2d),
-                sampler: glium::uniforms::SamplerBehavior {
-                    magnify_filter: glium::uniforms::MagnifySamplerFilter::Nearest,
-                    minify_filter: glium::uniforms::MinifySamplerFilter::Nearest,
-                    ..Default::default()
-                },
-            };
-
-            let textures = ctx.imgui_renderer.textures();
-            match self.tex_id {
-                Some(id) => {
-                    textures.replace(id, tex);
-                }
-                None => {
-                    self.tex_id = Some(textures.insert(tex));
-                }
-            }
+        let last_frame = std::mem::replace(&mut self.framebuffer, frame);
+        let last_pcs = std::mem::replace(&mut self.pc_buffer, pcs);
+        self.discarded = Some(DiscardedFrame {
+            frame: std::mem::replace(&mut self.last_frame, last_frame),
+            pcs: std::mem::replace(&mut self.last_pcs, last_pcs),
+        });
+        self.frame_count += 1;
+    }
+
+    // Frame replaced by the frame start during the last update
+    pub fn take_discarded(&mut self) -> Option<DiscardedFrame> {
+        self.discarded.take()
+    }
+
+    // Puts the frame being drawn back to before the frame start that
+    // discarded the given frame. The beam is rewound with restore_beam.
+    pub fn undo_render(&mut self, discarded: DiscardedFrame) {
+        self.framebuffer = std::mem::replace(&mut self.last_frame, discarded.frame);
+        self.pc_buffer = std::mem::replace(&mut self.last_pcs, discarded.pcs);
+    }
+
+    // Last completed frame as RGBA8 pixels
+    pub fn last_frame(&self) -> &[u8] {
+        &self.last_frame
+    }
+
+    // Number of frames completed since power on
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        self.size
+    }
+
+    pub fn mode(&self) -> &VideoMode {
+        &self.mode
+    }
+
+    // The frame being drawn, with the part the beam has not reached yet
+    // filled in from the last frame at half brightness
+    pub fn partial_frame(&self) -> Vec<u8> {
+        let mut frame = self.framebuffer[..self.pixel].to_vec();
+        frame.extend(
+            self.last_frame[self.pixel..]
+                .chunks_exact(4)
+                .flat_map(|p| [p[0] / 2, p[1] / 2, p[2] / 2, 255]),
+        );
+        frame
+    }
+
+    // Called before update when the instruction at pc loaded OUT this cycle
+    pub fn set_out_pc(&mut self, pc: u16) {
+        self.out_pc = Some(pc);
+    }
+
+    // Address of the instruction that output a pixel of the last frame, or of
+    // the frame being drawn if partial and the beam has passed it
+    pub fn pixel_pc(&self, x: u32, y: u32, partial: bool) -> Option<u16> {
+        let (width, height) = self.size;
+        if x >= width || y >= height {
+            return None;
+        }
+        let pixel = (y * width + x) as usize;
+        let cell = pixel / PIXELS_PER_CYCLE as usize;
+        if partial && pixel * 4 < self.pixel {
+            self.pc_buffer[cell]
+        } else {
+            self.last_pcs[cell]
         }
     }
 
-    pub fn show_ui(&self, ui: &mut imgui::Ui) {
-        ui.window("VGA Monitor").build(|| match self.tex_id {
-            Some(tex) => {
-                imgui::Image::new(tex, [self.size.0 as f32, self.size.1 as f32]).build(ui);
-            }
-            None => {
-                ui.text("Image go here");
-            }
-        });
+    pub fn timing(&self) -> &TimingAnalyser {
+        &self.timing
+    }
+
+    pub fn from_mode(mode: &VideoMode) -> Self {
+        Self::new(&mode.horiz, &mode.vert)
     }
 
     // Returns whether the next frame should be rendered now
-    pub fn update(&mut self, ctx: &mut RenderContext, reg: &RegisterFile) -> TimingResult {
+    pub fn update(&mut self, reg: &RegisterFile) -> TimingResult {
         let out = reg.out;
+        let vert_timing_err = self.timing.update(out);
         let falling = self.prev_out & !out;
         self.prev_out = out;
 
@@ -123,12 +403,12 @@ impl Vga {
         if render {
             self.row = -1;
             self.pixel = 0;
-            self.render(ctx);
+            self.render();
         }
 
         let mut horiz_cycle_err = false;
         if falling & HSYNC != 0 {
-            if self.col != 800 {
+            if self.col != self.line_length {
                 horiz_cycle_err = true;
             }
             self.col = 0;
@@ -142,8 +422,10 @@ impl Vga {
             let g = 85 * ((out >> 2) & 3);
             let b = 85 * ((out >> 4) & 3);
 
-            let fb = self.framebuffer.get_mut();
-            for _ in 0..4 {
+            self.pc_buffer[self.pixel / 4 / PIXELS_PER_CYCLE as usize] = self.out_pc;
+
+            let fb = &mut self.framebuffer;
+            for _ in 0..PIXELS_PER_CYCLE {
                 fb[self.pixel] = r;
                 fb[self.pixel + 1] = g;
                 fb[self.pixel + 2] = b;
@@ -152,11 +434,138 @@ impl Vga {
             }
         }
 
-        self.col += 4;
+        self.col += PIXELS_PER_CYCLE;
 
         TimingResult {
             should_render: render,
             horiz_cycle_err,
+            vert_timing_err,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_video_modes() {
+        for (name, mode) in VideoMode::NAMED {
+            assert_eq!(name.parse::<VideoMode>(), Ok(mode));
+            assert_eq!(mode.to_string(), name);
+        }
+
+        let custom: VideoMode = "16,96,48,640/10,2,12,500".parse().unwrap();
+        assert_eq!(custom.vert.total(), 524);
+        assert_eq!(custom.to_string().parse::<VideoMode>(), Ok(custom));
+
+        assert!("16,96,48,642/10,2,33,480".parse::<VideoMode>().is_err());
+        assert!("16,96,48/10,2,33,480".parse::<VideoMode>().is_err());
+        assert!("ntsc".parse::<VideoMode>().is_err());
+    }
+
+    #[test]
+    fn line_length_follows_mode() {
+        let mode: VideoMode = "16,96,48,320/10,2,33,480".parse().unwrap();
+        let mut vga = Vga::from_mode(&mode);
+        let mut reg = RegisterFile::new_random(&mut rand::thread_rng());
+
+        let mut errors = 0;
+        for line in 0..4 {
+            let length = if line == 1 { 130 } else { 120 };
+            for cycle in 0..length {
+                reg.out = if cycle < 24 { VSYNC } else { VSYNC | HSYNC };
+                errors += vga.update(&reg).horiz_cycle_err as i32;
+            }
         }
+        // Only the second line is longer than the mode's 480 pixels
+        assert_eq!(errors, 1);
+    }
+
+    #[test]
+    fn partial_frame_shades_undrawn_pixels() {
+        // Lines are a 1 cycle pulse and 2 cycles of pixels, frames are a VSYNC
+        // line and 2 visible lines
+        let mode: VideoMode = "0,4,0,8/0,1,0,2".parse().unwrap();
+        let mut vga = Vga::from_mode(&mode);
+        let mut reg = RegisterFile::new_random(&mut rand::thread_rng());
+        let mut line = |vsync: bool, colors: &[u8]| {
+            let v = if vsync { 0 } else { VSYNC };
+            for out in std::iter::once(v).chain(colors.iter().map(|c| v | HSYNC | c)) {
+                vga.set_out_pc(out as u16);
+                reg.out = out;
+                vga.update(&reg);
+            }
+        };
+
+        line(false, &[0]);
+        line(true, &[0, 0]);
+        line(false, &[0x3f, 0x3f]);
+        line(false, &[0x3f, 0x3f]);
+        line(true, &[0, 0]);
+        line(false, &[0x03, 0x03]);
+        line(false, &[0x03]);
+
+        let frame = vga.partial_frame();
+        assert_eq!(frame.len(), 16 * 4);
+        assert_eq!(frame[..12 * 4], [255, 0, 0, 255].repeat(12));
+        assert_eq!(frame[12 * 4..], [127, 127, 127, 255].repeat(4));
+
+        let white = (VSYNC | HSYNC | 0x3f) as u16;
+        let red = (VSYNC | HSYNC | 0x03) as u16;
+        assert_eq!(vga.pixel_pc(7, 1, false), Some(white));
+        assert_eq!(vga.pixel_pc(3, 1, true), Some(red));
+        assert_eq!(vga.pixel_pc(4, 1, true), Some(white));
+        assert_eq!(vga.pixel_pc(8, 1, true), None);
+    }
+
+    #[test]
+    fn undo_render_restores_frame() {
+        // Same layout as above, each frame is one solid color
+        let mode: VideoMode = "0,4,0,8/0,1,0,2".parse().unwrap();
+        let mut vga = Vga::from_mode(&mode);
+        let mut reg = RegisterFile::new_random(&mut rand::thread_rng());
+        let line = |vsync: bool, colors: &[u8]| {
+            let v = if vsync { 0 } else { VSYNC };
+            std::iter::once(v)
+                .chain(colors.iter().map(move |c| v | HSYNC | c))
+                .collect::<Vec<u8>>()
+        };
+        let frame = |color: u8| {
+            [
+                line(true, &[0, 0]),
+                line(false, &[color; 2]),
+                line(false, &[color; 2]),
+            ]
+            .concat()
+        };
+
+        let mut frame_start = None;
+        for (i, out) in [frame(0x30), frame(0x0c), frame(0x03)]
+            .concat()
+            .into_iter()
+            .enumerate()
+        {
+            let before = (
+                vga.beam(),
+                vga.partial_frame(),
+                vga.last_frame().to_vec(),
+                vga.pixel_pc(0, 0, true),
+            );
+            vga.set_out_pc(i as u16);
+            reg.out = out;
+            if vga.update(&reg).should_render && vga.frame_count() == 2 {
+                frame_start = Some((before, vga.take_discarded().unwrap()));
+            }
+        }
+
+        let ((beam, partial, last_frame, pc), discarded) = frame_start.unwrap();
+        assert_ne!(vga.partial_frame(), partial);
+        vga.restore_beam(beam);
+        vga.undo_render(discarded);
+        assert_eq!(vga.partial_frame(), partial);
+        assert_eq!(vga.last_frame(), last_frame);
+        assert_eq!(vga.pixel_pc(0, 0, true), pc);
+        assert_eq!(vga.frame_count(), 1);
     }
 }
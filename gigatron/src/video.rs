@@ -0,0 +1,209 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Error, Seek, SeekFrom, Write},
+};
+
+use flate2::{write::ZlibEncoder, Compression};
+
+use crate::cpu::CLOCK_HZ;
+
+const SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+// The acTL chunk directly follows the signature and IHDR
+const ACTL_OFFSET: u64 = 8 + 25;
+
+fn write_chunk(w: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> Result<(), Error> {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+
+    w.write_all(&(data.len() as u32).to_be_bytes())?;
+    w.write_all(kind)?;
+    w.write_all(data)?;
+    w.write_all(&crc.finalize().to_be_bytes())
+}
+
+// Seconds each frame is shown as a fraction, frame_cycles / CLOCK_HZ
+// reduced until both parts fit the fcTL chunk
+pub fn frame_delay(frame_cycles: u32) -> (u16, u16) {
+    fn gcd(a: u32, b: u32) -> u32 {
+        if b == 0 {
+            a
+        } else {
+            gcd(b, a % b)
+        }
+    }
+
+    let divisor = gcd(frame_cycles, CLOCK_HZ).max(1);
+    let (mut num, mut den) = (frame_cycles / divisor, CLOCK_HZ / divisor);
+    while num > u16::MAX as u32 || den > u16::MAX as u32 {
+        num = num.div_ceil(2);
+        den = den.div_ceil(2);
+    }
+    (num as u16, den as u16)
+}
+
+// Lossless animated PNG writer. Frames use a palette of the 64 colors the
+// Gigatron can output, and play back at exactly the emulated frame rate.
+pub struct VideoWriter {
+    file: BufWriter<File>,
+    size: (u32, u32),
+    delay: (u16, u16),
+    frame_count: u32,
+    sequence: u32,
+}
+
+impl VideoWriter {
+    // Frames are shown for frame_cycles CPU cycles each
+    pub fn create(file_name: &str, size: (u32, u32), frame_cycles: u32) -> Result<Self, Error> {
+        let mut writer = Self {
+            file: BufWriter::new(File::create(file_name)?),
+            size,
+            delay: frame_delay(frame_cycles),
+            frame_count: 0,
+            sequence: 0,
+        };
+
+        let mut ihdr = vec![];
+        ihdr.extend(size.0.to_be_bytes());
+        ihdr.extend(size.1.to_be_bytes());
+        ihdr.extend([8, 3, 0, 0, 0]); // 8 bit indexed, no interlace
+
+        let palette: Vec<u8> = (0..64u8)
+            .flat_map(|i| [85 * (i & 3), 85 * ((i >> 2) & 3), 85 * ((i >> 4) & 3)])
+            .collect();
+
+        writer.file.write_all(SIGNATURE)?;
+        write_chunk(&mut writer.file, b"IHDR", &ihdr)?;
+        writer.write_actl()?;
+        write_chunk(&mut writer.file, b"PLTE", &palette)?;
+        Ok(writer)
+    }
+
+    fn write_actl(&mut self) -> Result<(), Error> {
+        let mut actl = vec![];
+        actl.extend(self.frame_count.to_be_bytes());
+        actl.extend(0u32.to_be_bytes()); // Loop forever
+        write_chunk(&mut self.file, b"acTL", &actl)
+    }
+
+    fn next_sequence(&mut self) -> [u8; 4] {
+        self.sequence += 1;
+        (self.sequence - 1).to_be_bytes()
+    }
+
+    // Takes RGBA8 pixels as produced by Vga
+    pub fn write_frame(&mut self, rgba: &[u8]) -> Result<(), Error> {
+        let mut fctl = vec![];
+        fctl.extend(self.next_sequence());
+        fctl.extend(self.size.0.to_be_bytes());
+        fctl.extend(self.size.1.to_be_bytes());
+        fctl.extend([0; 8]); // Offset
+        fctl.extend(self.delay.0.to_be_bytes());
+        fctl.extend(self.delay.1.to_be_bytes());
+        fctl.extend([0, 0]); // No disposal, replace pixels
+        write_chunk(&mut self.file, b"fcTL", &fctl)?;
+
+        // Each scanline is a filter type byte followed by palette indices
+        let mut encoder = ZlibEncoder::new(vec![], Compression::fast());
+        for row in rgba.chunks_exact(self.size.0 as usize * 4) {
+            let indices: Vec<u8> = row
+                .chunks_exact(4)
+                .map(|p| (p[0] / 85) | (p[1] / 85) << 2 | (p[2] / 85) << 4)
+                .collect();
+            encoder.write_all(&[0])?;
+            encoder.write_all(&indices)?;
+        }
+        let compressed = encoder.finish()?;
+
+        // The first frame doubles as the still image for non-animated viewers
+        if self.frame_count == 0 {
+            write_chunk(&mut self.file, b"IDAT", &compressed)?;
+        } else {
+            let mut fdat = self.next_sequence().to_vec();
+            fdat.extend(compressed);
+            write_chunk(&mut self.file, b"fdAT", &fdat)?;
+        }
+        self.frame_count += 1;
+        Ok(())
+    }
+
+    pub fn frame_count(&self) -> u32 {
+        self.frame_count
+    }
+
+    // Fills in the frame count, which is unknown until recording stops
+    pub fn finish(mut self) -> Result<(), Error> {
+        if self.frame_count == 0 {
+            let (width, height) = self.size;
+            self.write_frame(&vec![0; (width * height * 4) as usize])?;
+        }
+        write_chunk(&mut self.file, b"IEND", &[])?;
+        self.file.seek(SeekFrom::Start(ACTL_OFFSET))?;
+        self.write_actl()?;
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vga::VideoMode;
+
+    #[test]
+    fn frame_delay_follows_mode() {
+        // 800x521 pixels at 4 per cycle, about 59.98 Hz
+        assert_eq!(
+            frame_delay(VideoMode::DEFAULT.total_frame_cycles() as u32),
+            (521, 31250)
+        );
+        // 800x449 pixels, about 69.6 Hz
+        let mode: VideoMode = "vga-640x400".parse().unwrap();
+        assert_eq!(frame_delay(mode.total_frame_cycles() as u32), (449, 31250));
+        // Too many cycles to reduce exactly
+        let (num, den) = frame_delay(6_250_001 * 2);
+        assert!((num as f64 / den as f64 - 2.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn writes_animated_png() {
+        let file_name =
+            std::env::temp_dir().join(format!("gigatron-video-{}.png", std::process::id()));
+        let file_name = file_name.to_str().unwrap();
+
+        let frames: Vec<Vec<u8>> = (0..3u8)
+            .map(|f| {
+                (0..8u8)
+                    .flat_map(|i| [85 * ((i + f) & 3), 85 * (i >> 2), 170, 255])
+                    .collect()
+            })
+            .collect();
+        let mode: VideoMode = "vga-640x400".parse().unwrap();
+        let frame_cycles = mode.total_frame_cycles() as u32;
+        let mut video = VideoWriter::create(file_name, (4, 2), frame_cycles).unwrap();
+        for frame in &frames {
+            video.write_frame(frame).unwrap();
+        }
+        video.finish().unwrap();
+
+        let mut decoder = png::Decoder::new(File::open(file_name).unwrap());
+        decoder.set_transformations(png::Transformations::EXPAND);
+        let mut reader = decoder.read_info().unwrap();
+        let actl = reader.info().animation_control.unwrap();
+        assert_eq!(actl.num_frames, 3);
+
+        for expected in &frames {
+            let mut buf = vec![0; reader.output_buffer_size()];
+            reader.next_frame(&mut buf).unwrap();
+            let fctl = reader.info().frame_control.unwrap();
+            assert_eq!((fctl.delay_num, fctl.delay_den), (449, 31250));
+
+            let rgb: Vec<u8> = expected
+                .chunks_exact(4)
+                .flat_map(|p| [p[0], p[1], p[2]])
+                .collect();
+            assert_eq!(&buf[..rgb.len()], rgb);
+        }
+        std::fs::remove_file(file_name).unwrap();
+    }
+}
@@ -0,0 +1,50 @@
+// Compares rendered frames against the reference images in tests/golden.
+// Run with UPDATE_GOLDEN=1 to regenerate them after an intended change. On a
+// mismatch, diff images are written next to the references. Needs the ROM
+// built by `python3 main.py`, run with `cargo test -- --ignored`.
+
+use gigatron::{
+    golden::{self, GoldenResult},
+    harness::Machine,
+    input::NO_BUTTONS,
+    movie::Movie,
+};
+
+const ROM_FILE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../main.rom");
+const SYM_FILE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../main.sym");
+const GOLDEN_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden");
+
+// Moves the first piece right and rotates it. Recorded with
+//   gigatron-run main.rom --seed 1 --fill zero --frames 24 \
+//       --input <script> --record-movie moved_and_rotated.movie
+const MOVIE_FILE: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/golden/moved_and_rotated.movie"
+);
+const START_FRAME: u64 = 10;
+
+fn check(m: &Machine, name: &str) {
+    let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+    let result = golden::check_frame(GOLDEN_DIR, name, m.vga.size(), m.vga.last_frame(), update);
+    if let GoldenResult::Mismatch(diff) = result.unwrap() {
+        panic!("{} differs: {}", name, diff);
+    }
+}
+
+#[test]
+#[ignore = "needs main.rom built by main.py"]
+fn tetris_frames_match_references() {
+    let movie = Movie::load(MOVIE_FILE).unwrap();
+    let mut m = Machine::load(ROM_FILE, SYM_FILE, movie.power_on)
+        .unwrap_or_else(|e| panic!("Failed to load {}: {}", ROM_FILE, e));
+    movie.start(&mut m.cpu).unwrap();
+
+    for frame in 0..movie.len() {
+        if frame == START_FRAME {
+            check(&m, "start");
+        }
+        m.cpu.input = movie.input_at(frame).unwrap_or(NO_BUTTONS);
+        m.run_frames(1).unwrap();
+    }
+    check(&m, "moved_and_rotated");
+}
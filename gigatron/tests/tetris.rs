@@ -0,0 +1,75 @@
+// Regression tests against the assembled ROM. Build it first with
+// `python3 main.py` in the repository root, then run these with
+// `cargo test -- --ignored`.
+
+use gigatron::{
+    cpu::{PowerOn, PowerOnFill},
+    harness::Machine,
+    input::{BUTTON_LEFT, BUTTON_RIGHT, BUTTON_UP},
+};
+
+const ROM_FILE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../main.rom");
+const SYM_FILE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../main.sym");
+
+// Piece indices are stored multiplied by 4
+const PIECE_T: u8 = 2 * 4;
+
+// Powers on and runs until the game has initialized
+fn machine() -> Machine {
+    let power_on = PowerOn::new(Some(1), PowerOnFill::Zero);
+    let mut m = Machine::load(ROM_FILE, SYM_FILE, power_on)
+        .unwrap_or_else(|e| panic!("Failed to load {}: {}", ROM_FILE, e));
+    m.run_frames(10).unwrap();
+    m
+}
+
+fn rotation(m: &Machine) -> (u8, u8, u8) {
+    (m.var("flipX"), m.var("flipY"), m.var("swapAxes"))
+}
+
+#[test]
+#[ignore = "needs main.rom built by main.py"]
+fn pressing_up_rotates_t_piece() {
+    let mut m = machine();
+    m.set_var("currentPiece", PIECE_T);
+    assert_eq!(rotation(&m), (1, 1, 0));
+
+    m.press(BUTTON_UP);
+    m.run_until_label("block_tryRotate", 1_000_000).unwrap();
+    m.run_frames(2).unwrap();
+    assert_eq!(rotation(&m), (1, 0, 1));
+    assert_eq!(m.var("currentPiece"), PIECE_T);
+}
+
+#[test]
+#[ignore = "needs main.rom built by main.py"]
+fn holding_up_rotates_once() {
+    let mut m = machine();
+    m.set_var("currentPiece", PIECE_T);
+
+    m.press(BUTTON_UP);
+    m.run_frames(10).unwrap();
+    assert_eq!(rotation(&m), (1, 0, 1));
+
+    m.release_all();
+    m.run_frames(2).unwrap();
+    m.press(BUTTON_UP);
+    m.run_frames(2).unwrap();
+    assert_eq!(rotation(&m), (0, 0, 0));
+}
+
+#[test]
+#[ignore = "needs main.rom built by main.py"]
+fn left_and_right_move_one_cell() {
+    let mut m = machine();
+    let x = m.var("pieceX");
+
+    m.press(BUTTON_RIGHT);
+    m.run_frames(2).unwrap();
+    assert_eq!(m.var("pieceX"), x + 5);
+
+    m.release_all();
+    m.press(BUTTON_LEFT);
+    m.run_frames(2).unwrap();
+    assert_eq!(m.var("pieceX"), x);
+}